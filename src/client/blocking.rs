@@ -0,0 +1,367 @@
+//! A blocking SOCKS5 client over [`std::net`], for synchronous programs that would rather not pull in a runtime.
+//!
+//! The functions taking a generic stream work on anything that is `Read + Write`. The `*_timeout` variants
+//! connect to the proxy themselves and bound the TCP connect and every read and write of the handshake by the given
+//! duration, leaving the returned stream without timeouts.
+
+use super::Handshake;
+use crate::{
+    error::Result,
+    protocol::{
+        handshake, password_method, Address, Command, Decode, Decoded, Response, StreamOperation, UdpHeader, UdpReassembler, UserKey,
+    },
+};
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+fn read_final<S: Read>(stream: &mut S) -> Result<Address> {
+    super::reply_address(Response::retrieve_from_stream(stream)?)
+}
+
+fn init<S, A>(stream: &mut S, command: Command, addr: A, auth: Option<UserKey>) -> Result<Address>
+where
+    S: Read + Write,
+    A: Into<Address>,
+{
+    let session = Handshake::new(command, addr.into(), auth)?;
+    stream.write_all(&session.greeting())?;
+    stream.flush()?;
+
+    let method = handshake::Response::retrieve_from_stream(stream)?.method;
+    if let Some(req) = session.select(method)? {
+        stream.write_all(&req)?;
+        stream.flush()?;
+        session.authenticated(password_method::Response::retrieve_from_stream(stream)?.status)?;
+    }
+
+    stream.write_all(&session.request())?;
+    stream.flush()?;
+    read_final(stream)
+}
+
+/// Runs `f` on `stream` with its read and write timeouts set to `timeout`, clearing them again afterwards.
+fn with_timeout<T, F>(stream: &TcpStream, timeout: Duration, f: F) -> Result<T>
+where
+    F: FnOnce(&mut &TcpStream) -> Result<T>,
+{
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let result = f(&mut &*stream);
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    result
+}
+
+/// Proxifies a TCP connection. Performs the [`CONNECT`] command under the hood.
+///
+/// [`CONNECT`]: https://tools.ietf.org/html/rfc1928#page-6
+///
+/// ```no_run
+/// # use socks5_impl::Result;
+/// # fn main() -> Result<()> {
+/// use socks5_impl::client::blocking as client;
+/// use std::net::TcpStream;
+///
+/// let mut stream = TcpStream::connect("my-proxy-server.com:54321")?;
+/// client::connect(&mut stream, ("google.com", 80), None)?;
+///
+/// # Ok(())
+/// # }
+/// ```
+pub fn connect<S, A>(stream: &mut S, addr: A, auth: Option<UserKey>) -> Result<Address>
+where
+    S: Read + Write,
+    A: Into<Address>,
+{
+    init(stream, Command::Connect, addr, auth)
+}
+
+/// Like [`connect`], but also connects to the proxy at `proxy_addr`, giving up on the TCP connect
+/// and on each handshake read or write after `timeout`.
+pub fn connect_timeout<A>(proxy_addr: &SocketAddr, addr: A, auth: Option<UserKey>, timeout: Duration) -> Result<(TcpStream, Address)>
+where
+    A: Into<Address>,
+{
+    let stream = TcpStream::connect_timeout(proxy_addr, timeout)?;
+    let addr = with_timeout(&stream, timeout, |stream| connect(stream, addr, auth))?;
+    Ok((stream, addr))
+}
+
+/// A listener that accepts TCP connections through a proxy.
+///
+/// ```no_run
+/// # use socks5_impl::Result;
+/// # fn main() -> Result<()> {
+/// use socks5_impl::client::blocking::SocksListener;
+/// use std::net::TcpStream;
+///
+/// let stream = TcpStream::connect("my-proxy-server.com:54321")?;
+/// let (stream, addr) = SocksListener::bind(stream, ("ftp-server.org", 21), None)?.accept()?;
+///
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct SocksListener<S> {
+    stream: S,
+    proxy_addr: Address,
+}
+
+impl<S> SocksListener<S>
+where
+    S: Read + Write,
+{
+    /// Creates `SocksListener`. Performs the [`BIND`] command under the hood.
+    ///
+    /// [`BIND`]: https://tools.ietf.org/html/rfc1928#page-6
+    pub fn bind<A>(mut stream: S, addr: A, auth: Option<UserKey>) -> Result<Self>
+    where
+        A: Into<Address>,
+    {
+        let addr = init(&mut stream, Command::Bind, addr, auth)?;
+        Ok(Self { stream, proxy_addr: addr })
+    }
+
+    pub fn proxy_addr(&self) -> &Address {
+        &self.proxy_addr
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Waits for the proxy to report the incoming connection, returning the stream and the address of the peer.
+    pub fn accept(mut self) -> Result<(S, Address)> {
+        let addr = read_final(&mut self.stream)?;
+        Ok((self.stream, addr))
+    }
+}
+
+impl SocksListener<TcpStream> {
+    /// Like [`bind`](Self::bind), but also connects to the proxy at `proxy_addr`, giving up on the TCP connect
+    /// and on each handshake read or write after `timeout`.
+    pub fn bind_timeout<A>(proxy_addr: &SocketAddr, addr: A, auth: Option<UserKey>, timeout: Duration) -> Result<Self>
+    where
+        A: Into<Address>,
+    {
+        let stream = TcpStream::connect_timeout(proxy_addr, timeout)?;
+        let addr = with_timeout(&stream, timeout, |stream| init(stream, Command::Bind, addr, auth))?;
+        Ok(Self { stream, proxy_addr: addr })
+    }
+
+    /// Like [`accept`](Self::accept), but gives up if no peer connects within `timeout`.
+    pub fn accept_timeout(self, timeout: Duration) -> Result<(TcpStream, Address)> {
+        let addr = with_timeout(&self.stream, timeout, |stream| read_final(stream))?;
+        Ok((self.stream, addr))
+    }
+}
+
+/// A UDP socket that sends packets through a proxy.
+///
+/// The association lasts as long as the stream it was made on, so it is kept alongside the socket.
+#[derive(Debug)]
+pub struct SocksDatagram<S> {
+    socket: UdpSocket,
+    proxy_addr: Address,
+    stream: S,
+    reassembler: Mutex<UdpReassembler>,
+}
+
+impl<S> SocksDatagram<S>
+where
+    S: Read + Write,
+{
+    /// Creates `SocksDatagram`. Performs [`UDP ASSOCIATE`] under the hood.
+    ///
+    /// [`UDP ASSOCIATE`]: https://tools.ietf.org/html/rfc1928#page-7
+    pub fn udp_associate(mut stream: S, socket: UdpSocket, auth: Option<UserKey>) -> Result<Self> {
+        let addr = if socket.local_addr()?.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let addr = addr.parse::<SocketAddr>()?;
+        let proxy_addr = init(&mut stream, Command::UdpAssociate, addr, auth)?;
+        let addr = proxy_addr.to_socket_addrs()?.next().ok_or("InvalidAddress")?;
+        socket.connect(addr)?;
+        Ok(Self {
+            socket,
+            proxy_addr,
+            stream,
+            reassembler: Mutex::new(UdpReassembler::default()),
+        })
+    }
+
+    /// Returns the address of the associated udp address.
+    pub fn proxy_addr(&self) -> &Address {
+        &self.proxy_addr
+    }
+
+    /// Returns a reference to the underlying udp socket.
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Returns a mutable reference to the underlying udp socket.
+    pub fn get_mut(&mut self) -> &mut UdpSocket {
+        &mut self.socket
+    }
+
+    /// Returns the associated stream and udp socket.
+    pub fn into_inner(self) -> (S, UdpSocket) {
+        (self.stream, self.socket)
+    }
+
+    /// Sends data via the udp socket to the given address.
+    pub fn send_to<A>(&self, buf: &[u8], addr: A) -> Result<usize>
+    where
+        A: Into<Address>,
+    {
+        let header = UdpHeader::new(0, addr.into());
+        let mut bytes = Vec::with_capacity(header.len() + buf.len());
+        header.try_write_to_buf(&mut bytes)?;
+        bytes.extend_from_slice(buf);
+        Ok(self.socket.send(&bytes)?)
+    }
+
+    /// Receives data from the udp socket and returns the number of bytes read and the origin of the data.
+    ///
+    /// Fragmented datagrams are reassembled as RFC 1928 section 7 describes, and `timeout` applies to the whole of them.
+    /// With a `timeout` of `None`, blocks until a whole datagram arrives.
+    pub fn recv_from(&self, timeout: Option<Duration>, buf: &mut Vec<u8>) -> Result<(usize, Address)> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut bytes = vec![0; u16::MAX as usize];
+        loop {
+            let timeout = match deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())) {
+                Some(left) if left.is_zero() => return Err(std::io::Error::from(ErrorKind::TimedOut).into()),
+                left => left,
+            };
+            self.socket.set_read_timeout(timeout)?;
+            let len = self.socket.recv(&mut bytes)?;
+            let (header, header_len) = match UdpHeader::decode(&bytes[..len])? {
+                Decoded::Complete(header, header_len) => (header, header_len),
+                Decoded::NeedMore(_) => return Err("Truncated UDP header".into()),
+            };
+            let res = self
+                .reassembler
+                .lock()
+                .unwrap()
+                .push(header.frag, &header.address, &bytes[header_len..len]);
+            if let Some((addr, data)) = res {
+                buf.clear();
+                buf.extend_from_slice(&data);
+                return Ok((data.len(), addr));
+            }
+        }
+    }
+}
+
+impl SocksDatagram<TcpStream> {
+    /// Like [`udp_associate`](Self::udp_associate), but also connects to the proxy at `proxy_addr` and binds the udp
+    /// socket, giving up on the TCP connect and on each handshake read or write after `timeout`.
+    pub fn udp_associate_timeout(proxy_addr: &SocketAddr, auth: Option<UserKey>, timeout: Duration) -> Result<Self> {
+        let client_addr = if proxy_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(client_addr)?;
+        let stream = TcpStream::connect_timeout(proxy_addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let datagram = Self::udp_associate(stream, socket, auth)?;
+        datagram.stream.set_read_timeout(None)?;
+        datagram.stream.set_write_timeout(None)?;
+        Ok(datagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, connect_timeout, SocksDatagram, SocksListener};
+    use crate::{
+        client::test_proxy::{spawn_proxy, FRAGMENTED_ECHO, PEER},
+        protocol::{Address, UserKey},
+        Error,
+    };
+    use std::{
+        io::{ErrorKind, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn connect_with_and_without_auth() {
+        let user_key = UserKey::new("hyper", "proxy");
+        let (proxy_addr, proxy) = spawn_proxy(vec![None, Some(user_key.clone()), Some(user_key)]);
+
+        let mut stream = TcpStream::connect(proxy_addr).unwrap();
+        connect(&mut stream, ("example.com", 80), None).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let auth = Some(UserKey::new("hyper", "proxy"));
+        let (mut stream, _) = connect_timeout(&proxy_addr, ("example.com", 80), auth, TIMEOUT).unwrap();
+        assert_eq!(stream.read_timeout().unwrap(), None);
+        stream.write_all(b"pong").unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        let auth = Some(UserKey::new("hyper", "wrong"));
+        let err = connect_timeout(&proxy_addr, ("example.com", 80), auth, TIMEOUT).unwrap_err();
+        assert!(matches!(err, Error::InvalidAuthStatus(0xff)));
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn connect_times_out_on_silent_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let err = connect_timeout(&proxy_addr, ("example.com", 80), None, Duration::from_millis(100)).unwrap_err();
+        match err {
+            Error::Io(err) => assert!(matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)),
+            err => panic!("unexpected {err:?}"),
+        }
+    }
+
+    #[test]
+    fn bind_and_accept() {
+        let (proxy_addr, proxy) = spawn_proxy(vec![None]);
+        let listener = SocksListener::bind_timeout(&proxy_addr, SocketAddr::from(PEER), None, TIMEOUT).unwrap();
+        assert_eq!(listener.proxy_addr().port(), proxy_addr.port());
+        let (_, peer) = listener.accept_timeout(TIMEOUT).unwrap();
+        assert_eq!(peer, Address::from(PEER));
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn udp_associate() {
+        let (proxy_addr, proxy) = spawn_proxy(vec![None]);
+        let datagram = SocksDatagram::udp_associate_timeout(&proxy_addr, None, TIMEOUT).unwrap();
+        datagram.send_to(b"hello", SocketAddr::from(PEER)).unwrap();
+        let mut buf = Vec::new();
+        let (len, addr) = datagram.recv_from(Some(TIMEOUT), &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(addr, Address::from(PEER));
+        drop(datagram);
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn udp_associate_reassembles_fragments() {
+        let (proxy_addr, proxy) = spawn_proxy(vec![None]);
+        let datagram = SocksDatagram::udp_associate_timeout(&proxy_addr, None, TIMEOUT).unwrap();
+        let payload = b"a reply in two fragments";
+        assert!(payload.len() > FRAGMENTED_ECHO);
+        datagram.send_to(payload, SocketAddr::from(PEER)).unwrap();
+        let mut buf = Vec::new();
+        let (len, addr) = datagram.recv_from(Some(TIMEOUT), &mut buf).unwrap();
+        assert_eq!(&buf[..len], payload);
+        assert_eq!(addr, Address::from(PEER));
+        let err = datagram.recv_from(Some(Duration::from_millis(50)), &mut buf).unwrap_err();
+        assert!(matches!(err, Error::Io(ref err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)));
+        drop(datagram);
+        proxy.join().unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+//! A SOCKS5 client over [`futures-io`](https://docs.rs/futures-io) streams, for runtimes other than tokio.
+//!
+//! Streams from tokio can be used too, through the `compat` adapters of
+//! [tokio-util](https://docs.rs/tokio-util/latest/tokio_util/compat/index.html).
+//! UDP ASSOCIATE is not offered here, since `futures-io` has no notion of a UDP socket.
+
+use super::Handshake;
+use crate::{
+    error::Result,
+    protocol::{handshake, password_method, Address, Command, FuturesStreamOperation, Response, UserKey},
+};
+use futures_util::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+async fn read_final<S>(stream: &mut S) -> Result<Address>
+where
+    S: AsyncRead + Send + Unpin,
+{
+    super::reply_address(Response::retrieve_from_futures_stream(stream).await?)
+}
+
+async fn init<S, A>(stream: &mut S, command: Command, addr: A, auth: Option<UserKey>) -> Result<Address>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    A: Into<Address>,
+{
+    let session = Handshake::new(command, addr.into(), auth)?;
+    stream.write_all(&session.greeting()).await?;
+    stream.flush().await?;
+
+    let method = handshake::Response::retrieve_from_futures_stream(stream).await?.method;
+    if let Some(req) = session.select(method)? {
+        stream.write_all(&req).await?;
+        stream.flush().await?;
+        let resp = password_method::Response::retrieve_from_futures_stream(stream).await?;
+        session.authenticated(resp.status)?;
+    }
+
+    stream.write_all(&session.request()).await?;
+    stream.flush().await?;
+    read_final(stream).await
+}
+
+/// Proxifies a TCP connection. Performs the [`CONNECT`] command under the hood.
+///
+/// [`CONNECT`]: https://tools.ietf.org/html/rfc1928#page-6
+///
+/// ```no_run
+/// # use socks5_impl::Result;
+/// # fn main() -> Result<()> {
+/// # smol::block_on(async {
+/// use socks5_impl::client::futures_io as client;
+///
+/// let mut stream = smol::net::TcpStream::connect("my-proxy-server.com:54321").await?;
+/// client::connect(&mut stream, ("google.com", 80), None).await?;
+///
+/// # Ok(())
+/// # })
+/// # }
+/// ```
+pub async fn connect<S, A>(socket: &mut S, addr: A, auth: Option<UserKey>) -> Result<Address>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    A: Into<Address>,
+{
+    init(socket, Command::Connect, addr, auth).await
+}
+
+/// A listener that accepts TCP connections through a proxy.
+#[derive(Debug)]
+pub struct SocksListener<S> {
+    stream: S,
+    proxy_addr: Address,
+}
+
+impl<S> SocksListener<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    /// Creates `SocksListener`. Performs the [`BIND`] command under the hood.
+    ///
+    /// [`BIND`]: https://tools.ietf.org/html/rfc1928#page-6
+    pub async fn bind<A>(mut stream: S, addr: A, auth: Option<UserKey>) -> Result<Self>
+    where
+        A: Into<Address>,
+    {
+        let addr = init(&mut stream, Command::Bind, addr, auth).await?;
+        Ok(Self { stream, proxy_addr: addr })
+    }
+
+    pub fn proxy_addr(&self) -> &Address {
+        &self.proxy_addr
+    }
+
+    pub async fn accept(mut self) -> Result<(S, Address)> {
+        let addr = read_final(&mut self.stream).await?;
+        Ok((self.stream, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, SocksListener};
+    use crate::{
+        client::test_proxy::{spawn_proxy, PEER},
+        protocol::{Address, UserKey},
+        Error,
+    };
+    use futures_util::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use std::net::SocketAddr;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    async fn connect_and_echo<S>(mut stream: S, proxy_addr: SocketAddr, auth: Option<UserKey>)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let bound = connect(&mut stream, ("example.com", 80), auth).await.unwrap();
+        assert_eq!(bound, Address::from(proxy_addr));
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    async fn bind_and_accept<S>(stream: S, proxy_addr: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let listener = SocksListener::bind(stream, SocketAddr::from(PEER), None).await.unwrap();
+        assert_eq!(listener.proxy_addr(), &Address::from(proxy_addr));
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, Address::from(PEER));
+    }
+
+    #[test]
+    fn smol_runtime() {
+        let user_key = UserKey::new("hyper", "proxy");
+        let (addr, proxy) = spawn_proxy(vec![None, Some(user_key.clone()), None]);
+        smol::block_on(async {
+            connect_and_echo(smol::net::TcpStream::connect(addr).await.unwrap(), addr, None).await;
+            connect_and_echo(smol::net::TcpStream::connect(addr).await.unwrap(), addr, Some(user_key)).await;
+            bind_and_accept(smol::net::TcpStream::connect(addr).await.unwrap(), addr).await;
+        });
+        proxy.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tokio_runtime() {
+        let (addr, proxy) = spawn_proxy(vec![None, None, Some(UserKey::new("hyper", "proxy"))]);
+        let connect_compat = || async { tokio::net::TcpStream::connect(addr).await.unwrap().compat() };
+        connect_and_echo(connect_compat().await, addr, None).await;
+        bind_and_accept(connect_compat().await, addr).await;
+        let err = connect(
+            &mut connect_compat().await,
+            ("example.com", 80),
+            Some(UserKey::new("hyper", "wrong")),
+        )
+        .await;
+        assert!(matches!(err, Err(Error::InvalidAuthStatus(0xff))));
+        proxy.join().unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+//! SOCKS5 over TLS on the client side, with the `tls` feature.
+//!
+//! [`connect_tls()`] opens the TLS-wrapped connection to the proxy, over which the usual client functions such as
+//! [`client::connect()`](crate::client::connect) run. The rustls configuration is built with [`TlsClientOptions`].
+
+use crate::{
+    error::Result,
+    tls::{provider, rustls, tls_error},
+};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, RootCertStore,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// Builds the rustls [`ClientConfig`] to reach a TLS-wrapped SOCKS5 proxy.
+///
+/// The proxy certificate is verified against the CA certificates added with [`with_root_certs()`](TlsClientOptions::with_root_certs),
+/// so at least one is needed.
+#[derive(Debug)]
+pub struct TlsClientOptions {
+    roots: RootCertStore,
+    client_cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl TlsClientOptions {
+    /// Creates options without any trusted CA certificate.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            roots: RootCertStore::empty(),
+            client_cert: None,
+        }
+    }
+
+    /// Trusts the CA certificates `certs` to issue the proxy certificate.
+    #[inline]
+    pub fn with_root_certs(mut self, certs: Vec<CertificateDer<'static>>) -> std::io::Result<Self> {
+        for cert in certs {
+            self.roots.add(cert).map_err(tls_error)?;
+        }
+        Ok(self)
+    }
+
+    /// Presents the certificate chain `certs` with its private key `key` to proxies asking for a client certificate.
+    #[inline]
+    pub fn with_client_cert(mut self, certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Self {
+        self.client_cert = Some((certs, key));
+        self
+    }
+
+    /// Builds the rustls client configuration.
+    pub fn build(self) -> std::io::Result<Arc<ClientConfig>> {
+        if self.roots.is_empty() {
+            return Err(tls_error("no root certificate configured"));
+        }
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(self.roots);
+        let config = match self.client_cert {
+            Some((certs, key)) => builder.with_client_auth_cert(certs, key).map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+impl Default for TlsClientOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Connects to the TLS-wrapped proxy at `proxy_addr`, sending `server_name` through SNI and verifying the proxy certificate for it.
+///
+/// `server_name` is a DNS name or an IP address.
+pub async fn connect_tls<A: Into<SocketAddr>>(proxy_addr: A, server_name: &str, config: Arc<ClientConfig>) -> Result<TlsStream<TcpStream>> {
+    let server_name = ServerName::try_from(server_name.to_owned()).map_err(tls_error)?;
+    let stream = TcpStream::connect(proxy_addr.into()).await?;
+    Ok(TlsConnector::from(config).connect(server_name, stream).await?)
+}
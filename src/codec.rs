@@ -0,0 +1,282 @@
+//! [`tokio_util::codec`] implementations of the SOCKS5 messages, for use with
+//! [`Framed`](tokio_util::codec::Framed) and [`UdpFramed`](https://docs.rs/tokio-util/latest/tokio_util/udp/struct.UdpFramed.html).
+//!
+//! The message types exchanged on a connection change as the SOCKS5 negotiation goes on, so a [`Socks5Codec`]
+//! decodes one message type and is swapped for the next one with
+//! [`Framed::map_codec`](tokio_util::codec::Framed::map_codec) once that phase is over.
+//!
+//! ```no_run
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> socks5_impl::Result<()> {
+//! use futures::{SinkExt, StreamExt};
+//! use socks5_impl::{
+//!     codec::Socks5Codec,
+//!     protocol::{handshake, Address, AuthMethod, Reply, Request, Response},
+//! };
+//! use tokio_util::codec::Framed;
+//!
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:1080").await?;
+//! let (stream, _) = listener.accept().await?;
+//!
+//! let mut framed = Framed::new(stream, Socks5Codec::<handshake::Request>::new());
+//! let _methods = framed.next().await.ok_or("connection closed")??;
+//! framed.send(handshake::Response::new(AuthMethod::NoAuth)).await?;
+//!
+//! let mut framed = framed.map_codec(|_| Socks5Codec::<Request>::new());
+//! let _request = framed.next().await.ok_or("connection closed")??;
+//! framed.send(Response::new(Reply::CommandNotSupported, Address::unspecified())).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    error::{Error, Result},
+    protocol::{handshake, password_method, Decode, Decoded, Request, Response, StreamOperation, UdpHeader},
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::marker::PhantomData;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A codec that decodes SOCKS5 messages of type `D` and encodes any SOCKS5 message.
+///
+/// Use `Socks5Codec<handshake::Request>`, `Socks5Codec<password_method::Request>` and `Socks5Codec<Request>`
+/// on the server side, and the corresponding response types on the client side.
+pub struct Socks5Codec<D> {
+    _marker: PhantomData<fn() -> D>,
+}
+
+impl<D> Socks5Codec<D> {
+    #[inline]
+    pub fn new() -> Self {
+        Self { _marker: PhantomData }
+    }
+}
+
+impl<D> Default for Socks5Codec<D> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> Clone for Socks5Codec<D> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl<D> std::fmt::Debug for Socks5Codec<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Socks5Codec").field("item", &std::any::type_name::<D>()).finish()
+    }
+}
+
+impl<D: Decode> Decoder for Socks5Codec<D> {
+    type Item = D;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>> {
+        match D::decode(src)? {
+            Decoded::Complete(msg, len) => {
+                src.advance(len);
+                Ok(Some(msg))
+            }
+            Decoded::NeedMore(n) => {
+                src.reserve(n);
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn encode<M: StreamOperation>(msg: &M, dst: &mut BytesMut, check: Result<()>) -> Result<()> {
+    check?;
+    dst.reserve(msg.len());
+    msg.write_to_buf(dst);
+    Ok(())
+}
+
+impl<D> Encoder<handshake::Request> for Socks5Codec<D> {
+    type Error = Error;
+
+    fn encode(&mut self, item: handshake::Request, dst: &mut BytesMut) -> Result<()> {
+        encode(&item, dst, item.validate())
+    }
+}
+
+impl<D> Encoder<handshake::Response> for Socks5Codec<D> {
+    type Error = Error;
+
+    fn encode(&mut self, item: handshake::Response, dst: &mut BytesMut) -> Result<()> {
+        encode(&item, dst, Ok(()))
+    }
+}
+
+impl<D> Encoder<password_method::Request> for Socks5Codec<D> {
+    type Error = Error;
+
+    fn encode(&mut self, item: password_method::Request, dst: &mut BytesMut) -> Result<()> {
+        encode(&item, dst, item.user_key.validate())
+    }
+}
+
+impl<D> Encoder<password_method::Response> for Socks5Codec<D> {
+    type Error = Error;
+
+    fn encode(&mut self, item: password_method::Response, dst: &mut BytesMut) -> Result<()> {
+        encode(&item, dst, Ok(()))
+    }
+}
+
+impl<D> Encoder<Request> for Socks5Codec<D> {
+    type Error = Error;
+
+    fn encode(&mut self, item: Request, dst: &mut BytesMut) -> Result<()> {
+        encode(&item, dst, item.address.validate())
+    }
+}
+
+impl<D> Encoder<Response> for Socks5Codec<D> {
+    type Error = Error;
+
+    fn encode(&mut self, item: Response, dst: &mut BytesMut) -> Result<()> {
+        encode(&item, dst, item.address.validate())
+    }
+}
+
+/// A datagram codec for SOCKS5 UDP relay packets, for use with
+/// [`UdpFramed`](https://docs.rs/tokio-util/latest/tokio_util/udp/struct.UdpFramed.html).
+///
+/// Every datagram is a [`UdpHeader`] followed by the payload, which are paired as `(Bytes, UdpHeader)` items.
+/// A datagram too short to hold its header is reported as an error rather than waiting for more bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UdpCodec;
+
+impl Decoder for UdpCodec {
+    type Item = (Bytes, UdpHeader);
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        match UdpHeader::decode(src)? {
+            Decoded::Complete(header, len) => {
+                src.advance(len);
+                let payload = src.split().freeze();
+                Ok(Some((payload, header)))
+            }
+            Decoded::NeedMore(_) => {
+                src.clear();
+                let err = "Truncated SOCKS5 UDP header";
+                Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, err).into())
+            }
+        }
+    }
+}
+
+impl Encoder<(Bytes, UdpHeader)> for UdpCodec {
+    type Error = Error;
+
+    fn encode(&mut self, (payload, header): (Bytes, UdpHeader), dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(header.len() + payload.len());
+        header.try_write_to_buf(dst)?;
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Address, AuthMethod, Command, Reply};
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::UdpSocket;
+    use tokio_util::{codec::Framed, udp::UdpFramed};
+
+    #[tokio::test]
+    async fn framed_negotiation() {
+        let (client, server) = tokio::io::duplex(64);
+
+        let client = async move {
+            let mut framed = Framed::new(client, Socks5Codec::<handshake::Response>::new());
+            framed.send(handshake::Request::new(vec![AuthMethod::UserPass])).await?;
+            assert_eq!(framed.next().await.unwrap()?.method, AuthMethod::UserPass);
+
+            let mut framed = framed.map_codec(|_| Socks5Codec::<password_method::Response>::new());
+            framed.send(password_method::Request::new("hyper", "proxy")).await?;
+            assert_eq!(framed.next().await.unwrap()?.status, password_method::Status::Succeeded);
+
+            let mut framed = framed.map_codec(|_| Socks5Codec::<Response>::new());
+            framed
+                .send(Request::new(Command::Connect, Address::from(("example.com", 80))))
+                .await?;
+            let resp = framed.next().await.unwrap()?;
+            assert_eq!(resp.reply, Reply::Succeeded);
+            assert_eq!(
+                resp.address,
+                Address::from(("127.0.0.1".parse::<std::net::Ipv4Addr>().unwrap(), 1080))
+            );
+            Ok::<_, Error>(())
+        };
+
+        let server = async move {
+            let mut framed = Framed::new(server, Socks5Codec::<handshake::Request>::new());
+            let req = framed.next().await.unwrap()?;
+            assert!(req.evaluate_method(AuthMethod::UserPass));
+            framed.send(handshake::Response::new(AuthMethod::UserPass)).await?;
+
+            let mut framed = framed.map_codec(|_| Socks5Codec::<password_method::Request>::new());
+            let req = framed.next().await.unwrap()?;
+            assert_eq!(req.user_key, crate::protocol::UserKey::new("hyper", "proxy"));
+            framed
+                .send(password_method::Response::new(password_method::Status::Succeeded))
+                .await?;
+
+            let mut framed = framed.map_codec(|_| Socks5Codec::<Request>::new());
+            let req = framed.next().await.unwrap()?;
+            assert_eq!(req.command, Command::Connect);
+            assert_eq!(req.address, Address::from(("example.com", 80)));
+            let bound = Address::from(("127.0.0.1".parse::<std::net::Ipv4Addr>().unwrap(), 1080));
+            framed.send(Response::new(Reply::Succeeded, bound)).await?;
+            Ok::<_, Error>(())
+        };
+
+        let (client, server) = tokio::join!(client, server);
+        client.unwrap();
+        server.unwrap();
+    }
+
+    #[tokio::test]
+    async fn framed_rejects_unencodable_response() {
+        let (_client, server) = tokio::io::duplex(64);
+        let mut framed = Framed::new(server, Socks5Codec::<Request>::new());
+        let resp = Response::new(Reply::Succeeded, Address::from(("a".repeat(300), 80)));
+        assert!(matches!(framed.send(resp).await, Err(Error::FieldTooLong("domain", 300))));
+    }
+
+    #[tokio::test]
+    async fn udp_framed_relay() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b_addr = b.local_addr().unwrap();
+        let mut a = UdpFramed::new(a, UdpCodec);
+        let mut b = UdpFramed::new(b, UdpCodec);
+
+        let header = UdpHeader::new(0, Address::from(("example.com", 53)));
+        a.send(((Bytes::from_static(b"query"), header), b_addr)).await.unwrap();
+
+        let ((payload, header), _) = b.next().await.unwrap().unwrap();
+        assert_eq!(payload, Bytes::from_static(b"query"));
+        assert_eq!(header.frag, 0);
+        assert_eq!(header.address, Address::from(("example.com", 53)));
+    }
+
+    #[test]
+    fn udp_truncated_header() {
+        let mut buf = BytesMut::from(&[0x00, 0x00, 0x00, 0x01, 127, 0][..]);
+        assert!(UdpCodec.decode(&mut buf).is_err());
+        assert!(UdpCodec.decode(&mut buf).unwrap().is_none());
+    }
+}
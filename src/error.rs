@@ -1,16 +1,26 @@
+use alloc::string::{String, ToString};
+
 /// The library's error type.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[cfg(feature = "std")]
+    #[error("{0}")]
+    Io(std::io::Error),
+
+    /// A deadline of the server expired, see [`server::timeout`](crate::server::timeout).
+    #[cfg(feature = "tokio")]
     #[error("{0}")]
-    Io(#[from] std::io::Error),
+    Expired(#[from] crate::server::timeout::Expired),
 
     #[error("{0}")]
-    FromUtf8(#[from] std::string::FromUtf8Error),
+    FromUtf8(#[from] alloc::string::FromUtf8Error),
 
     #[error("Invalid SOCKS version: {0:x}")]
     InvalidVersion(u8),
     #[error("Invalid command: {0:x}")]
     InvalidCommand(u8),
+    #[error("Invalid reply: {0:x}")]
+    InvalidReply(u8),
     #[error("Invalid address type: {0:x}")]
     InvalidAtyp(u8),
     #[error("Invalid reserved bytes: {0:x}")]
@@ -22,6 +32,9 @@ pub enum Error {
     #[error("Invalid fragment id: {0:x}")]
     InvalidFragmentId(u8),
 
+    #[error("{0} is {1} bytes long, which does not fit in a one-byte length field")]
+    FieldTooLong(&'static str, usize),
+
     #[error("Invalid authentication method: {0:?}")]
     InvalidAuthMethod(crate::protocol::AuthMethod),
 
@@ -29,13 +42,13 @@ pub enum Error {
     WrongVersion,
 
     #[error("AddrParseError: {0}")]
-    AddrParseError(#[from] std::net::AddrParseError),
+    AddrParseError(#[from] core::net::AddrParseError),
 
     #[error("ParseIntError: {0}")]
-    ParseIntError(#[from] std::num::ParseIntError),
+    ParseIntError(#[from] core::num::ParseIntError),
 
     #[error("Utf8Error: {0}")]
-    Utf8Error(#[from] std::str::Utf8Error),
+    Utf8Error(#[from] core::str::Utf8Error),
 
     #[error("{0}")]
     String(String),
@@ -59,11 +72,25 @@ impl From<&String> for Error {
     }
 }
 
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        #[cfg(feature = "tokio")]
+        if let Some(expired) = crate::server::timeout::Expired::from_io(&e) {
+            return Error::Expired(expired);
+        }
+        Error::Io(e)
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<Error> for std::io::Error {
     fn from(e: Error) -> Self {
         match e {
             Error::Io(e) => e,
-            _ => std::io::Error::new(std::io::ErrorKind::Other, e),
+            #[cfg(feature = "tokio")]
+            Error::Expired(e) => e.into(),
+            _ => std::io::Error::other(e),
         }
     }
 }
@@ -76,4 +103,4 @@ impl From<tokio::time::error::Elapsed> for Error {
 }
 
 /// The library's `Result` type alias.
-pub type Result<T, E = Error> = std::result::Result<T, E>;
+pub type Result<T, E = Error> = core::result::Result<T, E>;
@@ -1,10 +1,19 @@
-#![doc = include_str!("../README.md")]
+#![cfg_attr(feature = "std", doc = include_str!("../README.md"))]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-#[cfg(feature = "tokio")]
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod client;
+#[cfg(feature = "codec")]
+pub mod codec;
 pub mod error;
 pub mod protocol;
 #[cfg(feature = "tokio")]
+pub mod resolver;
+#[cfg(feature = "tokio")]
 pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 pub use crate::error::{Error, Result};
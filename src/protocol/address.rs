@@ -1,17 +1,22 @@
 #[cfg(feature = "tokio")]
 use crate::protocol::AsyncStreamOperation;
-use crate::protocol::StreamOperation;
+use crate::protocol::{need_more, Decode, Decoded, StreamOperation};
+use alloc::{
+    borrow::ToOwned,
+    string::{String, ToString},
+    vec::Vec,
+};
 #[cfg(feature = "tokio")]
 use async_trait::async_trait;
 use bytes::BufMut;
-use std::{
-    io::Cursor,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
-};
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "std")]
+use std::net::ToSocketAddrs;
 #[cfg(feature = "tokio")]
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[repr(u8)]
 pub enum AddressType {
     IPv4 = 0x01,
@@ -20,14 +25,13 @@ pub enum AddressType {
 }
 
 impl TryFrom<u8> for AddressType {
-    type Error = std::io::Error;
+    type Error = crate::Error;
     fn try_from(code: u8) -> core::result::Result<Self, Self::Error> {
-        let err = format!("Unsupported address type code {0:#x}", code);
         match code {
             0x01 => Ok(AddressType::IPv4),
             0x03 => Ok(AddressType::Domain),
             0x04 => Ok(AddressType::IPv6),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err)),
+            _ => Err(crate::Error::InvalidAtyp(code)),
         }
     }
 }
@@ -87,9 +91,51 @@ impl Address {
     pub const fn max_serialized_len() -> usize {
         1 + 1 + u8::MAX as usize + 2
     }
+
+    /// Constructs a `DomainAddress`, rejecting a domain longer than the 255 bytes its length field can express.
+    pub fn try_domain<D: Into<String>>(domain: D, port: u16) -> crate::Result<Self> {
+        let addr = Self::DomainAddress(domain.into(), port);
+        addr.validate()?;
+        Ok(addr)
+    }
+
+    /// Checks that the address can be encoded without truncation.
+    pub fn validate(&self) -> crate::Result<()> {
+        match self {
+            Self::DomainAddress(addr, _) if addr.len() > u8::MAX as usize => Err(crate::Error::FieldTooLong("domain", addr.len())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like [`write_to_buf`](StreamOperation::write_to_buf), but fails instead of writing a corrupt frame
+    /// when the address does not pass [`validate`](Self::validate).
+    pub fn try_write_to_buf<B: BufMut>(&self, buf: &mut B) -> crate::Result<()> {
+        self.validate()?;
+        self.write_to_buf(buf);
+        Ok(())
+    }
+
+    /// Resolves the address into socket addresses with the given [`Resolver`](crate::resolver::Resolver),
+    /// without blocking the runtime the way [`ToSocketAddrs`] does.
+    ///
+    /// A socket address, or a domain that is an IP literal, is returned as is without consulting the resolver.
+    #[cfg(feature = "tokio")]
+    pub async fn resolve<R>(&self, resolver: &R) -> std::io::Result<Vec<SocketAddr>>
+    where
+        R: crate::resolver::Resolver + ?Sized,
+    {
+        match self {
+            Self::SocketAddress(addr) => Ok(alloc::vec![*addr]),
+            Self::DomainAddress(domain, port) => match domain.parse::<IpAddr>() {
+                Ok(ip) => Ok(alloc::vec![SocketAddr::new(ip, *port)]),
+                Err(_) => resolver.resolve(domain, *port).await,
+            },
+        }
+    }
 }
 
 impl StreamOperation for Address {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R: std::io::Read>(stream: &mut R) -> std::io::Result<Self> {
         let mut atyp = [0; 1];
         stream.read_exact(&mut atyp)?;
@@ -105,7 +151,7 @@ impl StreamOperation for Address {
                 let mut len = [0; 1];
                 stream.read_exact(&mut len)?;
                 let len = len[0] as usize;
-                let mut buf = vec![0; len + 2];
+                let mut buf = alloc::vec![0; len + 2];
                 stream.read_exact(&mut buf)?;
 
                 let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
@@ -162,6 +208,45 @@ impl StreamOperation for Address {
     }
 }
 
+impl Decode for Address {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>> {
+        let Some(&atyp) = buf.first() else {
+            return Ok(Decoded::NeedMore(1));
+        };
+        match AddressType::try_from(atyp)? {
+            AddressType::IPv4 => {
+                if let Some(more) = need_more(buf, 1 + 4 + 2) {
+                    return Ok(more);
+                }
+                let addr = Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]);
+                let port = u16::from_be_bytes([buf[5], buf[6]]);
+                Ok(Decoded::Complete(Self::from((addr, port)), 1 + 4 + 2))
+            }
+            AddressType::Domain => {
+                if let Some(more) = need_more(buf, 2) {
+                    return Ok(more);
+                }
+                let len = buf[1] as usize;
+                if let Some(more) = need_more(buf, 2 + len + 2) {
+                    return Ok(more);
+                }
+                let addr = core::str::from_utf8(&buf[2..2 + len])?.to_owned();
+                let port = u16::from_be_bytes([buf[2 + len], buf[2 + len + 1]]);
+                Ok(Decoded::Complete(Self::DomainAddress(addr, port), 2 + len + 2))
+            }
+            AddressType::IPv6 => {
+                if let Some(more) = need_more(buf, 1 + 16 + 2) {
+                    return Ok(more);
+                }
+                let mut addr_bytes = [0; 16];
+                addr_bytes.copy_from_slice(&buf[1..17]);
+                let port = u16::from_be_bytes([buf[17], buf[18]]);
+                Ok(Decoded::Complete(Self::from((Ipv6Addr::from(addr_bytes), port)), 1 + 16 + 2))
+            }
+        }
+    }
+}
+
 #[cfg(feature = "tokio")]
 #[async_trait]
 impl AsyncStreamOperation for Address {
@@ -182,7 +267,7 @@ impl AsyncStreamOperation for Address {
             }
             AddressType::Domain => {
                 let len = stream.read_u8().await? as usize;
-                let mut buf = vec![0; len + 2];
+                let mut buf = alloc::vec![0; len + 2];
                 stream.read_exact(&mut buf).await?;
 
                 let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
@@ -209,20 +294,23 @@ impl AsyncStreamOperation for Address {
     }
 }
 
+#[cfg(feature = "std")]
 impl ToSocketAddrs for Address {
     type Iter = std::vec::IntoIter<SocketAddr>;
 
     fn to_socket_addrs(&self) -> std::io::Result<Self::Iter> {
         match self {
-            Address::SocketAddress(addr) => Ok(vec![*addr].into_iter()),
+            Address::SocketAddress(addr) => Ok(alloc::vec![*addr].into_iter()),
             Address::DomainAddress(addr, port) => Ok((addr.as_str(), *port).to_socket_addrs()?),
         }
     }
 }
 
-impl std::fmt::Display for Address {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+/// Formats as `host:port`, with the host in brackets if it contains a colon, so that it parses back with `TryFrom<&str>`.
+impl core::fmt::Display for Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
+            Address::DomainAddress(hostname, port) if hostname.contains(':') => write!(f, "[{hostname}]:{port}"),
             Address::DomainAddress(hostname, port) => write!(f, "{hostname}:{port}"),
             Address::SocketAddress(socket_addr) => write!(f, "{socket_addr}"),
         }
@@ -230,9 +318,9 @@ impl std::fmt::Display for Address {
 }
 
 impl TryFrom<Address> for SocketAddr {
-    type Error = std::io::Error;
+    type Error = crate::Error;
 
-    fn try_from(address: Address) -> std::result::Result<Self, Self::Error> {
+    fn try_from(address: Address) -> core::result::Result<Self, Self::Error> {
         match address {
             Address::SocketAddress(addr) => Ok(addr),
             Address::DomainAddress(addr, port) => {
@@ -241,8 +329,7 @@ impl TryFrom<Address> for SocketAddr {
                 } else if let Ok(addr) = addr.parse::<Ipv6Addr>() {
                     Ok(SocketAddr::from((addr, port)))
                 } else {
-                    let err = format!("domain address {addr} is not supported");
-                    Err(Self::Error::new(std::io::ErrorKind::Unsupported, err))
+                    Err(alloc::format!("domain address {addr} is not supported").into())
                 }
             }
         }
@@ -250,9 +337,9 @@ impl TryFrom<Address> for SocketAddr {
 }
 
 impl TryFrom<&Address> for SocketAddr {
-    type Error = std::io::Error;
+    type Error = crate::Error;
 
-    fn try_from(address: &Address) -> std::result::Result<Self, Self::Error> {
+    fn try_from(address: &Address) -> core::result::Result<Self, Self::Error> {
         TryFrom::<Address>::try_from(address.clone())
     }
 }
@@ -266,20 +353,21 @@ impl From<Address> for Vec<u8> {
 }
 
 impl TryFrom<Vec<u8>> for Address {
-    type Error = std::io::Error;
+    type Error = crate::Error;
 
-    fn try_from(data: Vec<u8>) -> std::result::Result<Self, Self::Error> {
-        let mut rdr = Cursor::new(data);
-        Self::retrieve_from_stream(&mut rdr)
+    fn try_from(data: Vec<u8>) -> core::result::Result<Self, Self::Error> {
+        Self::try_from(data.as_slice())
     }
 }
 
 impl TryFrom<&[u8]> for Address {
-    type Error = std::io::Error;
+    type Error = crate::Error;
 
-    fn try_from(data: &[u8]) -> std::result::Result<Self, Self::Error> {
-        let mut rdr = Cursor::new(data);
-        Self::retrieve_from_stream(&mut rdr)
+    fn try_from(data: &[u8]) -> core::result::Result<Self, Self::Error> {
+        match Self::decode(data)? {
+            Decoded::Complete(addr, _) => Ok(addr),
+            Decoded::NeedMore(_) => Err("Truncated address".into()),
+        }
     }
 }
 
@@ -331,26 +419,62 @@ impl From<&Address> for Address {
     }
 }
 
+/// Parses `host:port`, `[host]:port`, `host` or `[host]`, where a missing port is `0`.
+///
+/// A host that is an IP address, bracketed or bare, yields a `SocketAddress`, and anything else a `DomainAddress`.
 impl TryFrom<&str> for Address {
     type Error = crate::Error;
 
-    fn try_from(addr: &str) -> std::result::Result<Self, Self::Error> {
+    fn try_from(addr: &str) -> core::result::Result<Self, Self::Error> {
         if let Ok(addr) = addr.parse::<SocketAddr>() {
-            Ok(Address::SocketAddress(addr))
-        } else {
-            let (addr, port) = if let Some(pos) = addr.rfind(':') {
-                (&addr[..pos], &addr[pos + 1..])
+            return Ok(Address::SocketAddress(addr));
+        }
+        if let Ok(ip) = addr.parse::<IpAddr>() {
+            return Ok(Address::from((ip, 0)));
+        }
+        let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or("Missing closing bracket in address")?;
+            let port = if rest.is_empty() {
+                "0"
             } else {
-                (addr, "0")
+                rest.strip_prefix(':').ok_or("Unexpected characters after closing bracket")?
             };
-            let port = port.parse::<u16>()?;
-            Ok(Address::DomainAddress(addr.to_owned(), port))
+            (host, port)
+        } else if let Some(pos) = addr.rfind(':') {
+            (&addr[..pos], &addr[pos + 1..])
+        } else {
+            (addr, "0")
+        };
+        let port = port.parse::<u16>()?;
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Ok(Address::from((ip, port))),
+            Err(_) => Ok(Address::DomainAddress(host.to_owned(), port)),
         }
     }
 }
 
+/// Serializes as the `host:port` string produced by `Display`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Deserializes from a string, using the same parsing as `TryFrom<&str>`.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let addr = String::deserialize(deserializer)?;
+        Address::try_from(addr.as_str()).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "std")]
 #[test]
 fn test_address() {
+    use std::io::Cursor;
+
     let addr = Address::from((Ipv4Addr::new(127, 0, 0, 1), 8080));
     let mut buf = Vec::new();
     addr.write_to_buf(&mut buf);
@@ -373,9 +497,87 @@ fn test_address() {
     assert_eq!(addr, addr2);
 }
 
+#[test]
+fn test_address_parse() {
+    let v6 = |port| Address::from((Ipv6Addr::LOCALHOST, port));
+    assert_eq!(Address::try_from("[::1]:8080").unwrap(), v6(8080));
+    assert_eq!(Address::try_from("[::1]").unwrap(), v6(0));
+    assert_eq!(Address::try_from("::1").unwrap(), v6(0));
+    assert_eq!(Address::try_from("127.0.0.1").unwrap(), Address::from((Ipv4Addr::LOCALHOST, 0)));
+    assert_eq!(Address::try_from("example.com:80").unwrap(), Address::from(("example.com", 80)));
+    assert_eq!(Address::try_from("[example.com]:80").unwrap(), Address::from(("example.com", 80)));
+    assert_eq!(Address::try_from("example.com").unwrap(), Address::from(("example.com", 0)));
+    assert!(Address::try_from("[::1").is_err());
+    assert!(Address::try_from("[::1]80").is_err());
+    assert!(Address::try_from("example.com:http").is_err());
+
+    let addr = Address::from(("fe80::zone", 80));
+    assert_eq!(addr.to_string(), "[fe80::zone]:80");
+    assert_eq!(Address::try_from(addr.to_string().as_str()).unwrap(), addr);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_address_serde() {
+    let addrs = [
+        Address::from((Ipv4Addr::new(127, 0, 0, 1), 8080)),
+        Address::from((Ipv6Addr::new(0x45, 0xff89, 0, 0, 0, 0, 0, 1), 8080)),
+        Address::from(("example.com", 443)),
+    ];
+    let json = serde_json::to_string(&addrs).unwrap();
+    assert_eq!(json, r#"["127.0.0.1:8080","[45:ff89::1]:8080","example.com:443"]"#);
+    let addrs2: Vec<Address> = serde_json::from_str(&json).unwrap();
+    assert_eq!(addrs2, addrs);
+
+    let addr: Address = serde_json::from_str(r#""[::1]""#).unwrap();
+    assert_eq!(addr, Address::from((Ipv6Addr::LOCALHOST, 0)));
+    assert!(serde_json::from_str::<Address>(r#""example.com:99999""#).is_err());
+}
+
+#[test]
+fn test_address_validate() {
+    let domain = "a".repeat(255);
+    let addr = Address::try_domain(domain.as_str(), 80).unwrap();
+    let mut buf = Vec::new();
+    addr.try_write_to_buf(&mut buf).unwrap();
+    assert_eq!(buf.len(), addr.len());
+
+    let domain = "a".repeat(300);
+    assert!(matches!(
+        Address::try_domain(domain.as_str(), 80),
+        Err(crate::Error::FieldTooLong("domain", 300))
+    ));
+    let addr = Address::from((domain.as_str(), 80));
+    let mut buf = Vec::new();
+    assert!(addr.try_write_to_buf(&mut buf).is_err());
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_address_decode() {
+    use crate::protocol::assert_incremental_decode;
+
+    let addr = Address::from((Ipv4Addr::new(127, 0, 0, 1), 8080));
+    assert_eq!(assert_incremental_decode::<Address>(&Vec::from(addr.clone())), addr);
+
+    let addr = Address::from((Ipv6Addr::new(0x45, 0xff89, 0, 0, 0, 0, 0, 1), 8080));
+    assert_eq!(assert_incremental_decode::<Address>(&Vec::from(addr.clone())), addr);
+
+    let addr = Address::from(("sex.com".to_owned(), 8080));
+    assert_eq!(assert_incremental_decode::<Address>(&Vec::from(addr.clone())), addr);
+
+    assert!(matches!(Address::decode(&[0x02]), Err(crate::Error::InvalidAtyp(0x02))));
+    assert!(matches!(
+        Address::decode(&[0x03, 0x01, 0xff, 0, 80]),
+        Err(crate::Error::Utf8Error(_))
+    ));
+}
+
 #[cfg(feature = "tokio")]
 #[tokio::test]
 async fn test_address_async() {
+    use std::io::Cursor;
+
     let addr = Address::from((Ipv4Addr::new(127, 0, 0, 1), 8080));
     let mut buf = Vec::new();
     addr.write_to_async_stream(&mut buf).await.unwrap();
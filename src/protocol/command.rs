@@ -1,4 +1,5 @@
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum Command {
     Connect = 0x01,
     Bind = 0x02,
@@ -6,15 +7,14 @@ pub enum Command {
 }
 
 impl TryFrom<u8> for Command {
-    type Error = std::io::Error;
+    type Error = crate::Error;
 
-    fn try_from(code: u8) -> std::result::Result<Self, Self::Error> {
-        let err = format!("Unsupported command code {0:#x}", code);
+    fn try_from(code: u8) -> core::result::Result<Self, Self::Error> {
         match code {
             0x01 => Ok(Command::Connect),
             0x02 => Ok(Command::Bind),
             0x03 => Ok(Command::UdpAssociate),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err)),
+            _ => Err(crate::Error::InvalidCommand(code)),
         }
     }
 }
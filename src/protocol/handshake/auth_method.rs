@@ -1,6 +1,7 @@
 /// A proxy authentication method.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum AuthMethod {
     /// No authentication required.
     NoAuth = 0x00,
@@ -48,8 +49,8 @@ impl From<&AuthMethod> for u8 {
     }
 }
 
-impl std::fmt::Display for AuthMethod {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             AuthMethod::NoAuth => write!(f, "NoAuth"),
             AuthMethod::GssApi => write!(f, "GssApi"),
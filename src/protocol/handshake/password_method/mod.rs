@@ -1,32 +1,33 @@
 mod request;
 mod response;
 
-use serde::{Deserialize, Serialize};
-
 pub use self::{
     request::Request,
     response::{Response, Status},
 };
 
+use alloc::{string::String, vec::Vec};
+
 pub const SUBNEGOTIATION_VERSION: u8 = 0x01;
 
 /// Required for a username + password authentication.
-#[derive(Default, Debug, Eq, PartialEq, Clone, Hash, Deserialize, Serialize)]
+#[derive(Default, Debug, Eq, PartialEq, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub struct UserKey {
     pub username: String,
     pub password: String,
 }
 
-impl std::fmt::Display for UserKey {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for UserKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
         match (self.username.is_empty(), self.password.is_empty()) {
             (true, true) => write!(f, ""),
             (true, false) => write!(f, ":{}", percent_encode(self.password.as_bytes(), NON_ALPHANUMERIC)),
             (false, true) => write!(f, "{}", percent_encode(self.username.as_bytes(), NON_ALPHANUMERIC)),
             (false, false) => {
-                let username = percent_encode(self.username.as_bytes(), NON_ALPHANUMERIC).to_string();
-                let password = percent_encode(self.password.as_bytes(), NON_ALPHANUMERIC).to_string();
+                let username = percent_encode(self.username.as_bytes(), NON_ALPHANUMERIC);
+                let password = percent_encode(self.password.as_bytes(), NON_ALPHANUMERIC);
                 write!(f, "{}:{}", username, password)
             }
         }
@@ -46,6 +47,28 @@ impl UserKey {
         }
     }
 
+    /// Constructs `UserKey` like [`new`](Self::new), rejecting a username or password longer than 255 bytes.
+    pub fn try_new<U, P>(username: U, password: P) -> crate::Result<Self>
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        let user_key = Self::new(username, password);
+        user_key.validate()?;
+        Ok(user_key)
+    }
+
+    /// Checks that the username and password fit in the one-byte length fields of the sub-negotiation request.
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.username.len() > u8::MAX as usize {
+            return Err(crate::Error::FieldTooLong("username", self.username.len()));
+        }
+        if self.password.len() > u8::MAX as usize {
+            return Err(crate::Error::FieldTooLong("password", self.password.len()));
+        }
+        Ok(())
+    }
+
     pub fn username_arr(&self) -> Vec<u8> {
         self.username.as_bytes().to_vec()
     }
@@ -65,4 +88,14 @@ fn test_user_key() {
     assert_eq!(user_key.to_string(), ":password");
     let user_key = UserKey::new("", "");
     assert_eq!(user_key.to_string(), "");
+
+    assert!(UserKey::try_new("username", "a".repeat(255)).is_ok());
+    assert!(matches!(
+        UserKey::try_new("a".repeat(256), ""),
+        Err(crate::Error::FieldTooLong("username", 256))
+    ));
+    assert!(matches!(
+        UserKey::try_new("", "a".repeat(256)),
+        Err(crate::Error::FieldTooLong("password", 256))
+    ));
 }
@@ -1,6 +1,6 @@
 #[cfg(feature = "tokio")]
 use crate::protocol::AsyncStreamOperation;
-use crate::protocol::{StreamOperation, UserKey};
+use crate::protocol::{need_more, Decode, Decoded, StreamOperation, UserKey};
 #[cfg(feature = "tokio")]
 use async_trait::async_trait;
 #[cfg(feature = "tokio")]
@@ -26,9 +26,17 @@ impl Request {
         let user_key = UserKey::new(username, password);
         Self { user_key }
     }
+
+    /// Like [`write_to_buf`](StreamOperation::write_to_buf), but fails instead of truncating an over-long username or password.
+    pub fn try_write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) -> crate::Result<()> {
+        self.user_key.validate()?;
+        self.write_to_buf(buf);
+        Ok(())
+    }
 }
 
 impl StreamOperation for Request {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> std::io::Result<Self> {
         let mut ver = [0; 1];
         r.read_exact(&mut ver)?;
@@ -74,6 +82,29 @@ impl StreamOperation for Request {
     }
 }
 
+impl Decode for Request {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>> {
+        if let Some(more) = need_more(buf, 2) {
+            return Ok(more);
+        }
+        if buf[0] != super::SUBNEGOTIATION_VERSION {
+            return Err(crate::Error::InvalidAuthSubnegotiation(buf[0]));
+        }
+        let ulen = buf[1] as usize;
+        if let Some(more) = need_more(buf, 2 + ulen + 1) {
+            return Ok(more);
+        }
+        let plen = buf[2 + ulen] as usize;
+        let len = 2 + ulen + 1 + plen;
+        if let Some(more) = need_more(buf, len) {
+            return Ok(more);
+        }
+        let username = core::str::from_utf8(&buf[2..2 + ulen])?;
+        let password = core::str::from_utf8(&buf[2 + ulen + 1..len])?;
+        Ok(Decoded::Complete(Self::new(username, password), len))
+    }
+}
+
 #[cfg(feature = "tokio")]
 #[async_trait]
 impl AsyncStreamOperation for Request {
@@ -104,3 +135,24 @@ impl AsyncStreamOperation for Request {
         Ok(Self { user_key })
     }
 }
+
+#[test]
+fn test_password_request_decode() {
+    use crate::protocol::assert_incremental_decode;
+
+    let req = Request::new("username", "pass@word");
+    let mut buf = Vec::new();
+    req.write_to_buf(&mut buf);
+    let req2 = assert_incremental_decode::<Request>(&buf);
+    assert_eq!(req2.user_key, req.user_key);
+
+    let req = Request::new("", "");
+    let mut buf = Vec::new();
+    req.write_to_buf(&mut buf);
+    assert_eq!(assert_incremental_decode::<Request>(&buf).user_key, req.user_key);
+
+    assert!(matches!(
+        Request::decode(&[0x05, 0x00]),
+        Err(crate::Error::InvalidAuthSubnegotiation(0x05))
+    ));
+}
@@ -1,6 +1,6 @@
 #[cfg(feature = "tokio")]
 use crate::protocol::AsyncStreamOperation;
-use crate::protocol::StreamOperation;
+use crate::protocol::{need_more, Decode, Decoded, StreamOperation};
 #[cfg(feature = "tokio")]
 use async_trait::async_trait;
 #[cfg(feature = "tokio")]
@@ -20,20 +20,19 @@ impl From<Status> for u8 {
 }
 
 impl TryFrom<u8> for Status {
-    type Error = std::io::Error;
+    type Error = crate::Error;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        let err = format!("Invalid sub-negotiation status {0:#x}", value);
         match value {
             0x00 => Ok(Status::Succeeded),
             0xff => Ok(Status::Failed),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+            _ => Err(crate::Error::InvalidAuthStatus(value)),
         }
     }
 }
 
-impl std::fmt::Display for Status {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Status {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             Status::Succeeded => write!(f, "Succeeded"),
             Status::Failed => write!(f, "Failed"),
@@ -62,6 +61,7 @@ impl Response {
 }
 
 impl StreamOperation for Response {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> std::io::Result<Self> {
         let mut ver = [0; 1];
         r.read_exact(&mut ver)?;
@@ -88,6 +88,19 @@ impl StreamOperation for Response {
     }
 }
 
+impl Decode for Response {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>> {
+        if let Some(more) = need_more(buf, 2) {
+            return Ok(more);
+        }
+        if buf[0] != super::SUBNEGOTIATION_VERSION {
+            return Err(crate::Error::InvalidAuthSubnegotiation(buf[0]));
+        }
+        let status = Status::try_from(buf[1])?;
+        Ok(Decoded::Complete(Self { status }, 2))
+    }
+}
+
 #[cfg(feature = "tokio")]
 #[async_trait]
 impl AsyncStreamOperation for Response {
@@ -106,3 +119,15 @@ impl AsyncStreamOperation for Response {
         Ok(Self { status })
     }
 }
+
+#[test]
+fn test_password_response_decode() {
+    use crate::protocol::assert_incremental_decode;
+
+    let resp = assert_incremental_decode::<Response>(&[0x01, 0x00]);
+    assert_eq!(resp.status, Status::Succeeded);
+    assert!(matches!(
+        Response::decode(&[0x01, 0x07]),
+        Err(crate::Error::InvalidAuthStatus(0x07))
+    ));
+}
@@ -1,6 +1,7 @@
 #[cfg(feature = "tokio")]
 use crate::protocol::AsyncStreamOperation;
-use crate::protocol::{AuthMethod, StreamOperation, Version};
+use crate::protocol::{check_v5, need_more, AuthMethod, Decode, Decoded, StreamOperation, Version};
+use alloc::vec::Vec;
 #[cfg(feature = "tokio")]
 use async_trait::async_trait;
 #[cfg(feature = "tokio")]
@@ -25,12 +26,33 @@ impl Request {
         Self { methods }
     }
 
+    /// Returns the methods offered by the client, in its order of preference.
+    pub fn methods(&self) -> &[AuthMethod] {
+        &self.methods
+    }
+
     pub fn evaluate_method(&self, server_method: AuthMethod) -> bool {
-        self.methods.iter().any(|&m| m == server_method)
+        self.methods.contains(&server_method)
+    }
+
+    /// Checks that the methods fit in the one-byte count of the request.
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.methods.len() > u8::MAX as usize {
+            return Err(crate::Error::FieldTooLong("methods", self.methods.len()));
+        }
+        Ok(())
+    }
+
+    /// Like [`write_to_buf`](StreamOperation::write_to_buf), but fails instead of writing a corrupt frame for more than 255 methods.
+    pub fn try_write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) -> crate::Result<()> {
+        self.validate()?;
+        self.write_to_buf(buf);
+        Ok(())
     }
 }
 
 impl StreamOperation for Request {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> std::io::Result<Self> {
         let mut ver = [0; 1];
         r.read_exact(&mut ver)?;
@@ -45,7 +67,7 @@ impl StreamOperation for Request {
         r.read_exact(&mut mlen)?;
         let mlen = mlen[0];
 
-        let mut methods = vec![0; mlen as usize];
+        let mut methods = alloc::vec![0; mlen as usize];
         r.read_exact(&mut methods)?;
 
         let methods = methods.into_iter().map(AuthMethod::from).collect();
@@ -66,6 +88,21 @@ impl StreamOperation for Request {
     }
 }
 
+impl Decode for Request {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>> {
+        if let Some(more) = need_more(buf, 2) {
+            return Ok(more);
+        }
+        check_v5(buf[0])?;
+        let mlen = buf[1] as usize;
+        if let Some(more) = need_more(buf, 2 + mlen) {
+            return Ok(more);
+        }
+        let methods = buf[2..2 + mlen].iter().copied().map(AuthMethod::from).collect();
+        Ok(Decoded::Complete(Self { methods }, 2 + mlen))
+    }
+}
+
 #[cfg(feature = "tokio")]
 #[async_trait]
 impl AsyncStreamOperation for Request {
@@ -81,7 +118,7 @@ impl AsyncStreamOperation for Request {
         }
 
         let mlen = r.read_u8().await?;
-        let mut methods = vec![0; mlen as usize];
+        let mut methods = alloc::vec![0; mlen as usize];
         r.read_exact(&mut methods).await?;
 
         let methods = methods.into_iter().map(AuthMethod::from).collect();
@@ -89,3 +126,30 @@ impl AsyncStreamOperation for Request {
         Ok(Self { methods })
     }
 }
+
+#[test]
+fn test_handshake_request_decode() {
+    use crate::protocol::assert_incremental_decode;
+
+    let req = assert_incremental_decode::<Request>(&[0x05, 0x02, 0x00, 0x02]);
+    assert!(req.evaluate_method(AuthMethod::NoAuth));
+    assert!(req.evaluate_method(AuthMethod::UserPass));
+    assert!(!req.evaluate_method(AuthMethod::GssApi));
+
+    assert!(matches!(Request::decode(&[0x04, 0x01]), Err(crate::Error::WrongVersion)));
+}
+
+#[test]
+fn test_handshake_request_too_many_methods() {
+    let mut buf = Vec::new();
+    Request::new(alloc::vec![AuthMethod::NoAuth; 255])
+        .try_write_to_buf(&mut buf)
+        .unwrap();
+    assert_eq!(buf.len(), 257);
+
+    let req = Request::new(alloc::vec![AuthMethod::NoAuth; 256]);
+    assert!(matches!(
+        req.try_write_to_buf(&mut Vec::new()),
+        Err(crate::Error::FieldTooLong("methods", 256))
+    ));
+}
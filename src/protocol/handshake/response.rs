@@ -1,6 +1,6 @@
 #[cfg(feature = "tokio")]
 use crate::protocol::AsyncStreamOperation;
-use crate::protocol::{AuthMethod, StreamOperation, Version};
+use crate::protocol::{check_v5, need_more, AuthMethod, Decode, Decoded, StreamOperation, Version};
 #[cfg(feature = "tokio")]
 use async_trait::async_trait;
 #[cfg(feature = "tokio")]
@@ -27,6 +27,7 @@ impl Response {
 }
 
 impl StreamOperation for Response {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> std::io::Result<Self> {
         let mut ver = [0; 1];
         r.read_exact(&mut ver)?;
@@ -54,6 +55,17 @@ impl StreamOperation for Response {
     }
 }
 
+impl Decode for Response {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>> {
+        if let Some(more) = need_more(buf, 2) {
+            return Ok(more);
+        }
+        check_v5(buf[0])?;
+        let method = AuthMethod::from(buf[1]);
+        Ok(Decoded::Complete(Self { method }, 2))
+    }
+}
+
 #[cfg(feature = "tokio")]
 #[async_trait]
 impl AsyncStreamOperation for Response {
@@ -73,3 +85,11 @@ impl AsyncStreamOperation for Response {
         Ok(Self { method })
     }
 }
+
+#[test]
+fn test_handshake_response_decode() {
+    use crate::protocol::assert_incremental_decode;
+
+    let resp = assert_incremental_decode::<Response>(&[0x05, 0xff]);
+    assert_eq!(resp.method, AuthMethod::NoAcceptableMethods);
+}
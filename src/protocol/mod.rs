@@ -4,8 +4,11 @@ pub mod handshake;
 mod reply;
 mod request;
 mod response;
+pub mod socks4;
 mod udp;
 
+#[cfg(feature = "std")]
+pub use self::udp::UdpReassembler;
 pub use self::{
     address::{Address, AddressType},
     command::Command,
@@ -19,8 +22,12 @@ pub use self::{
     udp::UdpHeader,
 };
 
-#[cfg(feature = "tokio")]
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
 use async_trait::async_trait;
+#[cfg(feature = "futures-io")]
+use futures_util::io::{AsyncReadExt as _, AsyncWriteExt as _};
 #[cfg(feature = "tokio")]
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
@@ -33,13 +40,13 @@ pub enum Version {
 }
 
 impl TryFrom<u8> for Version {
-    type Error = std::io::Error;
+    type Error = crate::Error;
 
-    fn try_from(value: u8) -> std::io::Result<Self> {
+    fn try_from(value: u8) -> crate::Result<Self> {
         match value {
             4 => Ok(Version::V4),
             5 => Ok(Version::V5),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid version")),
+            _ => Err(crate::Error::InvalidVersion(value)),
         }
     }
 }
@@ -50,19 +57,66 @@ impl From<Version> for u8 {
     }
 }
 
-impl std::fmt::Display for Version {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let v: u8 = (*self).into();
         write!(f, "{}", v)
     }
 }
 
+/// The outcome of decoding a message from a buffer that may not hold all of it yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decoded<T> {
+    /// A complete message, along with the number of bytes it occupies at the front of the buffer.
+    Complete(T, usize),
+    /// The buffer ends before the message does. At least this many more bytes are needed to make progress.
+    NeedMore(usize),
+}
+
+impl<T> Decoded<T> {
+    /// Maps a complete message with `f`, shifting its length by `offset` bytes of already consumed prefix.
+    #[inline]
+    pub(crate) fn then<U, F: FnOnce(T) -> U>(self, offset: usize, f: F) -> Decoded<U> {
+        match self {
+            Decoded::Complete(msg, len) => Decoded::Complete(f(msg), offset + len),
+            Decoded::NeedMore(n) => Decoded::NeedMore(n),
+        }
+    }
+}
+
+/// Push-style decoding of a message from a byte slice, for use in event loops that own their buffers.
+///
+/// The buffer is never consumed: on [`Decoded::Complete`] the caller advances its buffer by the reported length,
+/// on [`Decoded::NeedMore`] it waits for more bytes and calls `decode` again with the grown buffer.
+/// Nothing is copied out of the buffer except the strings the message owns.
+pub trait Decode: Sized {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>>;
+}
+
+/// Returns `Some(NeedMore(..))` if `buf` is shorter than `len`.
+#[inline]
+pub(crate) fn need_more<T>(buf: &[u8], len: usize) -> Option<Decoded<T>> {
+    (buf.len() < len).then(|| Decoded::NeedMore(len - buf.len()))
+}
+
+/// Checks the SOCKS version byte of a SOCKS5 message.
+#[inline]
+pub(crate) fn check_v5(ver: u8) -> crate::Result<()> {
+    match Version::try_from(ver)? {
+        Version::V5 => Ok(()),
+        Version::V4 => Err(crate::Error::WrongVersion),
+    }
+}
+
+/// Encoding of a message into a [`BufMut`](bytes::BufMut), and with the `std` feature, blocking I/O on `Read`/`Write` streams.
 pub trait StreamOperation {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R>(stream: &mut R) -> std::io::Result<Self>
     where
         R: std::io::Read,
         Self: Sized;
 
+    #[cfg(feature = "std")]
     fn write_to_stream<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
         let mut buf = Vec::with_capacity(self.len());
         self.write_to_buf(&mut buf);
@@ -95,3 +149,62 @@ pub trait AsyncStreamOperation: StreamOperation {
         w.write_all(&buf).await
     }
 }
+
+/// The [`futures-io`](https://docs.rs/futures-io) counterpart of [`AsyncStreamOperation`], for runtimes other than tokio
+/// such as smol or async-std.
+///
+/// Implemented for every message that implements [`Decode`]: reading fetches exactly the bytes the decoder asks for,
+/// so nothing past the end of the message is consumed from the stream.
+#[cfg(feature = "futures-io")]
+#[async_trait]
+pub trait FuturesStreamOperation: StreamOperation + Decode + Send + Sync {
+    async fn retrieve_from_futures_stream<R>(r: &mut R) -> std::io::Result<Self>
+    where
+        R: futures_util::io::AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        loop {
+            match Self::decode(&buf)? {
+                Decoded::Complete(msg, _) => return Ok(msg),
+                Decoded::NeedMore(n) => {
+                    let len = buf.len();
+                    buf.resize(len + n, 0);
+                    r.read_exact(&mut buf[len..]).await?;
+                }
+            }
+        }
+    }
+
+    async fn write_to_futures_stream<W>(&self, w: &mut W) -> std::io::Result<()>
+    where
+        W: futures_util::io::AsyncWrite + Unpin + Send,
+    {
+        let mut buf = Vec::with_capacity(self.len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T: StreamOperation + Decode + Send + Sync> FuturesStreamOperation for T {}
+
+/// Asserts that `T` decodes `bytes` as one complete message, and that every strict prefix of it
+/// asks for more bytes without overshooting the message length.
+#[cfg(test)]
+pub(crate) fn assert_incremental_decode<T: Decode + core::fmt::Debug>(bytes: &[u8]) -> T {
+    for i in 0..bytes.len() {
+        match T::decode(&bytes[..i]).unwrap() {
+            Decoded::NeedMore(n) => assert!(n >= 1 && i + n <= bytes.len(), "prefix {i}: NeedMore({n})"),
+            other => panic!("prefix {i}: unexpected {other:?}"),
+        }
+    }
+    let mut extended = bytes.to_vec();
+    extended.extend_from_slice(&[0xee; 4]);
+    match T::decode(&extended).unwrap() {
+        Decoded::Complete(msg, len) => {
+            assert_eq!(len, bytes.len());
+            msg
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+}
@@ -1,5 +1,6 @@
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum Reply {
     Succeeded = 0x00,
     GeneralFailure = 0x01,
@@ -13,10 +14,9 @@ pub enum Reply {
 }
 
 impl TryFrom<u8> for Reply {
-    type Error = std::io::Error;
+    type Error = crate::Error;
 
     fn try_from(code: u8) -> Result<Self, Self::Error> {
-        let err = format!("Unsupported reply code {0:#x}", code);
         match code {
             0x00 => Ok(Reply::Succeeded),
             0x01 => Ok(Reply::GeneralFailure),
@@ -27,7 +27,7 @@ impl TryFrom<u8> for Reply {
             0x06 => Ok(Reply::TtlExpired),
             0x07 => Ok(Reply::CommandNotSupported),
             0x08 => Ok(Reply::AddressTypeNotSupported),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err)),
+            _ => Err(crate::Error::InvalidReply(code)),
         }
     }
 }
@@ -48,8 +48,8 @@ impl From<Reply> for u8 {
     }
 }
 
-impl std::fmt::Display for Reply {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Reply {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let s = match self {
             Reply::Succeeded => "Reply::Succeeded",
             Reply::GeneralFailure => "Reply::GeneralFailure",
@@ -95,4 +95,22 @@ mod tests {
         assert_eq!(u8::from(Reply::CommandNotSupported), 0x07);
         assert_eq!(u8::from(Reply::AddressTypeNotSupported), 0x08);
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn reply_serde() {
+        use crate::protocol::{AddressType, AuthMethod, Command};
+
+        let json = serde_json::to_string(&(Reply::ConnectionNotAllowed, Command::UdpAssociate, AddressType::IPv6)).unwrap();
+        assert_eq!(json, r#"["ConnectionNotAllowed","UdpAssociate","IPv6"]"#);
+        let (reply, command, atyp): (Reply, Command, AddressType) = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            (reply, command, atyp),
+            (Reply::ConnectionNotAllowed, Command::UdpAssociate, AddressType::IPv6)
+        );
+
+        let methods = vec![AuthMethod::NoAuth, AuthMethod::Private(0x80)];
+        let json = serde_json::to_string(&methods).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<AuthMethod>>(&json).unwrap(), methods);
+    }
 }
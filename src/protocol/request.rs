@@ -1,6 +1,6 @@
 #[cfg(feature = "tokio")]
 use crate::protocol::AsyncStreamOperation;
-use crate::protocol::{Address, Command, StreamOperation, Version};
+use crate::protocol::{check_v5, need_more, Address, Command, Decode, Decoded, StreamOperation, Version};
 #[cfg(feature = "tokio")]
 use async_trait::async_trait;
 #[cfg(feature = "tokio")]
@@ -25,9 +25,17 @@ impl Request {
     pub fn new(command: Command, address: Address) -> Self {
         Self { command, address }
     }
+
+    /// Like [`write_to_buf`](StreamOperation::write_to_buf), but fails instead of writing a corrupt frame for an over-long domain.
+    pub fn try_write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) -> crate::Result<()> {
+        self.address.validate()?;
+        self.write_to_buf(buf);
+        Ok(())
+    }
 }
 
 impl StreamOperation for Request {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R: std::io::Read>(stream: &mut R) -> std::io::Result<Self> {
         let mut ver = [0u8; 1];
         stream.read_exact(&mut ver)?;
@@ -59,6 +67,17 @@ impl StreamOperation for Request {
     }
 }
 
+impl Decode for Request {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>> {
+        if let Some(more) = need_more(buf, 3) {
+            return Ok(more);
+        }
+        check_v5(buf[0])?;
+        let command = Command::try_from(buf[1])?;
+        Ok(Address::decode(&buf[3..])?.then(3, |address| Self { command, address }))
+    }
+}
+
 #[cfg(feature = "tokio")]
 #[async_trait]
 impl AsyncStreamOperation for Request {
@@ -82,3 +101,25 @@ impl AsyncStreamOperation for Request {
         Ok(Self { command, address })
     }
 }
+
+#[test]
+fn test_request_decode() {
+    use crate::protocol::assert_incremental_decode;
+
+    let req = Request::new(Command::Connect, Address::from(("example.com", 443)));
+    let mut buf = Vec::new();
+    req.write_to_buf(&mut buf);
+    let req2 = assert_incremental_decode::<Request>(&buf);
+    assert_eq!(req2.command, req.command);
+    assert_eq!(req2.address, req.address);
+
+    assert!(matches!(Request::decode(&[0x04, 0x01, 0x00]), Err(crate::Error::WrongVersion)));
+    assert!(matches!(
+        Request::decode(&[0x06, 0x01, 0x00]),
+        Err(crate::Error::InvalidVersion(0x06))
+    ));
+    assert!(matches!(
+        Request::decode(&[0x05, 0x09, 0x00]),
+        Err(crate::Error::InvalidCommand(0x09))
+    ));
+}
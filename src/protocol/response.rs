@@ -1,6 +1,6 @@
 #[cfg(feature = "tokio")]
 use crate::protocol::AsyncStreamOperation;
-use crate::protocol::{Address, Reply, StreamOperation, Version};
+use crate::protocol::{check_v5, need_more, Address, Decode, Decoded, Reply, StreamOperation, Version};
 #[cfg(feature = "tokio")]
 use async_trait::async_trait;
 #[cfg(feature = "tokio")]
@@ -25,9 +25,17 @@ impl Response {
     pub fn new(reply: Reply, address: Address) -> Self {
         Self { reply, address }
     }
+
+    /// Like [`write_to_buf`](StreamOperation::write_to_buf), but fails instead of writing a corrupt frame for an over-long domain.
+    pub fn try_write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) -> crate::Result<()> {
+        self.address.validate()?;
+        self.write_to_buf(buf);
+        Ok(())
+    }
 }
 
 impl StreamOperation for Response {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R: std::io::Read>(stream: &mut R) -> std::io::Result<Self> {
         let mut ver = [0u8; 1];
         stream.read_exact(&mut ver)?;
@@ -59,6 +67,17 @@ impl StreamOperation for Response {
     }
 }
 
+impl Decode for Response {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>> {
+        if let Some(more) = need_more(buf, 3) {
+            return Ok(more);
+        }
+        check_v5(buf[0])?;
+        let reply = Reply::try_from(buf[1])?;
+        Ok(Address::decode(&buf[3..])?.then(3, |address| Self { reply, address }))
+    }
+}
+
 #[cfg(feature = "tokio")]
 #[async_trait]
 impl AsyncStreamOperation for Response {
@@ -82,3 +101,21 @@ impl AsyncStreamOperation for Response {
         Ok(Self { reply, address })
     }
 }
+
+#[test]
+fn test_response_decode() {
+    use crate::protocol::assert_incremental_decode;
+    use std::net::Ipv6Addr;
+
+    let resp = Response::new(Reply::Succeeded, Address::from((Ipv6Addr::LOCALHOST, 1080)));
+    let mut buf = Vec::new();
+    resp.write_to_buf(&mut buf);
+    let resp2 = assert_incremental_decode::<Response>(&buf);
+    assert_eq!(resp2.reply, resp.reply);
+    assert_eq!(resp2.address, resp.address);
+
+    assert!(matches!(
+        Response::decode(&[0x05, 0x09, 0x00]),
+        Err(crate::Error::InvalidReply(0x09))
+    ));
+}
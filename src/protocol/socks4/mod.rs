@@ -0,0 +1,55 @@
+mod reply;
+mod request;
+mod response;
+
+pub use self::{reply::Reply, request::Request, response::Response};
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The version byte of a SOCKS4 reply, which is always `0x00` rather than `0x04`.
+pub const REPLY_VERSION: u8 = 0x00;
+
+/// The maximum length of a NULL-terminated field (USERID or HOSTNAME) accepted when decoding.
+pub const MAX_FIELD_LEN: usize = u8::MAX as usize;
+
+/// Returns `true` if the destination IP signals a SOCKS4a request, i.e. `0.0.0.x` with `x` non-zero.
+pub(crate) fn is_socks4a(ip: &core::net::Ipv4Addr) -> bool {
+    let [a, b, c, d] = ip.octets();
+    a == 0 && b == 0 && c == 0 && d != 0
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn read_null_terminated<R: std::io::Read>(r: &mut R) -> std::io::Result<String> {
+    let mut buf = Vec::new();
+    loop {
+        let mut byte = [0; 1];
+        r.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        if buf.len() == MAX_FIELD_LEN {
+            let err = format!("SOCKS4 field exceeds {MAX_FIELD_LEN} bytes");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+        }
+        buf.push(byte[0]);
+    }
+    String::from_utf8(buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) async fn read_null_terminated_async<R: AsyncRead + Unpin + Send>(r: &mut R) -> std::io::Result<String> {
+    let mut buf = Vec::new();
+    loop {
+        let byte = r.read_u8().await?;
+        if byte == 0 {
+            break;
+        }
+        if buf.len() == MAX_FIELD_LEN {
+            let err = format!("SOCKS4 field exceeds {MAX_FIELD_LEN} bytes");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+        }
+        buf.push(byte);
+    }
+    String::from_utf8(buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
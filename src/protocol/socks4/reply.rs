@@ -0,0 +1,65 @@
+/// SOCKS4 reply code (the `CD` field of a reply)
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Reply {
+    /// Request granted.
+    Granted = 0x5a,
+    /// Request rejected or failed.
+    Rejected = 0x5b,
+    /// Request rejected because SOCKS server cannot connect to identd on the client.
+    IdentdUnreachable = 0x5c,
+    /// Request rejected because the client program and identd report different user-ids.
+    IdentdMismatch = 0x5d,
+}
+
+impl TryFrom<u8> for Reply {
+    type Error = crate::Error;
+
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        match code {
+            0x5a => Ok(Reply::Granted),
+            0x5b => Ok(Reply::Rejected),
+            0x5c => Ok(Reply::IdentdUnreachable),
+            0x5d => Ok(Reply::IdentdMismatch),
+            _ => Err(crate::Error::InvalidReply(code)),
+        }
+    }
+}
+
+impl From<Reply> for u8 {
+    fn from(reply: Reply) -> Self {
+        reply as u8
+    }
+}
+
+/// SOCKS4 has no fine-grained failure codes, so every SOCKS5 failure maps to [`Reply::Rejected`].
+impl From<crate::protocol::Reply> for Reply {
+    fn from(reply: crate::protocol::Reply) -> Self {
+        match reply {
+            crate::protocol::Reply::Succeeded => Reply::Granted,
+            _ => Reply::Rejected,
+        }
+    }
+}
+
+impl core::fmt::Display for Reply {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let s = match self {
+            Reply::Granted => "Reply::Granted",
+            Reply::Rejected => "Reply::Rejected",
+            Reply::IdentdUnreachable => "Reply::IdentdUnreachable",
+            Reply::IdentdMismatch => "Reply::IdentdMismatch",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[test]
+fn test_socks4_reply() {
+    for reply in [Reply::Granted, Reply::Rejected, Reply::IdentdUnreachable, Reply::IdentdMismatch] {
+        assert_eq!(Reply::try_from(u8::from(reply)).unwrap(), reply);
+    }
+    assert!(Reply::try_from(0x00).is_err());
+    assert_eq!(Reply::from(crate::protocol::Reply::Succeeded), Reply::Granted);
+    assert_eq!(Reply::from(crate::protocol::Reply::HostUnreachable), Reply::Rejected);
+}
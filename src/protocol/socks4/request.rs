@@ -0,0 +1,249 @@
+#[cfg(feature = "tokio")]
+use crate::protocol::AsyncStreamOperation;
+use crate::protocol::{Address, Command, StreamOperation, Version};
+use alloc::string::{String, ToString};
+#[cfg(feature = "tokio")]
+use async_trait::async_trait;
+#[cfg(feature = "std")]
+use core::net::Ipv4Addr;
+use core::net::SocketAddr;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// SOCKS4 and SOCKS4a request
+///
+/// ```plain
+/// +----+----+---------+-------+----------+------+------------+------+
+/// | VN | CD | DSTPORT | DSTIP |  USERID  | NULL |  HOSTNAME  | NULL |
+/// +----+----+---------+-------+----------+------+------------+------+
+/// | 1  | 1  |    2    |   4   | Variable |  1   | (Variable) | (1)  |
+/// +----+----+---------+-------+----------+------+------------+------+
+/// ```
+///
+/// `HOSTNAME` is only present in a SOCKS4a request, which is signalled by a `DSTIP` of `0.0.0.x` with `x` non-zero.
+/// A [`Address::DomainAddress`] is encoded as SOCKS4a, and so is an IPv6 [`Address::SocketAddress`],
+/// whose textual form is sent as the hostname since SOCKS4 has no IPv6 address field.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub command: Command,
+    pub address: Address,
+    pub user_id: String,
+}
+
+impl Request {
+    pub fn new<U: Into<String>>(command: Command, address: Address, user_id: U) -> Self {
+        let user_id = user_id.into();
+        Self { command, address, user_id }
+    }
+
+    /// Checks that the request encodes into one that decodes back the same: the user ID and hostname must fit in
+    /// [`MAX_FIELD_LEN`](super::MAX_FIELD_LEN) bytes without a NUL byte, and an IPv4 destination must not be a `0.0.0.x`
+    /// address, which reads as a SOCKS4a request.
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.user_id.len() > super::MAX_FIELD_LEN {
+            return Err(crate::Error::FieldTooLong("user_id", self.user_id.len()));
+        }
+        if self.user_id.contains('\0') {
+            return Err("SOCKS4 user_id contains a NUL byte".into());
+        }
+        match &self.address {
+            Address::SocketAddress(SocketAddr::V4(addr)) if super::is_socks4a(addr.ip()) => {
+                Err("SOCKS4 destination 0.0.0.x is reserved for SOCKS4a requests".into())
+            }
+            Address::DomainAddress(domain, _) if domain.len() > super::MAX_FIELD_LEN => {
+                Err(crate::Error::FieldTooLong("hostname", domain.len()))
+            }
+            Address::DomainAddress(domain, _) if domain.contains('\0') => Err("SOCKS4a hostname contains a NUL byte".into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like [`write_to_buf`](StreamOperation::write_to_buf), but fails instead of writing a request that does not pass
+    /// [`validate`](Self::validate).
+    pub fn try_write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) -> crate::Result<()> {
+        self.validate()?;
+        self.write_to_buf(buf);
+        Ok(())
+    }
+
+    fn hostname(&self) -> Option<String> {
+        match &self.address {
+            Address::SocketAddress(SocketAddr::V4(_)) => None,
+            Address::SocketAddress(SocketAddr::V6(addr)) => Some(addr.ip().to_string()),
+            Address::DomainAddress(domain, _) => Some(domain.clone()),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn parse_header(buf: &[u8; 8]) -> std::io::Result<(Command, u16, Ipv4Addr)> {
+        let ver = Version::try_from(buf[0])?;
+        if ver != Version::V4 {
+            let err = format!("Unsupported SOCKS version {0:#x}", u8::from(ver));
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, err));
+        }
+
+        let command = Command::try_from(buf[1])?;
+        if command == Command::UdpAssociate {
+            let err = "UDP ASSOCIATE is not a SOCKS4 command";
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err));
+        }
+
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        Ok((command, port, ip))
+    }
+}
+
+impl StreamOperation for Request {
+    #[cfg(feature = "std")]
+    fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> std::io::Result<Self> {
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf)?;
+        let (command, port, ip) = Self::parse_header(&buf)?;
+
+        let user_id = super::read_null_terminated(r)?;
+
+        let address = if super::is_socks4a(&ip) {
+            Address::DomainAddress(super::read_null_terminated(r)?, port)
+        } else {
+            Address::from((ip, port))
+        };
+
+        Ok(Self { command, address, user_id })
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(Version::V4.into());
+        buf.put_u8(u8::from(self.command));
+        buf.put_u16(self.address.port());
+        match &self.address {
+            Address::SocketAddress(SocketAddr::V4(addr)) => buf.put_slice(&addr.ip().octets()),
+            _ => buf.put_slice(&[0, 0, 0, 1]),
+        }
+        buf.put_slice(self.user_id.as_bytes());
+        buf.put_u8(0x00);
+        if let Some(hostname) = self.hostname() {
+            buf.put_slice(hostname.as_bytes());
+            buf.put_u8(0x00);
+        }
+    }
+
+    fn len(&self) -> usize {
+        let hostname_len = self.hostname().map(|h| h.len() + 1).unwrap_or_default();
+        8 + self.user_id.len() + 1 + hostname_len
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[async_trait]
+impl AsyncStreamOperation for Request {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> std::io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf).await?;
+        let (command, port, ip) = Self::parse_header(&buf)?;
+
+        let user_id = super::read_null_terminated_async(r).await?;
+
+        let address = if super::is_socks4a(&ip) {
+            Address::DomainAddress(super::read_null_terminated_async(r).await?, port)
+        } else {
+            Address::from((ip, port))
+        };
+
+        Ok(Self { command, address, user_id })
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_socks4_request() {
+    use std::io::Cursor;
+
+    let req = Request::new(Command::Connect, Address::from((Ipv4Addr::new(127, 0, 0, 1), 8080)), "alice");
+    let mut buf = Vec::new();
+    req.write_to_buf(&mut buf);
+    assert_eq!(buf, vec![0x04, 0x01, 0x1f, 0x90, 127, 0, 0, 1, b'a', b'l', b'i', b'c', b'e', 0x00]);
+    assert_eq!(buf.len(), req.len());
+    let req2 = Request::retrieve_from_stream(&mut Cursor::new(&buf)).unwrap();
+    assert_eq!(req2.command, Command::Connect);
+    assert_eq!(req2.address, req.address);
+    assert_eq!(req2.user_id, "alice");
+
+    let req = Request::new(Command::Bind, Address::from(("example.com", 21)), "");
+    let mut buf = Vec::new();
+    req.write_to_buf(&mut buf);
+    let mut expected = vec![0x04, 0x02, 0x00, 0x15, 0, 0, 0, 1, 0x00];
+    expected.extend_from_slice(b"example.com\0");
+    assert_eq!(buf, expected);
+    assert_eq!(buf.len(), req.len());
+    let req2 = Request::retrieve_from_stream(&mut Cursor::new(&buf)).unwrap();
+    assert_eq!(req2.command, Command::Bind);
+    assert_eq!(req2.address, req.address);
+    assert_eq!(req2.user_id, "");
+
+    let req = Request::new(Command::Connect, Address::from((std::net::Ipv6Addr::LOCALHOST, 80)), "bob");
+    let mut buf = Vec::new();
+    req.write_to_buf(&mut buf);
+    assert_eq!(buf.len(), req.len());
+    let req2 = Request::retrieve_from_stream(&mut Cursor::new(&buf)).unwrap();
+    assert_eq!(req2.address, Address::from(("::1", 80)));
+
+    let buf = [0x04, 0x03, 0x00, 0x50, 1, 2, 3, 4, 0x00];
+    assert!(Request::retrieve_from_stream(&mut Cursor::new(&buf)).is_err());
+    let buf = [0x05, 0x01, 0x00, 0x50, 1, 2, 3, 4, 0x00];
+    assert!(Request::retrieve_from_stream(&mut Cursor::new(&buf)).is_err());
+    let mut buf = vec![0x04, 0x01, 0x00, 0x50, 1, 2, 3, 4];
+    buf.extend_from_slice(&[b'x'; 300]);
+    buf.push(0x00);
+    assert!(Request::retrieve_from_stream(&mut Cursor::new(&buf)).is_err());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_socks4_request_validate() {
+    let ip = |ip: [u8; 4]| Address::from((Ipv4Addr::from(ip), 80));
+    let mut buf = Vec::new();
+    let req = Request::new(Command::Connect, Address::from(("a".repeat(255), 80)), "b".repeat(255));
+    req.try_write_to_buf(&mut buf).unwrap();
+    assert_eq!(buf.len(), req.len());
+    assert!(Request::new(Command::Connect, ip([0, 0, 0, 0]), "").validate().is_ok());
+
+    let req = Request::new(Command::Connect, ip([1, 2, 3, 4]), "a".repeat(256));
+    assert!(matches!(req.validate(), Err(crate::Error::FieldTooLong("user_id", 256))));
+    let req = Request::new(Command::Connect, Address::from(("a".repeat(256), 80)), "");
+    assert!(matches!(req.validate(), Err(crate::Error::FieldTooLong("hostname", 256))));
+    for req in [
+        Request::new(Command::Connect, ip([1, 2, 3, 4]), "ali\0ce"),
+        Request::new(Command::Connect, Address::from(("example.com\0", 80)), ""),
+        Request::new(Command::Connect, ip([0, 0, 0, 7]), ""),
+    ] {
+        assert!(
+            matches!(req.try_write_to_buf(&mut Vec::new()), Err(crate::Error::String(_))),
+            "{req:?}"
+        );
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_socks4_request_async() {
+    use std::io::Cursor;
+
+    let req = Request::new(Command::Connect, Address::from((Ipv4Addr::new(10, 0, 0, 1), 443)), "alice");
+    let mut buf = Vec::new();
+    req.write_to_async_stream(&mut buf).await.unwrap();
+    let req2 = Request::retrieve_from_async_stream(&mut Cursor::new(&buf)).await.unwrap();
+    assert_eq!(req2.command, req.command);
+    assert_eq!(req2.address, req.address);
+    assert_eq!(req2.user_id, req.user_id);
+
+    let req = Request::new(Command::Connect, Address::from(("example.com", 443)), "alice");
+    let mut buf = Vec::new();
+    req.write_to_async_stream(&mut buf).await.unwrap();
+    let req2 = Request::retrieve_from_async_stream(&mut Cursor::new(&buf)).await.unwrap();
+    assert_eq!(req2.address, req.address);
+    assert_eq!(req2.user_id, req.user_id);
+}
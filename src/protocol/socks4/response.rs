@@ -0,0 +1,110 @@
+#[cfg(feature = "tokio")]
+use crate::protocol::AsyncStreamOperation;
+use crate::protocol::{socks4::Reply, StreamOperation};
+#[cfg(feature = "tokio")]
+use async_trait::async_trait;
+#[cfg(feature = "std")]
+use core::net::Ipv4Addr;
+use core::net::SocketAddrV4;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// SOCKS4 and SOCKS4a response
+///
+/// ```plain
+/// +----+----+---------+-------+
+/// | VN | CD | DSTPORT | DSTIP |
+/// +----+----+---------+-------+
+/// | 1  | 1  |    2    |   4   |
+/// +----+----+---------+-------+
+/// ```
+///
+/// `VN` is the reply version [`REPLY_VERSION`](super::REPLY_VERSION), not the SOCKS version.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub reply: Reply,
+    pub address: SocketAddrV4,
+}
+
+impl Response {
+    pub fn new(reply: Reply, address: SocketAddrV4) -> Self {
+        Self { reply, address }
+    }
+
+    #[cfg(feature = "std")]
+    fn parse(buf: &[u8; 8]) -> std::io::Result<Self> {
+        if buf[0] != super::REPLY_VERSION {
+            let err = format!("Unsupported SOCKS4 reply version {0:#x}", buf[0]);
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, err));
+        }
+        let reply = Reply::try_from(buf[1])?;
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        Ok(Self::new(reply, SocketAddrV4::new(ip, port)))
+    }
+}
+
+impl StreamOperation for Response {
+    #[cfg(feature = "std")]
+    fn retrieve_from_stream<R: std::io::Read>(r: &mut R) -> std::io::Result<Self> {
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf)?;
+        Self::parse(&buf)
+    }
+
+    fn write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) {
+        buf.put_u8(super::REPLY_VERSION);
+        buf.put_u8(u8::from(self.reply));
+        buf.put_u16(self.address.port());
+        buf.put_slice(&self.address.ip().octets());
+    }
+
+    fn len(&self) -> usize {
+        8
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[async_trait]
+impl AsyncStreamOperation for Response {
+    async fn retrieve_from_async_stream<R>(r: &mut R) -> std::io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf).await?;
+        Self::parse(&buf)
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_socks4_response() {
+    use std::io::Cursor;
+
+    let resp = Response::new(Reply::Granted, SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 1080));
+    let mut buf = Vec::new();
+    resp.write_to_buf(&mut buf);
+    assert_eq!(buf, vec![0x00, 0x5a, 0x04, 0x38, 192, 168, 1, 1]);
+    let resp2 = Response::retrieve_from_stream(&mut Cursor::new(&buf)).unwrap();
+    assert_eq!(resp2.reply, resp.reply);
+    assert_eq!(resp2.address, resp.address);
+
+    let buf = [0x04, 0x5a, 0x04, 0x38, 192, 168, 1, 1];
+    assert!(Response::retrieve_from_stream(&mut Cursor::new(&buf)).is_err());
+    let buf = [0x00, 0x00, 0x04, 0x38, 192, 168, 1, 1];
+    assert!(Response::retrieve_from_stream(&mut Cursor::new(&buf)).is_err());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_socks4_response_async() {
+    use std::io::Cursor;
+
+    let resp = Response::new(Reply::Rejected, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+    let mut buf = Vec::new();
+    resp.write_to_async_stream(&mut buf).await.unwrap();
+    let resp2 = Response::retrieve_from_async_stream(&mut Cursor::new(&buf)).await.unwrap();
+    assert_eq!(resp2.reply, Reply::Rejected);
+    assert_eq!(resp2.address, resp.address);
+}
@@ -1,6 +1,7 @@
 #[cfg(feature = "tokio")]
 use crate::protocol::AsyncStreamOperation;
-use crate::protocol::{Address, StreamOperation};
+use crate::protocol::{need_more, Address, Decode, Decoded, StreamOperation};
+use alloc::{format, vec, vec::Vec};
 #[cfg(feature = "tokio")]
 use async_trait::async_trait;
 #[cfg(feature = "tokio")]
@@ -29,9 +30,166 @@ impl UdpHeader {
     pub const fn max_serialized_len() -> usize {
         3 + Address::max_serialized_len()
     }
+
+    /// Like [`write_to_buf`](StreamOperation::write_to_buf), but fails instead of writing a corrupt header for an over-long domain.
+    pub fn try_write_to_buf<B: bytes::BufMut>(&self, buf: &mut B) -> crate::Result<()> {
+        self.address.validate()?;
+        self.write_to_buf(buf);
+        Ok(())
+    }
+}
+
+impl UdpHeader {
+    /// The bit of [`frag`](Self::frag) marking the last fragment of a datagram, RFC 1928 section 7.
+    pub const END_OF_SEQUENCE: u8 = 0x80;
+
+    /// The highest fragment position, as positions are the low seven bits of [`frag`](Self::frag).
+    pub const MAX_FRAGMENTS: usize = 0x7f;
+
+    /// Splits `payload`, destined to or coming from `address`, into datagrams of at most `mtu` bytes, headers included.
+    ///
+    /// A payload that fits is returned as a single unfragmented datagram. Otherwise fragments are numbered from 1,
+    /// the last one carrying [`END_OF_SEQUENCE`](Self::END_OF_SEQUENCE). Fails if `mtu` leaves no room for data after
+    /// the header, or if more than [`MAX_FRAGMENTS`](Self::MAX_FRAGMENTS) fragments would be needed.
+    pub fn fragment(address: &Address, payload: &[u8], mtu: usize) -> crate::Result<Vec<Vec<u8>>> {
+        address.validate()?;
+        let header_len = 3 + address.len();
+        if header_len + payload.len() <= mtu {
+            let mut buf = Vec::with_capacity(header_len + payload.len());
+            Self::new(0, address.clone()).write_to_buf(&mut buf);
+            buf.extend_from_slice(payload);
+            return Ok(vec![buf]);
+        }
+
+        let chunk = mtu.saturating_sub(header_len);
+        if chunk == 0 || payload.len().div_ceil(chunk) > Self::MAX_FRAGMENTS {
+            let msg = format!(
+                "a {} bytes payload cannot be split into {} fragments of {mtu} bytes",
+                payload.len(),
+                Self::MAX_FRAGMENTS
+            );
+            return Err(crate::Error::String(msg));
+        }
+        let count = payload.len().div_ceil(chunk);
+        let datagrams = payload
+            .chunks(chunk)
+            .enumerate()
+            .map(|(i, data)| {
+                let mut frag = i as u8 + 1;
+                if i + 1 == count {
+                    frag |= Self::END_OF_SEQUENCE;
+                }
+                let mut buf = Vec::with_capacity(header_len + data.len());
+                Self::new(frag, address.clone()).write_to_buf(&mut buf);
+                buf.extend_from_slice(data);
+                buf
+            })
+            .collect();
+        Ok(datagrams)
+    }
+}
+
+/// The reassembly queue and timer of RFC 1928 section 7, for one stream of fragmented UDP datagrams.
+///
+/// Fragments are pushed in the order they arrive. The queue is abandoned when its timer expires, when a fragment
+/// does not directly follow the previous one, or when it carries another address; a fragment at position 1 then
+/// starts a new queue. Unfragmented datagrams are passed through, and abandon any queue too.
+///
+/// ```
+/// use socks5_impl::protocol::{Address, UdpHeader, UdpReassembler};
+///
+/// let mut reassembler = UdpReassembler::default();
+/// let addr = Address::from(("example.com", 53));
+/// assert_eq!(reassembler.push(1, &addr, b"hello "), None);
+/// assert_eq!(reassembler.push(2 | UdpHeader::END_OF_SEQUENCE, &addr, b"world"), Some((addr, b"hello world".to_vec())));
+/// ```
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct UdpReassembler {
+    timeout: std::time::Duration,
+    queue: Option<ReassemblyQueue>,
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+struct ReassemblyQueue {
+    address: Address,
+    position: u8,
+    started: std::time::Instant,
+    data: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl UdpReassembler {
+    /// The shortest reassembly timer allowed by RFC 1928, and the default one.
+    pub const MIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    /// Creates a reassembler whose queue is abandoned `timeout` after its first fragment. Shorter timeouts than
+    /// [`MIN_TIMEOUT`](Self::MIN_TIMEOUT) are raised to it.
+    pub fn new(timeout: std::time::Duration) -> Self {
+        Self {
+            timeout: timeout.max(Self::MIN_TIMEOUT),
+            queue: None,
+        }
+    }
+
+    /// Returns the reassembly timeout.
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout
+    }
+
+    /// Returns whether fragments are waiting for the rest of their datagram, and their timer has not expired yet.
+    pub fn is_pending(&self) -> bool {
+        self.queue.as_ref().is_some_and(|queue| queue.started.elapsed() < self.timeout)
+    }
+
+    /// Pushes a received datagram with its `frag` field, and returns the complete datagram once there is one.
+    pub fn push(&mut self, frag: u8, address: &Address, data: &[u8]) -> Option<(Address, Vec<u8>)> {
+        self.push_at(std::time::Instant::now(), frag, address, data)
+    }
+
+    fn push_at(&mut self, now: std::time::Instant, frag: u8, address: &Address, data: &[u8]) -> Option<(Address, Vec<u8>)> {
+        if frag == 0 {
+            self.queue = None;
+            return Some((address.clone(), data.to_vec()));
+        }
+
+        let position = frag & !UdpHeader::END_OF_SEQUENCE;
+        let queue = self
+            .queue
+            .take()
+            .filter(|queue| now.duration_since(queue.started) < self.timeout)
+            .filter(|queue| queue.position + 1 == position && &queue.address == address);
+        let mut queue = match queue {
+            Some(queue) => queue,
+            None if position == 1 => ReassemblyQueue {
+                address: address.clone(),
+                position: 0,
+                started: now,
+                data: Vec::new(),
+            },
+            None => return None,
+        };
+
+        queue.position = position;
+        queue.data.extend_from_slice(data);
+        if frag & UdpHeader::END_OF_SEQUENCE != 0 {
+            return Some((queue.address, queue.data));
+        }
+        self.queue = Some(queue);
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for UdpReassembler {
+    fn default() -> Self {
+        Self::new(Self::MIN_TIMEOUT)
+    }
 }
 
 impl StreamOperation for UdpHeader {
+    #[cfg(feature = "std")]
     fn retrieve_from_stream<R: std::io::Read>(stream: &mut R) -> std::io::Result<Self> {
         let mut buf = [0; 3];
         stream.read_exact(&mut buf)?;
@@ -53,6 +211,16 @@ impl StreamOperation for UdpHeader {
     }
 }
 
+impl Decode for UdpHeader {
+    fn decode(buf: &[u8]) -> crate::Result<Decoded<Self>> {
+        if let Some(more) = need_more(buf, 3) {
+            return Ok(more);
+        }
+        let frag = buf[2];
+        Ok(Address::decode(&buf[3..])?.then(3, |address| Self { frag, address }))
+    }
+}
+
 #[cfg(feature = "tokio")]
 #[async_trait]
 impl AsyncStreamOperation for UdpHeader {
@@ -69,3 +237,93 @@ impl AsyncStreamOperation for UdpHeader {
         Ok(Self { frag, address })
     }
 }
+
+#[test]
+fn test_udp_header_decode() {
+    use crate::protocol::assert_incremental_decode;
+
+    let header = UdpHeader::new(0, Address::from(("example.com", 53)));
+    let mut buf = Vec::new();
+    header.write_to_buf(&mut buf);
+    let header2 = assert_incremental_decode::<UdpHeader>(&buf);
+    assert_eq!(header2.frag, header.frag);
+    assert_eq!(header2.address, header.address);
+
+    buf.extend_from_slice(b"payload");
+    match UdpHeader::decode(&buf).unwrap() {
+        Decoded::Complete(_, len) => assert_eq!(&buf[len..], b"payload"),
+        Decoded::NeedMore(_) => panic!("header is complete"),
+    }
+}
+
+#[test]
+fn test_udp_header_fragment() {
+    let addr = Address::from(("example.com", 53));
+    let header_len = UdpHeader::new(0, addr.clone()).len();
+
+    let datagrams = UdpHeader::fragment(&addr, b"hello", 64).unwrap();
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(datagrams[0][2], 0);
+
+    let payload = (0..=255).collect::<Vec<u8>>();
+    let datagrams = UdpHeader::fragment(&addr, &payload, header_len + 100).unwrap();
+    let frags = datagrams.iter().map(|datagram| datagram[2]).collect::<Vec<_>>();
+    assert_eq!(frags, [1, 2, 3 | UdpHeader::END_OF_SEQUENCE]);
+    assert!(datagrams.iter().all(|datagram| datagram.len() <= header_len + 100));
+
+    let data = datagrams
+        .iter()
+        .flat_map(|datagram| &datagram[header_len..])
+        .copied()
+        .collect::<Vec<_>>();
+    assert_eq!(data, payload);
+
+    assert!(UdpHeader::fragment(&addr, &payload, header_len).is_err());
+    assert!(UdpHeader::fragment(&addr, &payload, header_len + 2).is_err());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_udp_reassembler() {
+    use std::time::{Duration, Instant};
+
+    let addr = Address::from(("example.com", 53));
+    let other = Address::from(("example.org", 53));
+    let end = UdpHeader::END_OF_SEQUENCE;
+    let now = Instant::now();
+    let mut reassembler = UdpReassembler::new(Duration::from_secs(1));
+    assert_eq!(reassembler.timeout(), UdpReassembler::MIN_TIMEOUT);
+
+    // In order.
+    assert_eq!(reassembler.push_at(now, 1, &addr, b"a"), None);
+    assert_eq!(reassembler.push_at(now, 2, &addr, b"b"), None);
+    assert_eq!(
+        reassembler.push_at(now, 3 | end, &addr, b"c"),
+        Some((addr.clone(), b"abc".to_vec()))
+    );
+    assert!(!reassembler.is_pending());
+
+    // A gap or a step back abandons the queue, and only position 1 starts a new one.
+    assert_eq!(reassembler.push_at(now, 1, &addr, b"a"), None);
+    assert_eq!(reassembler.push_at(now, 3, &addr, b"c"), None);
+    assert!(!reassembler.is_pending());
+    assert_eq!(reassembler.push_at(now, 2 | end, &addr, b"b"), None);
+    assert_eq!(reassembler.push_at(now, 1, &addr, b"x"), None);
+    assert_eq!(reassembler.push_at(now, 1, &addr, b"a"), None);
+    assert_eq!(reassembler.push_at(now, 2 | end, &addr, b"b"), Some((addr.clone(), b"ab".to_vec())));
+
+    // Another address abandons the queue.
+    assert_eq!(reassembler.push_at(now, 1, &addr, b"a"), None);
+    assert_eq!(reassembler.push_at(now, 2 | end, &other, b"b"), None);
+
+    // So does an unfragmented datagram, which is passed through.
+    assert_eq!(reassembler.push_at(now, 1, &addr, b"a"), None);
+    assert_eq!(reassembler.push_at(now, 0, &other, b"z"), Some((other.clone(), b"z".to_vec())));
+    assert!(!reassembler.is_pending());
+
+    // And the timer.
+    assert_eq!(reassembler.push_at(now, 1, &addr, b"a"), None);
+    let later = now + UdpReassembler::MIN_TIMEOUT;
+    assert_eq!(reassembler.push_at(later, 2 | end, &addr, b"b"), None);
+    assert!(!reassembler.is_pending());
+}
@@ -0,0 +1,144 @@
+//! Asynchronous name resolution for [`Address`](crate::protocol::Address).
+//!
+//! `Address` also implements [`std::net::ToSocketAddrs`], but that calls `getaddrinfo` and blocks the current thread,
+//! which stalls a runtime worker when done from async code. A [`Resolver`] is used instead by the client and server
+//! layers, and can be swapped for a custom one, e.g. a DNS-over-HTTPS client or the [`StaticResolver`] in tests.
+
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+/// Resolves a host name into the socket addresses it is reachable at.
+#[async_trait]
+pub trait Resolver: Debug + Send + Sync {
+    /// Looks up `host` and returns its addresses with `port`. Returns an error rather than an empty list
+    /// if the host has no address.
+    async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>>;
+}
+
+#[async_trait]
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        (**self).resolve(host, port).await
+    }
+}
+
+/// The default resolver, backed by [`tokio::net::lookup_host`](https://docs.rs/tokio/latest/tokio/net/fn.lookup_host.html),
+/// which runs the system resolver on the blocking thread pool.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioResolver;
+
+#[async_trait]
+impl Resolver for TokioResolver {
+    async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        let addrs = tokio::net::lookup_host((host, port)).await?.collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(not_found(host));
+        }
+        Ok(addrs)
+    }
+}
+
+/// A resolver answering from a fixed hosts map, without touching the network. Host names are matched case-insensitively.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> std::io::Result<()> {
+/// use socks5_impl::{protocol::Address, resolver::StaticResolver};
+///
+/// let resolver = StaticResolver::from_iter([("example.com", "192.0.2.1".parse().unwrap())]);
+/// let addrs = Address::from(("example.com", 80)).resolve(&resolver).await?;
+/// assert_eq!(addrs, ["192.0.2.1:80".parse().unwrap()]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    /// Creates an empty hosts map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `ip` to the addresses of `host`.
+    pub fn insert<H: Into<String>>(&mut self, host: H, ip: IpAddr) -> &mut Self {
+        let host = host.into().to_ascii_lowercase();
+        self.hosts.entry(host).or_default().push(ip);
+        self
+    }
+}
+
+impl<H: Into<String>> FromIterator<(H, IpAddr)> for StaticResolver {
+    fn from_iter<I: IntoIterator<Item = (H, IpAddr)>>(iter: I) -> Self {
+        let mut resolver = Self::new();
+        for (host, ip) in iter {
+            resolver.insert(host, ip);
+        }
+        resolver
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some(ips) if !ips.is_empty() => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect()),
+            _ => Err(not_found(host)),
+        }
+    }
+}
+
+fn not_found(host: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, format!("no address found for host {host}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Resolver, StaticResolver, TokioResolver};
+    use crate::protocol::Address;
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    #[tokio::test]
+    async fn static_resolver() {
+        let mut resolver = StaticResolver::new();
+        resolver
+            .insert("Example.com", IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)))
+            .insert("example.com", IpAddr::from(Ipv6Addr::LOCALHOST));
+
+        let addrs = Address::from(("EXAMPLE.com", 443)).resolve(&resolver).await.unwrap();
+        assert_eq!(
+            addrs,
+            [
+                SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 443)),
+                SocketAddr::from((Ipv6Addr::LOCALHOST, 443))
+            ]
+        );
+
+        let err = Address::from(("unknown.test", 80)).resolve(&resolver).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        // Socket addresses and IP literals never reach the resolver.
+        let addr = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 80));
+        assert_eq!(Address::from(addr).resolve(&resolver).await.unwrap(), [addr]);
+        assert_eq!(Address::from(("10.0.0.1", 80)).resolve(&resolver).await.unwrap(), [addr]);
+
+        let resolver: Arc<dyn Resolver> = Arc::new(resolver);
+        assert_eq!(Address::from(("example.com", 80)).resolve(&resolver).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn tokio_resolver() {
+        let addrs = TokioResolver.resolve("localhost", 80).await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 80));
+    }
+}
@@ -0,0 +1,930 @@
+//! Access control for the requests of authenticated clients.
+//!
+//! An [`Acl`] is an ordered list of allow / deny [`Rule`]s, the first matching rule deciding about a request and the
+//! default action of the list applying when none does. Evaluation is pure, see [`Acl::check()`], and the whole list can be
+//! loaded from a config with the `serde` feature:
+//!
+//! ```
+//! # #[cfg(feature = "serde")]
+//! # fn main() -> socks5_impl::Result<()> {
+//! use socks5_impl::{
+//!     protocol::{Address, Command},
+//!     server::acl::{Acl, AclRequest, Action},
+//! };
+//!
+//! let acl: Acl = serde_json::from_str(
+//!     r#"{
+//!         "default": "deny",
+//!         "rules": [
+//!             { "action": "deny", "destinations": ["10.0.0.0/8", "127.0.0.0/8"] },
+//!             { "action": "allow", "users": ["alice"], "commands": ["Connect"], "domains": [".example.com"], "ports": ["443", "8000-8999"] }
+//!         ]
+//!     }"#,
+//! )
+//! .unwrap();
+//!
+//! let addr = Address::from(("www.example.com", 443));
+//! assert_eq!(acl.check(&AclRequest::new(Command::Connect, &addr).with_user("alice")), Action::Allow);
+//! assert_eq!(acl.check(&AclRequest::new(Command::Connect, &addr).with_user("bob")), Action::Deny);
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "serde"))]
+//! # fn main() {}
+//! ```
+//!
+//! [`AclHandler`] applies an [`Acl`] in [`Server::serve`](crate::server::Server::serve), answering denied requests with
+//! [`Reply::ConnectionNotAllowed`].
+
+use crate::{
+    protocol::{Address, Command, Reply},
+    server::{
+        connection::{associate, bind, connect},
+        AuthUser, Bind, Connect, DefaultHandler, Handler, UdpAssociate,
+    },
+};
+use async_trait::async_trait;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// What happens to a request.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize), serde(rename_all = "lowercase"))]
+pub enum Action {
+    Allow,
+    #[default]
+    Deny,
+}
+
+/// A request as seen by an [`Acl`].
+#[derive(Clone, Copy, Debug)]
+pub struct AclRequest<'a> {
+    client: Option<IpAddr>,
+    user: Option<&'a str>,
+    command: Command,
+    destination: &'a Address,
+}
+
+impl<'a> AclRequest<'a> {
+    /// Creates a request for `command` to `destination`, of an anonymous client without IP address.
+    #[inline]
+    pub fn new(command: Command, destination: &'a Address) -> Self {
+        Self {
+            client: None,
+            user: None,
+            command,
+            destination,
+        }
+    }
+
+    /// Sets the IP address of the client.
+    #[inline]
+    pub fn with_client(mut self, client: IpAddr) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Sets the name the client authenticated as.
+    #[inline]
+    pub fn with_user(mut self, user: &'a str) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    /// Returns the IP address of the client.
+    #[inline]
+    pub fn client(&self) -> Option<IpAddr> {
+        self.client
+    }
+
+    /// Returns the name the client authenticated as.
+    #[inline]
+    pub fn user(&self) -> Option<&'a str> {
+        self.user
+    }
+
+    /// Returns the requested command.
+    #[inline]
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// Returns the requested destination.
+    #[inline]
+    pub fn destination(&self) -> &'a Address {
+        self.destination
+    }
+}
+
+/// An ordered list of [`Rule`]s.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Acl {
+    #[cfg_attr(feature = "serde", serde(default))]
+    default: Action,
+    #[cfg_attr(feature = "serde", serde(default))]
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    /// Creates an empty list, taking `default` on every request.
+    #[inline]
+    pub fn new(default: Action) -> Self {
+        Self {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Appends `rule`, which is only consulted for requests no earlier rule matches.
+    #[inline]
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Returns the action taken when no rule matches, [`Action::Deny`] unless set otherwise.
+    #[inline]
+    pub fn default_action(&self) -> Action {
+        self.default
+    }
+
+    /// Returns the rules in the order they are evaluated.
+    #[inline]
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Returns the first rule matching `request`.
+    pub fn matching_rule(&self, request: &AclRequest<'_>) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(request))
+    }
+
+    /// Decides about `request`.
+    #[inline]
+    pub fn check(&self, request: &AclRequest<'_>) -> Action {
+        self.matching_rule(request).map_or(self.default, |rule| rule.action)
+    }
+}
+
+/// A rule matching requests on all of its criteria, a criterion without entries matching every request.
+///
+/// Within a criterion any entry may match. The destination matches if its IP address is in one of the
+/// [`destinations`](Rule::with_destination) or its domain name matches one of the [`domains`](Rule::with_domain), domain names
+/// being matched as requested, i.e. before they are resolved. Client networks never match clients without an IP address, such as
+/// those of Unix sockets, and users never match anonymous clients.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Rule {
+    action: Action,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    clients: Vec<Cidr>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    users: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    commands: Vec<Command>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    destinations: Vec<Cidr>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    domains: Vec<DomainPattern>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    ports: Vec<PortRange>,
+}
+
+impl Rule {
+    /// Creates a rule taking `action` on every request.
+    #[inline]
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            clients: Vec::new(),
+            users: Vec::new(),
+            commands: Vec::new(),
+            destinations: Vec::new(),
+            domains: Vec::new(),
+            ports: Vec::new(),
+        }
+    }
+
+    /// Creates a rule allowing every request.
+    #[inline]
+    pub fn allow() -> Self {
+        Self::new(Action::Allow)
+    }
+
+    /// Creates a rule denying every request.
+    #[inline]
+    pub fn deny() -> Self {
+        Self::new(Action::Deny)
+    }
+
+    /// Adds a network of client addresses.
+    #[inline]
+    pub fn with_client(mut self, client: Cidr) -> Self {
+        self.clients.push(client);
+        self
+    }
+
+    /// Adds a user name, compared exactly.
+    #[inline]
+    pub fn with_user<U: Into<String>>(mut self, user: U) -> Self {
+        self.users.push(user.into());
+        self
+    }
+
+    /// Adds a command.
+    #[inline]
+    pub fn with_command(mut self, command: Command) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Adds a network of destination addresses. Domains that are IP literals, such as `"127.0.0.1"`, are matched against it
+    /// rather than against the domain patterns.
+    #[inline]
+    pub fn with_destination(mut self, destination: Cidr) -> Self {
+        self.destinations.push(destination);
+        self
+    }
+
+    /// Adds a pattern of destination domain names.
+    #[inline]
+    pub fn with_domain(mut self, domain: DomainPattern) -> Self {
+        self.domains.push(domain);
+        self
+    }
+
+    /// Adds a range of destination ports.
+    #[inline]
+    pub fn with_ports(mut self, ports: PortRange) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    /// Returns the action taken on matching requests.
+    #[inline]
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Returns whether the rule matches `request`.
+    pub fn matches(&self, request: &AclRequest<'_>) -> bool {
+        let client = match request.client {
+            Some(ip) => self.clients.is_empty() || self.clients.iter().any(|net| net.contains(ip)),
+            None => self.clients.is_empty(),
+        };
+        let user = match request.user {
+            Some(name) => self.users.is_empty() || self.users.iter().any(|user| user == name),
+            None => self.users.is_empty(),
+        };
+        let command = self.commands.is_empty() || self.commands.contains(&request.command);
+        let destination = (self.destinations.is_empty() && self.domains.is_empty())
+            || match request.destination {
+                Address::SocketAddress(addr) => self.destinations.iter().any(|net| net.contains(addr.ip())),
+                // A domain that is an IP literal reaches that IP, so it is matched as one.
+                Address::DomainAddress(domain, _) => match domain.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+                    Ok(ip) => self.destinations.iter().any(|net| net.contains(ip)),
+                    Err(_) => self.domains.iter().any(|pattern| pattern.matches(domain)),
+                },
+            };
+        let port = request.destination.port();
+        let ports = self.ports.is_empty() || self.ports.iter().any(|range| range.contains(port));
+        client && user && command && destination && ports
+    }
+}
+
+/// An IP network in CIDR notation, e.g. `192.168.0.0/16` or `fc00::/7`. A bare address is a network of that address only.
+///
+/// IPv4-mapped IPv6 addresses are matched as IPv4 addresses.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Creates the network of `addr` with a prefix of `prefix` bits, clearing the host bits of `addr`.
+    pub fn new(addr: IpAddr, prefix: u8) -> crate::Result<Self> {
+        let addr = addr.to_canonical();
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > bits {
+            return Err(format!("prefix /{prefix} is longer than {bits} bits").into());
+        }
+        let addr = match addr {
+            IpAddr::V4(ip) => IpAddr::from(Ipv4Addr::from(
+                u32::from(ip) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0),
+            )),
+            IpAddr::V6(ip) => IpAddr::from(Ipv6Addr::from(
+                u128::from(ip) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0),
+            )),
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    /// Returns the first address of the network.
+    #[inline]
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the length of the prefix in bits.
+    #[inline]
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Returns whether `ip` is in the network.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match Self::new(ip, self.prefix) {
+            Ok(net) => net.addr == self.addr,
+            Err(_) => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        match s.split_once('/') {
+            Some((addr, prefix)) => Self::new(addr.parse()?, prefix.parse()?),
+            None => {
+                let addr = s.parse::<IpAddr>()?.to_canonical();
+                Self::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = crate::Error;
+
+    fn try_from(s: String) -> crate::Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+/// A pattern of domain names, compared case-insensitively and without a trailing dot.
+///
+/// - `.example.com` is a suffix, matching `example.com` and all its subdomains.
+/// - `*` and `?` make a glob, `*` standing for any number of characters, dots included, and `?` for exactly one.
+///   `cdn-*.example.com` matches `cdn-1.example.com` and `cdn-eu.west.example.com`.
+/// - Anything else matches that domain name only.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct DomainPattern(String);
+
+impl DomainPattern {
+    /// Creates a pattern, see the type documentation for the syntax.
+    pub fn new(pattern: &str) -> crate::Result<Self> {
+        let pattern = normalize_domain(pattern);
+        if pattern.is_empty() || pattern == "." {
+            return Err("empty domain pattern".into());
+        }
+        Ok(Self(pattern))
+    }
+
+    /// Returns whether `domain` matches the pattern.
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = normalize_domain(domain);
+        if let Some(suffix) = self.0.strip_prefix('.') {
+            domain == suffix || domain.strip_suffix(&self.0).is_some_and(|sub| !sub.is_empty())
+        } else {
+            glob_match(self.0.as_bytes(), domain.as_bytes())
+        }
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for DomainPattern {
+    type Error = crate::Error;
+
+    fn try_from(s: String) -> crate::Result<Self> {
+        Self::new(&s)
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<DomainPattern> for String {
+    fn from(pattern: DomainPattern) -> Self {
+        pattern.0
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    let domain = domain.strip_suffix('.').filter(|d| !d.is_empty()).unwrap_or(domain);
+    domain.to_ascii_lowercase()
+}
+
+/// Matches `text` against `pattern` with `*` and `?` wildcards, backtracking only to the last `*`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// An inclusive range of ports, e.g. `8000-8999`, or a single port such as `443`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    /// Creates the range from `start` to `end`, both included.
+    pub fn new(start: u16, end: u16) -> crate::Result<Self> {
+        if start > end {
+            return Err(format!("empty port range {start}-{end}").into());
+        }
+        Ok(Self { start, end })
+    }
+
+    /// Returns the first port of the range.
+    #[inline]
+    pub fn start(&self) -> u16 {
+        self.start
+    }
+
+    /// Returns the last port of the range.
+    #[inline]
+    pub fn end(&self) -> u16 {
+        self.end
+    }
+
+    /// Returns whether `port` is in the range.
+    #[inline]
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl From<u16> for PortRange {
+    fn from(port: u16) -> Self {
+        Self { start: port, end: port }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        match s.split_once('-') {
+            Some((start, end)) => Self::new(start.trim().parse()?, end.trim().parse()?),
+            None => Ok(Self::from(s.trim().parse::<u16>()?)),
+        }
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = crate::Error;
+
+    fn try_from(s: String) -> crate::Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+/// A [`Handler`] checking every request against an [`Acl`] before handing it to an inner handler.
+///
+/// Denied requests are answered with [`Reply::ConnectionNotAllowed`] and the connection is closed.
+/// The destination of a `UDP ASSOCIATE` request is the address the client expects to send datagrams from, so rules are
+/// matched against that address to accept the request, then against the destination of every datagram the built-in relay
+/// forwards, see [`UdpAssociate::with_destination_filter()`]. Datagrams to denied destinations are dropped.
+///
+/// ```no_run
+/// use socks5_impl::server::{
+///     acl::{Acl, AclHandler, Action, Cidr, Rule},
+///     auth, DefaultHandler, Server,
+/// };
+/// use std::sync::Arc;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> socks5_impl::Result<()> {
+/// let acl = Acl::new(Action::Allow).with_rule(Rule::deny().with_destination("127.0.0.0/8".parse()?));
+/// let server = Server::bind("127.0.0.1:1080".parse().unwrap(), Arc::new(auth::NoAuth)).await?;
+/// server.serve(AclHandler::new(Arc::new(acl), DefaultHandler)).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct AclHandler<H = DefaultHandler> {
+    acl: Arc<Acl>,
+    inner: H,
+}
+
+impl<H> AclHandler<H> {
+    /// Checks requests against `acl` before handing them to `inner`.
+    #[inline]
+    pub fn new(acl: Arc<Acl>, inner: H) -> Self {
+        Self { acl, inner }
+    }
+
+    /// Returns the access control list.
+    #[inline]
+    pub fn acl(&self) -> &Arc<Acl> {
+        &self.acl
+    }
+
+    /// Returns the inner handler.
+    #[inline]
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    fn client(&self, user: Option<&str>, peer_addr: std::io::Result<SocketAddr>) -> AclClient {
+        AclClient {
+            acl: self.acl.clone(),
+            ip: peer_addr.ok().map(|addr| addr.ip()),
+            user: user.map(str::to_owned),
+        }
+    }
+}
+
+/// The client of a request, checked against the ACL for the request and, for `UDP ASSOCIATE`, for every datagram.
+struct AclClient {
+    acl: Arc<Acl>,
+    ip: Option<IpAddr>,
+    user: Option<String>,
+}
+
+impl AclClient {
+    fn allows(&self, command: Command, addr: &Address) -> bool {
+        let mut request = AclRequest::new(command, addr);
+        if let Some(ip) = self.ip {
+            request = request.with_client(ip);
+        }
+        if let Some(user) = &self.user {
+            request = request.with_user(user);
+        }
+        let allowed = self.acl.check(&request) == Action::Allow;
+        if !allowed {
+            log::debug!("{command:?} {addr} denied for {:?} at {:?}", request.user(), request.client());
+        }
+        allowed
+    }
+}
+
+#[async_trait]
+impl<O, T, H> Handler<O, T> for AclHandler<H>
+where
+    O: AuthUser + Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    H: Handler<O, T>,
+{
+    async fn authenticated(&self, auth: &O, peer_addr: SocketAddr) -> bool {
+        self.inner.authenticated(auth, peer_addr).await
+    }
+
+    async fn connect(&self, connect: Connect<connect::NeedReply, O, T>, addr: Address) -> crate::Result<()> {
+        if self
+            .client(connect.auth().user_name(), connect.peer_addr())
+            .allows(Command::Connect, &addr)
+        {
+            return self.inner.connect(connect, addr).await;
+        }
+        let mut conn = connect.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+        conn.shutdown().await?;
+        Ok(())
+    }
+
+    async fn bind(&self, bind: Bind<bind::NeedFirstReply, O, T>, addr: Address) -> crate::Result<()> {
+        if self.client(bind.auth().user_name(), bind.peer_addr()).allows(Command::Bind, &addr) {
+            return self.inner.bind(bind, addr).await;
+        }
+        let mut conn = bind.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+        conn.shutdown().await?;
+        Ok(())
+    }
+
+    async fn udp_associate(&self, associate: UdpAssociate<associate::NeedReply, O, T>, addr: Address) -> crate::Result<()> {
+        let client = self.client(associate.auth().user_name(), associate.peer_addr());
+        if client.allows(Command::UdpAssociate, &addr) {
+            let associate = associate.with_destination_filter(move |dst| client.allows(Command::UdpAssociate, dst));
+            return self.inner.udp_associate(associate, addr).await;
+        }
+        let mut conn = associate.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+        conn.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acl, AclHandler, AclRequest, Action, Cidr, DomainPattern, PortRange, Rule};
+    use crate::{
+        client,
+        protocol::{Address, Command, Reply},
+        server::{auth, DefaultHandler, Server},
+    };
+    use std::{net::IpAddr, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, BufStream},
+        net::{TcpListener, TcpStream, UdpSocket},
+    };
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr() {
+        let net: Cidr = "192.168.1.77/16".parse().unwrap();
+        assert_eq!(net.to_string(), "192.168.0.0/16");
+        assert!(net.contains(ip("192.168.255.1")));
+        assert!(net.contains(ip("::ffff:192.168.0.1")));
+        assert!(!net.contains(ip("192.169.0.1")));
+        assert!(!net.contains(ip("fe80::1")));
+
+        let net: Cidr = "fc00::/7".parse().unwrap();
+        assert!(net.contains(ip("fd12::1")));
+        assert!(!net.contains(ip("fe80::1")));
+        assert!(!net.contains(ip("10.0.0.1")));
+
+        assert_eq!("10.0.0.1".parse::<Cidr>().unwrap().to_string(), "10.0.0.1/32");
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn domain_pattern() {
+        let suffix: DomainPattern = ".Example.com".parse().unwrap();
+        assert!(suffix.matches("example.com"));
+        assert!(suffix.matches("www.EXAMPLE.com."));
+        assert!(!suffix.matches("badexample.com"));
+
+        let glob: DomainPattern = "cdn-*.example.com".parse().unwrap();
+        assert!(glob.matches("cdn-1.example.com"));
+        assert!(glob.matches("cdn-eu.west.example.com"));
+        assert!(!glob.matches("cdn.example.com"));
+
+        let glob: DomainPattern = "host?.lan".parse().unwrap();
+        assert!(glob.matches("host1.lan"));
+        assert!(!glob.matches("host12.lan"));
+
+        let exact: DomainPattern = "example.com".parse().unwrap();
+        assert!(exact.matches("example.com."));
+        assert!(!exact.matches("www.example.com"));
+        assert!("".parse::<DomainPattern>().is_err());
+    }
+
+    #[test]
+    fn port_range() {
+        let range: PortRange = "8000-8999".parse().unwrap();
+        assert!(range.contains(8000) && range.contains(8999));
+        assert!(!range.contains(9000));
+        assert_eq!("443".parse::<PortRange>().unwrap(), PortRange::from(443));
+        assert!("9000-8000".parse::<PortRange>().is_err());
+        assert!("70000".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let acl = Acl::new(Action::Deny)
+            .with_rule(Rule::deny().with_destination("10.0.0.0/8".parse().unwrap()))
+            .with_rule(Rule::deny().with_command(Command::Bind))
+            .with_rule(
+                Rule::allow()
+                    .with_client("192.168.0.0/16".parse().unwrap())
+                    .with_domain(".example.com".parse().unwrap())
+                    .with_destination("0.0.0.0/0".parse().unwrap())
+                    .with_ports(PortRange::new(80, 443).unwrap()),
+            )
+            .with_rule(Rule::allow().with_user("admin"));
+
+        let web = Address::from(("www.example.com", 443));
+        let ip_web = Address::from((ip("93.184.216.34"), 80));
+        let private = Address::from((ip("10.1.2.3"), 80));
+        let ssh = Address::from(("www.example.com", 22));
+        let internal = Address::from(("internal", 22));
+        let lan = ip("192.168.1.10");
+        let check = |command, addr, user: Option<&str>, client: Option<IpAddr>| {
+            let mut request = AclRequest::new(command, addr);
+            if let Some(user) = user {
+                request = request.with_user(user);
+            }
+            if let Some(client) = client {
+                request = request.with_client(client);
+            }
+            acl.check(&request)
+        };
+
+        assert_eq!(check(Command::Connect, &web, None, Some(lan)), Action::Allow);
+        assert_eq!(check(Command::Connect, &ip_web, None, Some(lan)), Action::Allow);
+        assert_eq!(check(Command::UdpAssociate, &web, None, Some(lan)), Action::Allow);
+        // Port out of range, client elsewhere or without an address.
+        assert_eq!(check(Command::Connect, &ssh, None, Some(lan)), Action::Deny);
+        assert_eq!(check(Command::Connect, &web, None, Some(ip("172.16.0.1"))), Action::Deny);
+        assert_eq!(check(Command::Connect, &web, None, None), Action::Deny);
+        // Earlier deny rules win over the admin rule.
+        assert_eq!(check(Command::Connect, &private, Some("admin"), Some(lan)), Action::Deny);
+        assert_eq!(check(Command::Bind, &web, Some("admin"), None), Action::Deny);
+        assert_eq!(check(Command::Connect, &internal, Some("admin"), None), Action::Allow);
+        assert_eq!(check(Command::Connect, &internal, Some("guest"), None), Action::Deny);
+
+        let rule = acl.matching_rule(&AclRequest::new(Command::Bind, &web)).unwrap();
+        assert_eq!(rule, &acl.rules()[1]);
+        assert_eq!(Acl::new(Action::Allow).check(&AclRequest::new(Command::Bind, &web)), Action::Allow);
+    }
+
+    #[test]
+    fn ip_literal_domains_match_destinations() {
+        let rule = Rule::deny()
+            .with_destination("127.0.0.0/8".parse().unwrap())
+            .with_destination("::1".parse().unwrap());
+        for domain in ["127.0.0.1", "::1", "[::1]"] {
+            let addr = Address::DomainAddress(domain.into(), 80);
+            assert!(rule.matches(&AclRequest::new(Command::Connect, &addr)), "{domain}");
+        }
+        let addr = Address::DomainAddress("10.0.0.1".into(), 80);
+        assert!(!rule.matches(&AclRequest::new(Command::Connect, &addr)));
+        let rule = Rule::allow().with_domain("*".parse().unwrap());
+        assert!(!rule.matches(&AclRequest::new(Command::Connect, &addr)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn acl_serde() {
+        let acl: Acl = serde_json::from_str(
+            r#"{
+                "rules": [
+                    { "action": "allow", "clients": ["127.0.0.1"], "commands": ["Connect", "UdpAssociate"], "ports": ["1-1023"] },
+                    { "action": "deny", "users": ["mallory"], "domains": ["*.onion"] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(acl.default_action(), Action::Deny);
+        let expected = Acl::new(Action::Deny)
+            .with_rule(
+                Rule::allow()
+                    .with_client("127.0.0.1/32".parse().unwrap())
+                    .with_command(Command::Connect)
+                    .with_command(Command::UdpAssociate)
+                    .with_ports(PortRange::new(1, 1023).unwrap()),
+            )
+            .with_rule(Rule::deny().with_user("mallory").with_domain("*.onion".parse().unwrap()));
+        assert_eq!(acl, expected);
+
+        let json = serde_json::to_string(&acl).unwrap();
+        assert_eq!(serde_json::from_str::<Acl>(&json).unwrap(), acl);
+        assert!(json.contains(r#""clients":["127.0.0.1/32"]"#));
+        assert!(serde_json::from_str::<Acl>(r#"{ "rules": [{ "action": "allow", "ports": ["80-"] }] }"#).is_err());
+        assert!(serde_json::from_str::<Acl>(r#"{ "rules": [{ "action": "maybe" }] }"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn acl_handler_replies_connection_not_allowed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    tokio::io::copy(&mut r, &mut w).await
+                });
+            }
+        });
+
+        let acl = Acl::new(Action::Deny)
+            .with_rule(Rule::deny().with_command(Command::Bind))
+            .with_rule(Rule::deny().with_destination("127.0.0.2".parse().unwrap()))
+            .with_rule(
+                Rule::allow()
+                    .with_client("127.0.0.0/8".parse().unwrap())
+                    .with_ports(echo.port().into()),
+            );
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth)).await.unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.serve(AclHandler::new(Arc::new(acl), DefaultHandler)));
+
+        let mut stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        client::connect(&mut stream, echo, None).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        let err = client::connect(&mut stream, (echo.ip(), echo.port() + 1), None).await.unwrap_err();
+        assert_eq!(err.to_string(), Reply::ConnectionNotAllowed.to_string());
+
+        // The same address sent as a domain, with ATYP 0x03.
+        let mut stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        let err = client::connect(&mut stream, ("127.0.0.2", echo.port()), None).await.unwrap_err();
+        assert_eq!(err.to_string(), Reply::ConnectionNotAllowed.to_string());
+
+        let stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        let err = client::SocksListener::bind(stream, echo, None).await.unwrap_err();
+        assert_eq!(err.to_string(), Reply::ConnectionNotAllowed.to_string());
+    }
+
+    #[tokio::test]
+    async fn acl_handler_filters_udp_datagrams() {
+        let mut echoes = Vec::new();
+        for _ in 0..2 {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            echoes.push(socket.local_addr().unwrap());
+            tokio::spawn(async move {
+                let mut buf = [0; 1500];
+                while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                    let _ = socket.send_to(&buf[..len], from).await;
+                }
+            });
+        }
+        let (allowed, denied) = (echoes[0], echoes[1]);
+
+        let acl = Acl::new(Action::Allow).with_rule(Rule::deny().with_ports(denied.port().into()));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth)).await.unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.serve(AclHandler::new(Arc::new(acl), DefaultHandler)));
+
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        udp.send_to(b"denied", denied).await.unwrap();
+        udp.send_to(b"denied", ("127.0.0.1", denied.port())).await.unwrap();
+        udp.send_to(b"allowed", allowed).await.unwrap();
+        let mut buf = Vec::new();
+        let (_, from) = udp.recv_from(Duration::from_secs(5), &mut buf).await.unwrap();
+        assert_eq!(buf, b"allowed");
+        assert_eq!(from, Address::from(allowed));
+        assert!(udp.recv_from(Duration::from_millis(200), &mut buf).await.is_err());
+    }
+}
@@ -1,8 +1,58 @@
-use crate::protocol::{handshake::password_method, AsyncStreamOperation, AuthMethod, UserKey};
-use as_any::AsAny;
+use crate::{
+    protocol::{handshake::password_method, AsyncStreamOperation, AuthMethod, UserKey},
+    server::credentials::{Credential, CredentialStore},
+};
 use async_trait::async_trait;
-use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::{
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Semaphore,
+};
+
+/// The stream an [`AuthExecutor`] runs its sub-negotiation on.
+///
+/// It is implemented for every `AsyncRead + AsyncWrite` stream, so the same executor serves a server over TCP, TLS,
+/// Unix sockets or anything else the connection is wrapped around.
+pub trait AuthStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + ?Sized> AuthStream for T {}
+
+/// An authentication output naming the user the client authenticated as.
+///
+/// Access control lists and bandwidth limits match users by this name. Anonymous clients have none.
+pub trait AuthUser {
+    /// Returns the name of the authenticated user, if any.
+    fn user_name(&self) -> Option<&str>;
+}
+
+impl AuthUser for () {
+    #[inline]
+    fn user_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl AuthUser for String {
+    #[inline]
+    fn user_name(&self) -> Option<&str> {
+        Some(self)
+    }
+}
+
+impl<U: AuthUser> AuthUser for Option<U> {
+    #[inline]
+    fn user_name(&self) -> Option<&str> {
+        self.as_ref().and_then(AuthUser::user_name)
+    }
+}
 
 /// This trait is for defining the socks5 authentication method.
 ///
@@ -11,24 +61,29 @@ use tokio::net::TcpStream;
 /// You can create your own authentication method by implementing this trait. Since GAT is not stabled yet,
 /// [async_trait](https://docs.rs/async-trait/latest/async_trait/index.html) needs to be used.
 ///
+/// The output is what the client authenticated as, such as a user name or the claims of a token. It is kept on the
+/// [`Authenticated`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Authenticated.html) connection
+/// and the request that follows, typed. An error from [`execute`](#tymethod.execute) rejects the client.
+///
+/// The sub-negotiation runs on an [`AuthStream`], whatever the transport of the connection is.
+///
 /// # Example
 /// ```rust
 /// use async_trait::async_trait;
 /// use socks5_impl::protocol::AuthMethod;
-/// use socks5_impl::server::AuthExecutor;
-/// use tokio::net::TcpStream;
+/// use socks5_impl::server::{AuthExecutor, AuthStream};
 ///
 /// pub struct MyAuth;
 ///
 /// #[async_trait]
 /// impl AuthExecutor for MyAuth {
-///     type Output = std::io::Result<usize>;
+///     type Output = usize;
 ///
 ///     fn auth_method(&self) -> AuthMethod {
 ///         AuthMethod::from(0x80)
 ///     }
 ///
-///     async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+///     async fn execute(&self, stream: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
 ///         // do something
 ///         Ok(1145141919810)
 ///     }
@@ -36,9 +91,33 @@ use tokio::net::TcpStream;
 /// ```
 #[async_trait]
 pub trait AuthExecutor {
-    type Output: AsAny;
+    type Output: Send;
     fn auth_method(&self) -> AuthMethod;
-    async fn execute(&self, stream: &mut TcpStream) -> Self::Output;
+    async fn execute(&self, stream: &mut dyn AuthStream) -> std::io::Result<Self::Output>;
+
+    /// Identifies a SOCKS4 or SOCKS4a client by the `USERID` field of its request.
+    ///
+    /// SOCKS4 has no authentication sub-negotiation, so this hook plays the role of
+    /// [`execute`](#tymethod.execute) for those clients. Returning `None` rejects the client,
+    /// which is the default, since a `USERID` alone cannot satisfy most authentication methods.
+    async fn identify_socks4(&self, _user_id: &str) -> Option<Self::Output> {
+        None
+    }
+
+    /// Picks the method to use with a client at `peer_addr` offering `methods`, or `None` to refuse it.
+    ///
+    /// The default picks [`auth_method`](#tymethod.auth_method) if the client offers it.
+    fn select_method(&self, methods: &[AuthMethod], _peer_addr: SocketAddr) -> Option<AuthMethod> {
+        let method = self.auth_method();
+        methods.contains(&method).then_some(method)
+    }
+
+    /// Runs the sub-negotiation of `method`, as picked by [`select_method`](#method.select_method).
+    ///
+    /// The default calls [`execute`](#tymethod.execute), which suits executors of a single method.
+    async fn execute_method(&self, _method: AuthMethod, stream: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
+        self.execute(stream).await
+    }
 }
 
 pub type AuthAdaptor<O> = Arc<dyn AuthExecutor<Output = O> + Send + Sync>;
@@ -54,10 +133,18 @@ impl AuthExecutor for NoAuth {
         AuthMethod::NoAuth
     }
 
-    async fn execute(&self, _: &mut TcpStream) -> Self::Output {}
+    async fn execute(&self, _: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
+        Ok(())
+    }
+
+    async fn identify_socks4(&self, _user_id: &str) -> Option<Self::Output> {
+        Some(())
+    }
 }
 
-/// Username and password as the socks5 handshake method.
+/// Username and password as the socks5 handshake method, for a single user. The output is the user name.
+///
+/// See [`CredentialAuth`] for more users.
 pub struct UserKeyAuth {
     user_key: UserKey,
 }
@@ -71,23 +158,478 @@ impl UserKeyAuth {
 
 #[async_trait]
 impl AuthExecutor for UserKeyAuth {
-    type Output = std::io::Result<bool>;
+    type Output = String;
 
     fn auth_method(&self) -> AuthMethod {
         AuthMethod::UserPass
     }
 
-    async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+    async fn execute(&self, mut stream: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
         use password_method::{Request, Response, Status::*};
-        let req = Request::retrieve_from_async_stream(stream).await?;
+        let req = Request::retrieve_from_async_stream(&mut stream).await?;
 
-        let is_equal = req.user_key == self.user_key;
+        let username = req.user_key.username.as_bytes().ct_eq(self.user_key.username.as_bytes());
+        let password = req.user_key.password.as_bytes().ct_eq(self.user_key.password.as_bytes());
+        let is_equal = bool::from(username & password);
         let resp = Response::new(if is_equal { Succeeded } else { Failed });
-        resp.write_to_async_stream(stream).await?;
+        resp.write_to_async_stream(&mut stream).await?;
         if is_equal {
-            Ok(true)
+            Ok(req.user_key.username)
+        } else {
+            let err = "username or password is incorrect";
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, err))
+        }
+    }
+}
+
+/// Username and password as the socks5 handshake method, checked against a
+/// [`CredentialStore`](crate::server::credentials::CredentialStore). The output is the user name.
+///
+/// Password hashes are checked on the blocking thread pool of tokio, so slow hashes do not hold up other connections, and
+/// at most [`with_max_concurrent_hashes()`](Self::with_max_concurrent_hashes) at once, so a flood of logins cannot take
+/// up the whole pool. Unknown users are checked against a dummy credential of the same kind as the users found last,
+/// so they are not told apart by how long the answer takes.
+///
+/// # Example
+/// ```rust
+/// use socks5_impl::server::{auth::CredentialAuth, credentials::MemoryCredentials, AuthAdaptor};
+/// use std::sync::Arc;
+///
+/// let users = Arc::new(MemoryCredentials::new().with_user("hyper", "proxy").with_user("other", "secret"));
+/// let auth: AuthAdaptor<String> = Arc::new(CredentialAuth::new(users.clone()));
+/// // Users can still be changed while the server runs.
+/// users.remove("other");
+/// ```
+pub struct CredentialAuth {
+    store: Arc<dyn CredentialStore>,
+    hashing: Semaphore,
+    hashed_store: AtomicBool,
+}
+
+impl CredentialAuth {
+    /// Checks the users of `store`, hashing at most as many passwords at once as there are CPUs.
+    pub fn new(store: Arc<dyn CredentialStore>) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        Self {
+            store,
+            hashing: Semaphore::new(cpus),
+            hashed_store: AtomicBool::new(cfg!(feature = "password-hash")),
+        }
+    }
+
+    /// Hashes at most `max` passwords at once, the other clients waiting for their turn. A `max` of 0 is raised to 1,
+    /// so that logins never wait forever.
+    pub fn with_max_concurrent_hashes(mut self, max: usize) -> Self {
+        self.hashing = Semaphore::new(max.max(1));
+        self
+    }
+
+    /// Returns whether `user_key` matches a user of the store.
+    pub async fn verify(&self, user_key: &UserKey) -> bool {
+        let credential = self.store.lookup(&user_key.username).await;
+        let hashed = match &credential {
+            Some(credential) => {
+                self.hashed_store.store(credential.is_hashed(), Ordering::Relaxed);
+                credential.is_hashed()
+            }
+            None => self.hashed_store.load(Ordering::Relaxed),
+        };
+        if hashed {
+            let Ok(_permit) = self.hashing.acquire().await else {
+                return false;
+            };
+            let password = user_key.password.clone();
+            tokio::task::spawn_blocking(move || verify_or_dummy(credential, hashed, &password))
+                .await
+                .unwrap_or(false)
         } else {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "username or password is incorrect"))
+            verify_or_dummy(credential, hashed, &user_key.password)
+        }
+    }
+}
+
+/// Checks `password` against `credential`, or against a dummy credential taking as long if there is none, failing then.
+fn verify_or_dummy(credential: Option<Credential>, hashed: bool, password: &str) -> bool {
+    match credential {
+        Some(credential) => credential.verify(password),
+        None => {
+            #[cfg(feature = "password-hash")]
+            if hashed {
+                DUMMY_HASH.verify(password);
+                return false;
+            }
+            let _ = hashed;
+            Credential::Plain(String::new()).verify(password);
+            false
+        }
+    }
+}
+
+/// The dummy credential unknown users are checked against when the store holds password hashes.
+#[cfg(feature = "password-hash")]
+static DUMMY_HASH: std::sync::LazyLock<Credential> = std::sync::LazyLock::new(|| {
+    use argon2::{password_hash::SaltString, PasswordHasher};
+    let salt = SaltString::encode_b64(b"socks5-impl-dummy").unwrap();
+    let hash = argon2::Argon2::default().hash_password(b"", &salt).unwrap();
+    Credential::Hashed(hash.to_string())
+});
+
+impl std::fmt::Debug for CredentialAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CredentialAuth").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl AuthExecutor for CredentialAuth {
+    type Output = String;
+
+    fn auth_method(&self) -> AuthMethod {
+        AuthMethod::UserPass
+    }
+
+    async fn execute(&self, mut stream: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
+        use password_method::{Request, Response, Status::*};
+        let req = Request::retrieve_from_async_stream(&mut stream).await?;
+
+        let is_valid = self.verify(&req.user_key).await;
+        let resp = Response::new(if is_valid { Succeeded } else { Failed });
+        resp.write_to_async_stream(&mut stream).await?;
+        if is_valid {
+            Ok(req.user_key.username)
+        } else {
+            let err = "username or password is incorrect";
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, err))
+        }
+    }
+}
+
+/// How [`MultiAuth`] picks one of its methods for a client.
+#[derive(Clone)]
+pub enum AuthSelection {
+    /// The method of the first executor, in the order they were added, that accepts the methods of the client.
+    ServerPreference,
+    /// The first method of the client that an executor accepts.
+    ClientPreference,
+    /// A callback seeing the methods offered by the client and its address. A method without an executor is a refusal.
+    Custom(MethodSelector),
+}
+
+/// The callback of [`AuthSelection::Custom`].
+pub type MethodSelector = Arc<dyn Fn(&[AuthMethod], SocketAddr) -> Option<AuthMethod> + Send + Sync>;
+
+impl std::fmt::Debug for AuthSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ServerPreference => f.write_str("ServerPreference"),
+            Self::ClientPreference => f.write_str("ClientPreference"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Several authentication methods offered on the same server, picked per client with an [`AuthSelection`].
+///
+/// Executors are matched to the picked method by their [`auth_method`](AuthExecutor::auth_method), and must all
+/// have the same output. Those of other outputs can be converted with [`with_mapped`](Self::with_mapped).
+///
+/// # Example
+/// ```rust
+/// use socks5_impl::{
+///     protocol::AuthMethod,
+///     server::auth::{AuthSelection, MultiAuth, NoAuth, UserKeyAuth},
+/// };
+/// use std::sync::Arc;
+///
+/// // Password-less access from loopback only, passwords from anywhere. The output is the user name, if any.
+/// let selection = AuthSelection::Custom(Arc::new(|methods, peer_addr| {
+///     if peer_addr.ip().is_loopback() && methods.contains(&AuthMethod::NoAuth) {
+///         Some(AuthMethod::NoAuth)
+///     } else {
+///         methods.contains(&AuthMethod::UserPass).then_some(AuthMethod::UserPass)
+///     }
+/// }));
+/// let auth = MultiAuth::new(selection)
+///     .with_mapped(UserKeyAuth::new("hyper", "proxy"), Some)
+///     .with_mapped(NoAuth, |()| None);
+/// ```
+pub struct MultiAuth<O> {
+    executors: Vec<AuthAdaptor<O>>,
+    selection: AuthSelection,
+}
+
+impl<O: Send + 'static> MultiAuth<O> {
+    /// Creates a set of executors, empty at first, picking methods with `selection`.
+    pub fn new(selection: AuthSelection) -> Self {
+        Self {
+            executors: Vec::new(),
+            selection,
+        }
+    }
+
+    /// Adds `executor`, after those added already.
+    pub fn with<E>(mut self, executor: E) -> Self
+    where
+        E: AuthExecutor<Output = O> + Send + Sync + 'static,
+    {
+        self.executors.push(Arc::new(executor));
+        self
+    }
+
+    /// Adds `executor`, whose output is converted with `f`.
+    pub fn with_mapped<E, F>(self, executor: E, f: F) -> Self
+    where
+        E: AuthExecutor + Send + Sync + 'static,
+        F: Fn(E::Output) -> O + Send + Sync + 'static,
+    {
+        self.with(Mapped { executor, f })
+    }
+
+    /// Returns the methods of the executors, in the order they were added.
+    pub fn methods(&self) -> impl Iterator<Item = AuthMethod> + '_ {
+        self.executors.iter().map(|executor| executor.auth_method())
+    }
+
+    fn executor(&self, method: AuthMethod) -> Option<&AuthAdaptor<O>> {
+        self.executors.iter().find(|executor| executor.auth_method() == method)
+    }
+}
+
+impl<O: Send + 'static> std::fmt::Debug for MultiAuth<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let methods = self.methods().collect::<Vec<_>>();
+        f.debug_struct("MultiAuth")
+            .field("methods", &methods)
+            .field("selection", &self.selection)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<O: Send + 'static> AuthExecutor for MultiAuth<O> {
+    type Output = O;
+
+    /// Returns the method of the first executor, or [`AuthMethod::NoAcceptableMethods`] if there is none.
+    fn auth_method(&self) -> AuthMethod {
+        self.methods().next().unwrap_or(AuthMethod::NoAcceptableMethods)
+    }
+
+    /// Runs the first executor. Use [`execute_method`](AuthExecutor::execute_method) to run the one of a given method.
+    async fn execute(&self, stream: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
+        self.execute_method(self.auth_method(), stream).await
+    }
+
+    /// Tries the executors in the order they were added, and returns the first output.
+    async fn identify_socks4(&self, user_id: &str) -> Option<Self::Output> {
+        for executor in &self.executors {
+            if let Some(output) = executor.identify_socks4(user_id).await {
+                return Some(output);
+            }
+        }
+        None
+    }
+
+    fn select_method(&self, methods: &[AuthMethod], peer_addr: SocketAddr) -> Option<AuthMethod> {
+        match &self.selection {
+            AuthSelection::ServerPreference => self
+                .executors
+                .iter()
+                .find_map(|executor| executor.select_method(methods, peer_addr)),
+            AuthSelection::ClientPreference => methods.iter().copied().find(|&method| {
+                self.executor(method)
+                    .is_some_and(|executor| executor.select_method(&[method], peer_addr).is_some())
+            }),
+            AuthSelection::Custom(select) => select(methods, peer_addr).filter(|&method| self.executor(method).is_some()),
+        }
+    }
+
+    /// Runs the executor of `method`, or fails with [`Unsupported`](std::io::ErrorKind::Unsupported) if no executor
+    /// has this method, e.g. when [`execute`](AuthExecutor::execute) is called on a `MultiAuth` without executors.
+    async fn execute_method(&self, method: AuthMethod, stream: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
+        let Some(executor) = self.executor(method) else {
+            let err = format!("no executor for the {method:?} method");
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, err));
+        };
+        executor.execute_method(method, stream).await
+    }
+}
+
+/// An executor whose output is converted, see [`MultiAuth::with_mapped`].
+struct Mapped<E, F> {
+    executor: E,
+    f: F,
+}
+
+#[async_trait]
+impl<E, F, O> AuthExecutor for Mapped<E, F>
+where
+    E: AuthExecutor + Send + Sync,
+    F: Fn(E::Output) -> O + Send + Sync,
+    O: Send,
+{
+    type Output = O;
+
+    fn auth_method(&self) -> AuthMethod {
+        self.executor.auth_method()
+    }
+
+    async fn execute(&self, stream: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
+        self.executor.execute(stream).await.map(&self.f)
+    }
+
+    async fn identify_socks4(&self, user_id: &str) -> Option<Self::Output> {
+        self.executor.identify_socks4(user_id).await.map(&self.f)
+    }
+
+    fn select_method(&self, methods: &[AuthMethod], peer_addr: SocketAddr) -> Option<AuthMethod> {
+        self.executor.select_method(methods, peer_addr)
+    }
+
+    async fn execute_method(&self, method: AuthMethod, stream: &mut dyn AuthStream) -> std::io::Result<Self::Output> {
+        self.executor.execute_method(method, stream).await.map(&self.f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthExecutor, AuthSelection, CredentialAuth, MultiAuth, NoAuth, UserKeyAuth};
+    use crate::{
+        client,
+        protocol::{AuthMethod, UserKey},
+        server::{credentials::MemoryCredentials, DefaultHandler, Server},
+    };
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::{io::BufStream, net::TcpStream};
+
+    const LOOPBACK: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 1080);
+    const REMOTE: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1)), 1080);
+
+    fn multi_auth(selection: AuthSelection) -> MultiAuth<Option<String>> {
+        MultiAuth::new(selection)
+            .with_mapped(UserKeyAuth::new("hyper", "proxy"), Some)
+            .with_mapped(NoAuth, |()| None)
+    }
+
+    #[test]
+    fn selection() {
+        use AuthMethod::*;
+
+        let auth = multi_auth(AuthSelection::ServerPreference);
+        assert_eq!(auth.methods().collect::<Vec<_>>(), [UserPass, NoAuth]);
+        assert_eq!(auth.select_method(&[NoAuth, UserPass], REMOTE), Some(UserPass));
+        assert_eq!(auth.select_method(&[NoAuth], REMOTE), Some(NoAuth));
+        assert_eq!(auth.select_method(&[GssApi], REMOTE), None);
+
+        let auth = multi_auth(AuthSelection::ClientPreference);
+        assert_eq!(auth.select_method(&[GssApi, NoAuth, UserPass], REMOTE), Some(NoAuth));
+        assert_eq!(auth.select_method(&[UserPass, NoAuth], REMOTE), Some(UserPass));
+
+        let auth = multi_auth(AuthSelection::Custom(Arc::new(|methods, peer_addr| {
+            if peer_addr.ip().is_loopback() && methods.contains(&NoAuth) {
+                Some(NoAuth)
+            } else {
+                Some(GssApi)
+            }
+        })));
+        assert_eq!(auth.select_method(&[NoAuth, UserPass], LOOPBACK), Some(NoAuth));
+        // GSSAPI has no executor here.
+        assert_eq!(auth.select_method(&[NoAuth, GssApi], REMOTE), None);
+    }
+
+    #[tokio::test]
+    async fn multi_auth_without_executor() {
+        let (mut stream, _peer) = tokio::io::duplex(64);
+        let err = MultiAuth::<()>::new(AuthSelection::ServerPreference)
+            .execute(&mut stream)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        let err = multi_auth(AuthSelection::ServerPreference)
+            .execute_method(AuthMethod::GssApi, &mut stream)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn multi_auth_server() {
+        let auth = Arc::new(multi_auth(AuthSelection::ServerPreference));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), auth).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(DefaultHandler));
+
+        // Nothing is sent to the target, it only needs to accept connections.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        for auth in [None, Some(UserKey::new("hyper", "proxy"))] {
+            let mut stream = BufStream::new(TcpStream::connect(addr).await.unwrap());
+            client::connect(&mut stream, target, auth).await.unwrap();
         }
+        let mut stream = BufStream::new(TcpStream::connect(addr).await.unwrap());
+        assert!(client::connect(&mut stream, target, Some(UserKey::new("hyper", "wrong")))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn credential_auth_server() {
+        let users = Arc::new(MemoryCredentials::new().with_user("hyper", "proxy").with_user("other", "secret"));
+        let auth = Arc::new(CredentialAuth::new(users.clone()));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), auth).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(DefaultHandler));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let connect = |user_key| async move {
+            let mut stream = BufStream::new(TcpStream::connect(addr).await.unwrap());
+            client::connect(&mut stream, target, Some(user_key)).await
+        };
+        assert!(connect(UserKey::new("hyper", "proxy")).await.is_ok());
+        assert!(connect(UserKey::new("other", "secret")).await.is_ok());
+        assert!(connect(UserKey::new("other", "proxy")).await.is_err());
+        assert!(connect(UserKey::new("nobody", "proxy")).await.is_err());
+
+        users.remove("other");
+        assert!(connect(UserKey::new("other", "secret")).await.is_err());
+    }
+
+    #[cfg(feature = "password-hash")]
+    #[tokio::test]
+    async fn credential_auth_hashes() {
+        use crate::server::credentials::Credential;
+
+        let hash = Credential::hashed(&bcrypt::hash("proxy", 4).unwrap()).unwrap();
+        let users = Arc::new(MemoryCredentials::new().with_credential("hyper", hash));
+        let auth = Arc::new(CredentialAuth::new(users).with_max_concurrent_hashes(1));
+        let checks = [
+            ("hyper", "proxy", true),
+            ("hyper", "proxi", false),
+            ("nobody", "proxy", false),
+            ("", "", false),
+        ];
+        let tasks = checks.map(|(username, password, valid)| {
+            let auth = auth.clone();
+            tokio::spawn(async move { auth.verify(&UserKey::new(username, password)).await == valid })
+        });
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+        assert_eq!(auth.hashing.available_permits(), 1);
+    }
+
+    #[cfg(feature = "password-hash")]
+    #[tokio::test]
+    async fn credential_auth_hashes_with_zero_max() {
+        use crate::server::credentials::Credential;
+        use std::time::Duration;
+
+        let hash = Credential::hashed(&bcrypt::hash("proxy", 4).unwrap()).unwrap();
+        let users = Arc::new(MemoryCredentials::new().with_credential("hyper", hash));
+        let auth = CredentialAuth::new(users).with_max_concurrent_hashes(0);
+        assert_eq!(auth.hashing.available_permits(), 1);
+        let user_key = UserKey::new("hyper", "proxy");
+        let verified = tokio::time::timeout(Duration::from_secs(5), auth.verify(&user_key)).await;
+        assert!(verified.unwrap());
     }
 }
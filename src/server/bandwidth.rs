@@ -0,0 +1,578 @@
+//! Bandwidth limits on relayed traffic, with token buckets.
+//!
+//! A [`BandwidthLimiter`] holds a global limit, a default limit per user and per client IP, and overrides for single users
+//! and IPs, each with separate upload and download rates. All of them can be changed while the server runs, through the
+//! `Arc` the limiter is shared with. Users are named by the [`AuthUser`](crate::server::AuthUser) output of the
+//! authentication.
+//!
+//! The [`Throttle`] of a connection, from [`BandwidthLimiter::throttle()`], draws from the buckets of the limits that apply
+//! to it. It slows down TCP streams wrapped in a [`Throttled`], and makes an
+//! [`AssociatedUdpSocket`](crate::server::AssociatedUdpSocket) drop datagrams above the rate, see
+//! [`AssociatedUdpSocket::set_throttle()`](crate::server::AssociatedUdpSocket::set_throttle).
+//!
+//! A limiter set with [`Server::with_bandwidth_limiter()`](crate::server::Server::with_bandwidth_limiter) is applied by the
+//! built-in CONNECT, BIND and UDP ASSOCIATE relays.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// Upload and download rates in bytes per second, `None` being unlimited.
+///
+/// Upload is the traffic from the client to its destinations, download the traffic back to the client.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize), serde(default))]
+pub struct Bandwidth {
+    upload: Option<u64>,
+    download: Option<u64>,
+}
+
+impl Bandwidth {
+    /// No limit in either direction.
+    pub const UNLIMITED: Self = Self {
+        upload: None,
+        download: None,
+    };
+
+    /// Creates a limit of `upload` and `download` bytes per second.
+    #[inline]
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        Self { upload, download }
+    }
+
+    /// Sets the upload rate in bytes per second.
+    #[inline]
+    pub fn with_upload(mut self, rate: Option<u64>) -> Self {
+        self.upload = rate;
+        self
+    }
+
+    /// Sets the download rate in bytes per second.
+    #[inline]
+    pub fn with_download(mut self, rate: Option<u64>) -> Self {
+        self.download = rate;
+        self
+    }
+
+    /// Returns the upload rate in bytes per second.
+    #[inline]
+    pub fn upload(&self) -> Option<u64> {
+        self.upload
+    }
+
+    /// Returns the download rate in bytes per second.
+    #[inline]
+    pub fn download(&self) -> Option<u64> {
+        self.download
+    }
+}
+
+/// A token bucket refilled at `rate` bytes per second and holding up to one second of traffic.
+///
+/// Sends are never split: the bucket goes into debt for them, and the next one waits until the debt is paid back.
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>) -> Self {
+        let tokens = rate.unwrap_or(0) as f64;
+        Self {
+            rate,
+            tokens,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last = now;
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>) {
+        self.refill(Instant::now());
+        if let Some(rate) = rate {
+            let full = if self.rate.is_none() { rate as f64 } else { self.tokens };
+            self.tokens = full.min(rate as f64);
+        }
+        self.rate = rate;
+    }
+
+    /// How long until the bucket is out of debt.
+    fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        match self.rate {
+            Some(rate) if self.tokens < 0.0 => Duration::from_secs_f64(-self.tokens / rate.max(1) as f64),
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn take(&mut self, len: usize) {
+        if self.rate.is_some() {
+            self.tokens -= len as f64;
+        }
+    }
+}
+
+/// The upload and download buckets of one limit.
+#[derive(Debug)]
+struct Buckets {
+    upload: Mutex<Bucket>,
+    download: Mutex<Bucket>,
+}
+
+impl Buckets {
+    fn new(bandwidth: Bandwidth) -> Self {
+        Self {
+            upload: Mutex::new(Bucket::new(bandwidth.upload)),
+            download: Mutex::new(Bucket::new(bandwidth.download)),
+        }
+    }
+
+    fn set(&self, bandwidth: Bandwidth) {
+        self.upload.lock().unwrap().set_rate(bandwidth.upload);
+        self.download.lock().unwrap().set_rate(bandwidth.download);
+    }
+
+    fn bucket(&self, direction: Direction) -> &Mutex<Bucket> {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+/// The limits of one kind of key, users or IPs: the default, the overrides, and the buckets in use.
+#[derive(Debug)]
+struct Table<K> {
+    default: Bandwidth,
+    overrides: HashMap<K, Bandwidth>,
+    buckets: HashMap<K, Weak<Buckets>>,
+}
+
+impl<K: Hash + Eq + Clone> Table<K> {
+    fn new() -> Self {
+        Self {
+            default: Bandwidth::UNLIMITED,
+            overrides: HashMap::new(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn limit(&self, key: &K) -> Bandwidth {
+        self.overrides.get(key).copied().unwrap_or(self.default)
+    }
+
+    /// The buckets of `key`, shared by all its connections and dropped with the last of them.
+    fn buckets(&mut self, key: &K) -> Arc<Buckets> {
+        if let Some(buckets) = self.buckets.get(key).and_then(Weak::upgrade) {
+            return buckets;
+        }
+        self.buckets.retain(|_, buckets| buckets.strong_count() > 0);
+        let buckets = Arc::new(Buckets::new(self.limit(key)));
+        self.buckets.insert(key.clone(), Arc::downgrade(&buckets));
+        buckets
+    }
+
+    fn set_default(&mut self, bandwidth: Bandwidth) {
+        self.default = bandwidth;
+        for (key, buckets) in &self.buckets {
+            if let (Some(buckets), false) = (buckets.upgrade(), self.overrides.contains_key(key)) {
+                buckets.set(bandwidth);
+            }
+        }
+    }
+
+    fn set_override(&mut self, key: K, bandwidth: Option<Bandwidth>) {
+        match bandwidth {
+            Some(bandwidth) => self.overrides.insert(key.clone(), bandwidth),
+            None => self.overrides.remove(&key),
+        };
+        if let Some(buckets) = self.buckets.get(&key).and_then(Weak::upgrade) {
+            buckets.set(self.limit(&key));
+        }
+    }
+}
+
+/// Bandwidth limits of a server: global, per user and per client IP.
+///
+/// A connection is held to all the limits that apply to it, so it gets the smallest of their rates, and the connections of a
+/// user or an IP share the rate of its limit. Everything is unlimited by default.
+///
+/// ```
+/// use socks5_impl::server::bandwidth::{Bandwidth, BandwidthLimiter};
+/// use std::sync::Arc;
+///
+/// let limiter = Arc::new(
+///     BandwidthLimiter::new()
+///         .with_global(Bandwidth::new(Some(100 << 20), Some(100 << 20)))
+///         .with_per_user(Bandwidth::new(Some(1 << 20), Some(4 << 20))),
+/// );
+/// // Later, from anywhere holding the `Arc`:
+/// limiter.set_user("alice", Some(Bandwidth::UNLIMITED));
+/// ```
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    global: Arc<Buckets>,
+    global_limit: Mutex<Bandwidth>,
+    users: Mutex<Table<String>>,
+    ips: Mutex<Table<IpAddr>>,
+}
+
+impl BandwidthLimiter {
+    /// Creates a limiter without any limit.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            global: Arc::new(Buckets::new(Bandwidth::UNLIMITED)),
+            global_limit: Mutex::new(Bandwidth::UNLIMITED),
+            users: Mutex::new(Table::new()),
+            ips: Mutex::new(Table::new()),
+        }
+    }
+
+    /// Sets the limit shared by all connections.
+    #[inline]
+    pub fn with_global(self, bandwidth: Bandwidth) -> Self {
+        self.set_global(bandwidth);
+        self
+    }
+
+    /// Sets the default limit of each user.
+    #[inline]
+    pub fn with_per_user(self, bandwidth: Bandwidth) -> Self {
+        self.set_per_user(bandwidth);
+        self
+    }
+
+    /// Sets the default limit of each client IP.
+    #[inline]
+    pub fn with_per_ip(self, bandwidth: Bandwidth) -> Self {
+        self.set_per_ip(bandwidth);
+        self
+    }
+
+    /// Changes the limit shared by all connections.
+    pub fn set_global(&self, bandwidth: Bandwidth) {
+        *self.global_limit.lock().unwrap() = bandwidth;
+        self.global.set(bandwidth);
+    }
+
+    /// Changes the default limit of each user, for the users without a limit of their own.
+    pub fn set_per_user(&self, bandwidth: Bandwidth) {
+        self.users.lock().unwrap().set_default(bandwidth);
+    }
+
+    /// Changes the default limit of each client IP, for the IPs without a limit of their own.
+    pub fn set_per_ip(&self, bandwidth: Bandwidth) {
+        self.ips.lock().unwrap().set_default(bandwidth);
+    }
+
+    /// Gives `user` a limit of its own instead of the default one, or back the default one with `None`.
+    pub fn set_user(&self, user: &str, bandwidth: Option<Bandwidth>) {
+        self.users.lock().unwrap().set_override(user.to_owned(), bandwidth);
+    }
+
+    /// Gives `ip` a limit of its own instead of the default one, or back the default one with `None`.
+    pub fn set_ip(&self, ip: IpAddr, bandwidth: Option<Bandwidth>) {
+        self.ips.lock().unwrap().set_override(ip.to_canonical(), bandwidth);
+    }
+
+    /// Returns the limit shared by all connections.
+    pub fn global(&self) -> Bandwidth {
+        *self.global_limit.lock().unwrap()
+    }
+
+    /// Returns the limit of `user`.
+    pub fn user(&self, user: &str) -> Bandwidth {
+        self.users.lock().unwrap().limit(&user.to_owned())
+    }
+
+    /// Returns the limit of `ip`.
+    pub fn ip(&self, ip: IpAddr) -> Bandwidth {
+        self.ips.lock().unwrap().limit(&ip.to_canonical())
+    }
+
+    /// Returns the throttle of a connection of `user` from `ip`. Anonymous clients and clients without an IP address, such as
+    /// those of Unix sockets, are only held to the limits that apply to them.
+    pub fn throttle(&self, user: Option<&str>, ip: Option<IpAddr>) -> Throttle {
+        let mut buckets = vec![self.global.clone()];
+        if let Some(user) = user {
+            buckets.push(self.users.lock().unwrap().buckets(&user.to_owned()));
+        }
+        if let Some(ip) = ip {
+            buckets.push(self.ips.lock().unwrap().buckets(&ip.to_canonical()));
+        }
+        Throttle { buckets: buckets.into() }
+    }
+}
+
+impl Default for BandwidthLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The share of a connection in the limits of a [`BandwidthLimiter`]. Clones draw from the same buckets.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    buckets: Arc<[Arc<Buckets>]>,
+}
+
+impl Throttle {
+    fn wait(&self, direction: Direction) -> Duration {
+        let now = Instant::now();
+        let wait = self
+            .buckets
+            .iter()
+            .map(|buckets| buckets.bucket(direction).lock().unwrap().wait(now));
+        wait.max().unwrap_or_default()
+    }
+
+    fn take(&self, direction: Direction, len: usize) {
+        for buckets in self.buckets.iter() {
+            buckets.bucket(direction).lock().unwrap().take(len);
+        }
+    }
+
+    /// Returns how long the next upload has to wait for the limits to allow it.
+    #[inline]
+    pub fn upload_wait(&self) -> Duration {
+        self.wait(Direction::Upload)
+    }
+
+    /// Returns how long the next download has to wait for the limits to allow it.
+    #[inline]
+    pub fn download_wait(&self) -> Duration {
+        self.wait(Direction::Download)
+    }
+
+    /// Counts `len` bytes of upload against the limits.
+    #[inline]
+    pub fn take_upload(&self, len: usize) {
+        self.take(Direction::Upload, len)
+    }
+
+    /// Counts `len` bytes of download against the limits.
+    #[inline]
+    pub fn take_download(&self, len: usize) {
+        self.take(Direction::Download, len)
+    }
+
+    /// Counts an upload of `len` bytes against the limits if they allow one now, which is how datagrams above the rate are dropped.
+    pub fn try_upload(&self, len: usize) -> bool {
+        let allowed = self.upload_wait().is_zero();
+        if allowed {
+            self.take_upload(len);
+        }
+        allowed
+    }
+
+    /// Counts a download of `len` bytes against the limits if they allow one now, which is how datagrams above the rate are dropped.
+    pub fn try_download(&self, len: usize) -> bool {
+        let allowed = self.download_wait().is_zero();
+        if allowed {
+            self.take_download(len);
+        }
+        allowed
+    }
+}
+
+/// A client-side stream slowed down to a [`Throttle`]: what is read from it is upload, what is written to it is download.
+///
+/// Without a throttle it is passed through untouched.
+#[derive(Debug)]
+pub struct Throttled<S> {
+    stream: S,
+    throttle: Option<Throttle>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// Wraps `stream`.
+    #[inline]
+    pub fn new(stream: S, throttle: Option<Throttle>) -> Self {
+        Self {
+            stream,
+            throttle,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Returns the throttle of the stream.
+    #[inline]
+    pub fn throttle(&self) -> Option<&Throttle> {
+        self.throttle.as_ref()
+    }
+
+    /// Returns the wrapped stream.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Waits for `delay`, then for as long as `wait` says the limits need.
+fn poll_delay(delay: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>, wait: impl Fn() -> Duration) -> Poll<()> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        let wait = wait();
+        if wait.is_zero() {
+            return Poll::Ready(());
+        }
+        *delay = Some(Box::pin(tokio::time::sleep(wait)));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(throttle) = &this.throttle else {
+            return Pin::new(&mut this.stream).poll_read(cx, buf);
+        };
+        ready!(poll_delay(&mut this.read_delay, cx, || throttle.upload_wait()));
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        throttle.take_upload(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(throttle) = &this.throttle else {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        };
+        ready!(poll_delay(&mut this.write_delay, cx, || throttle.download_wait()));
+        let len = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        throttle.take_download(len);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bandwidth, BandwidthLimiter};
+    use crate::{
+        client,
+        server::{auth, DefaultHandler, Server},
+    };
+    use std::{net::IpAddr, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, BufStream},
+        net::{TcpListener, TcpStream},
+        time::Instant,
+    };
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn about(wait: Duration, secs: u64) -> bool {
+        let secs = Duration::from_secs(secs);
+        wait <= secs && wait > secs - Duration::from_millis(100)
+    }
+
+    #[test]
+    fn throttles_share_buckets() {
+        let limiter = BandwidthLimiter::new().with_per_ip(Bandwidth::new(None, Some(1000)));
+        let a = limiter.throttle(Some("alice"), Some(ip("192.0.2.1")));
+        let b = limiter.throttle(None, Some(ip("::ffff:192.0.2.1")));
+        let other = limiter.throttle(None, Some(ip("192.0.2.2")));
+
+        a.take_download(3000);
+        assert!(about(b.download_wait(), 2));
+        assert!(!b.try_download(1));
+        assert!(other.try_download(1000));
+        assert!(a.upload_wait().is_zero());
+
+        limiter.set_user("alice", Some(Bandwidth::new(Some(10), None)));
+        limiter.set_ip(ip("192.0.2.1"), Some(Bandwidth::UNLIMITED));
+        assert_eq!(limiter.ip(ip("::ffff:192.0.2.1")), Bandwidth::UNLIMITED);
+        assert!(a.try_download(1 << 20));
+        a.take_upload(20);
+        assert!(about(a.upload_wait(), 1));
+        assert!(b.upload_wait().is_zero());
+
+        limiter.set_global(Bandwidth::new(None, Some(100)));
+        b.take_download(200);
+        assert!(about(other.download_wait(), 1));
+    }
+
+    #[tokio::test]
+    async fn limited_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    tokio::io::copy(&mut r, &mut w).await
+                });
+            }
+        });
+        let limiter = Arc::new(BandwidthLimiter::new().with_per_ip(Bandwidth::new(None, Some(50_000))));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth))
+            .await
+            .unwrap()
+            .with_bandwidth_limiter(limiter.clone());
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.serve(DefaultHandler));
+
+        let transfer = || async {
+            let mut stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+            client::connect(&mut stream, echo, None).await.unwrap();
+            let start = Instant::now();
+            stream.write_all(&[7; 110_000]).await.unwrap();
+            stream.flush().await.unwrap();
+            let mut buf = vec![0; 110_000];
+            stream.read_exact(&mut buf).await.unwrap();
+            start.elapsed()
+        };
+        // One second of burst, then more than a second at the rate.
+        let elapsed = transfer().await;
+        assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+
+        limiter.set_ip(ip("127.0.0.1"), Some(Bandwidth::UNLIMITED));
+        let elapsed = transfer().await;
+        assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+    }
+}
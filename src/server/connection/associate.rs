@@ -1,60 +1,153 @@
-use crate::protocol::{Address, AsyncStreamOperation, Reply, Response, StreamOperation, UdpHeader};
-use bytes::{Bytes, BytesMut};
+use crate::{
+    protocol::{Address, AsyncStreamOperation, Reply, StreamOperation, UdpHeader, UdpReassembler, Version},
+    resolver::Resolver,
+    server::{
+        bandwidth::Throttle,
+        connection::{ConnContext, StreamAddrs},
+        ssrf::SsrfGuard,
+        timeout::{Expired, Phase, Timeouts},
+    },
+};
+use bytes::Bytes;
 use std::{
-    net::SocketAddr,
+    collections::{hash_map::Entry, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
     time::Duration,
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::{TcpStream, ToSocketAddrs, UdpSocket},
+    task::{Id, JoinHandle, JoinSet},
+    time::Instant,
 };
 
 /// Socks5 connection type `UdpAssociate`
+///
+/// `O` is the output of the [`AuthExecutor`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html)
+/// that authenticated the client, see [`auth()`](#method.auth). `T` is the transport stream, see
+/// [`IncomingConnection`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.IncomingConnection.html).
 #[derive(Debug)]
-pub struct UdpAssociate<S> {
-    stream: TcpStream,
+pub struct UdpAssociate<S, O = (), T = TcpStream> {
+    stream: T,
+    addrs: StreamAddrs,
+    ctx: ConnContext,
+    auth: O,
+    filter: Option<DestinationFilter>,
     _state: S,
 }
 
-impl<S: Default> UdpAssociate<S> {
+impl<S: Default, O, T> UdpAssociate<S, O, T> {
     #[inline]
-    pub(super) fn new(stream: TcpStream) -> Self {
+    pub(super) fn new(stream: T, addrs: StreamAddrs, ctx: ConnContext, auth: O) -> Self {
         Self {
             stream,
+            addrs,
+            ctx,
+            auth,
+            filter: None,
             _state: S::default(),
         }
     }
 
-    /// Reply to the SOCKS5 client with the given reply and address.
-    ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<UdpAssociate<Ready>> {
-        let resp = Response::new(reply, addr);
-        resp.write_to_async_stream(&mut self.stream).await?;
-        Ok(UdpAssociate::<Ready>::new(self.stream))
+    /// Only lets [`relay()`](#method.relay) forward datagrams of the client to the destinations `filter` returns `true`
+    /// for, as given in their header, before they are looked up. The others are dropped. Each call adds a filter that
+    /// must allow a destination too, so handlers wrapping each other can all add one.
+    pub fn with_destination_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Address) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(match self.filter.take() {
+            Some(DestinationFilter(outer)) => DestinationFilter(Arc::new(move |addr: &Address| outer(addr) && filter(addr))),
+            None => DestinationFilter(Arc::new(filter)),
+        });
+        self
     }
 
-    /// Causes the other peer to receive a read of length 0, indicating that no more data will be sent. This only closes the stream in one direction.
+    /// Returns what the client was authenticated as, e.g. its user name.
     #[inline]
-    pub async fn shutdown(&mut self) -> std::io::Result<()> {
-        self.stream.shutdown().await
+    pub fn auth(&self) -> &O {
+        &self.auth
+    }
+
+    /// Returns the resolver the server was configured with, for looking up the requested address.
+    #[inline]
+    pub fn resolver(&self) -> &Arc<dyn Resolver> {
+        &self.ctx.resolver
+    }
+
+    /// Returns the SSRF guard the server was configured with, if any.
+    #[inline]
+    pub fn ssrf_guard(&self) -> Option<&Arc<SsrfGuard>> {
+        self.ctx.ssrf_guard.as_ref()
+    }
+
+    /// Returns the deadlines of the connection, see the
+    /// [`timeout`](https://docs.rs/socks5-impl/latest/socks5_impl/server/timeout/index.html) module.
+    #[inline]
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.ctx.timeouts
+    }
+
+    /// Returns the share of the client in the bandwidth limits of the server, if it has any.
+    #[inline]
+    pub fn throttle(&self) -> Option<&Throttle> {
+        self.ctx.throttle.as_ref()
+    }
+
+    /// Looks up `addr` with the [`resolver()`](#method.resolver), keeping only the addresses the [`ssrf_guard()`](#method.ssrf_guard)
+    /// allows. Fails with [`PermissionDenied`](std::io::ErrorKind::PermissionDenied) if the guard blocks all of them.
+    ///
+    /// The returned future does not borrow the connection, so it can be awaited while the connection is used elsewhere.
+    #[inline]
+    pub fn resolve(&self, addr: &Address) -> impl std::future::Future<Output = std::io::Result<Vec<SocketAddr>>> + Send + 'static {
+        let (ctx, addr) = (self.ctx.clone(), addr.clone());
+        async move { ctx.resolve(&addr).await }
     }
 
     /// Returns the local address that this stream is bound to.
     #[inline]
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.local_addr()
+        self.addrs.local()
     }
 
     /// Returns the remote address that this stream is connected to.
     #[inline]
     pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.peer_addr()
+        self.addrs.peer()
     }
 
+    /// Returns the transport stream.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+impl<S: Default, O, T: AsyncWrite + Unpin + Send> UdpAssociate<S, O, T> {
+    /// Reply to the SOCKS5 client with the given reply and address.
+    ///
+    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<UdpAssociate<Ready, O, T>> {
+        super::write_reply(&mut self.stream, Version::V5, reply, addr).await?;
+        let mut conn = UdpAssociate::new(self.stream, self.addrs, self.ctx, self.auth);
+        conn.filter = self.filter;
+        Ok(conn)
+    }
+
+    /// Causes the other peer to receive a read of length 0, indicating that no more data will be sent. This only closes the stream in one direction.
+    #[inline]
+    pub async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+impl<S: Default, O> UdpAssociate<S, O> {
     /// Reads the linger duration for this socket by getting the `SO_LINGER` option.
     ///
     /// For more information about this option, see
@@ -72,6 +165,7 @@ impl<S: Default> UdpAssociate<S> {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
         self.stream.set_linger(dur)
     }
@@ -110,13 +204,23 @@ impl<S: Default> UdpAssociate<S> {
     }
 }
 
+/// The destination filters added with [`UdpAssociate::with_destination_filter()`], all in one.
+#[derive(Clone)]
+struct DestinationFilter(Arc<dyn Fn(&Address) -> bool + Send + Sync>);
+
+impl std::fmt::Debug for DestinationFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DestinationFilter")
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct NeedReply;
 
 #[derive(Debug, Default)]
 pub struct Ready;
 
-impl UdpAssociate<Ready> {
+impl<O, T: AsyncRead + Unpin> UdpAssociate<Ready, O, T> {
     /// Wait until the client closes this TCP connection.
     ///
     /// Socks5 protocol defines that when the client closes the TCP connection used to send the associate command,
@@ -132,8 +236,8 @@ impl UdpAssociate<Ready> {
     }
 }
 
-impl std::ops::Deref for UdpAssociate<Ready> {
-    type Target = TcpStream;
+impl<O, T> std::ops::Deref for UdpAssociate<Ready, O, T> {
+    type Target = T;
 
     #[inline]
     fn deref(&self) -> &Self::Target {
@@ -141,21 +245,21 @@ impl std::ops::Deref for UdpAssociate<Ready> {
     }
 }
 
-impl std::ops::DerefMut for UdpAssociate<Ready> {
+impl<O, T> std::ops::DerefMut for UdpAssociate<Ready, O, T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.stream
     }
 }
 
-impl AsyncRead for UdpAssociate<Ready> {
+impl<O, T: AsyncRead + Unpin> AsyncRead for UdpAssociate<Ready, O, T> {
     #[inline]
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
         Pin::new(&mut self.stream).poll_read(cx, buf)
     }
 }
 
-impl AsyncWrite for UdpAssociate<Ready> {
+impl<O, T: AsyncWrite + Unpin> AsyncWrite for UdpAssociate<Ready, O, T> {
     #[inline]
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
         Pin::new(&mut self.stream).poll_write(cx, buf)
@@ -172,9 +276,219 @@ impl AsyncWrite for UdpAssociate<Ready> {
     }
 }
 
-impl<S> From<UdpAssociate<S>> for TcpStream {
+impl<O, T> UdpAssociate<NeedReply, O, T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// Serves the request from start to end, `addr` being the `DST.ADDR` of the client, i.e. the address it will send
+    /// datagrams from.
+    ///
+    /// Opens a relay socket on the IP the client reached the server at and sends it in the reply, then forwards
+    /// datagrams until the client closes the TCP connection. Datagrams are only accepted from the IP of `addr`, or of the
+    /// TCP connection if `addr` is unspecified or a domain, and from the port of `addr` if it is not 0. Over a transport
+    /// without an IP, such as a Unix socket, an unspecified `addr` accepts the IP of the first datagram, and only it.
+    /// See [`UdpRelayOptions::with_advertised_ip()`] for where the relay socket is opened then.
+    ///
+    /// Each pair of client source and destination gets a mapping with an outbound socket of its own, of the family of the
+    /// destination, so IPv4 and IPv6 targets can be mixed and replies always reach the client that caused them. Mappings
+    /// without traffic for [`UdpRelayOptions::with_mapping_idle_timeout()`] are closed, and datagrams that would open more
+    /// than [`UdpRelayOptions::with_max_mappings()`] are dropped, as are datagrams to destinations a filter added with
+    /// [`with_destination_filter()`](#method.with_destination_filter) denies. Domain destinations are looked up with the
+    /// resolver of the connection once per mapping, in the background so that a slow lookup holds up no other traffic,
+    /// and their datagrams wait for it, up to 16 of them. Datagrams to destinations the SSRF guard of the connection
+    /// blocks are dropped.
+    /// Fragmented datagrams from the client are reassembled once they passed the source check, for at most
+    /// [`MAX_REASSEMBLY_QUEUES`] client ports at once, and replies are fragmented if
+    /// [`UdpRelayOptions::with_fragment_mtu()`] is set. Datagrams above the bandwidth limits of the client are dropped.
+    ///
+    /// The association ends with an [`Expired`] error once no datagram went through it for the relay idle timeout of the
+    /// connection, or once it reaches the maximum lifetime, see [`timeouts()`](#method.timeouts).
+    ///
+    /// `stats` is updated as traffic flows and can be read from elsewhere while the relay runs.
+    pub async fn relay(self, addr: Address, options: &UdpRelayOptions, stats: Arc<UdpRelayStats>) -> crate::Result<()> {
+        let peer_ip = self.addrs.peer().ok().map(|addr| addr.ip());
+        let mut client = match addr {
+            Address::SocketAddress(addr) if !addr.ip().is_unspecified() => (Some(addr.ip()), addr.port()),
+            Address::SocketAddress(addr) => (peer_ip, addr.port()),
+            Address::DomainAddress(..) => (peer_ip, 0),
+        };
+
+        let bind_ip = options
+            .bind_ip
+            .or_else(|| self.addrs.local().ok().map(|addr| addr.ip()))
+            .or_else(|| options.advertised_ip.map(unspecified_of));
+        let Some(bind_ip) = bind_ip else {
+            let err = "no IP to open the relay socket on, the transport has none and no advertised IP is set";
+            return Err(self.fail(std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, err)).await);
+        };
+        let listen = match UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await {
+            Ok(listen) => listen,
+            Err(err) => return Err(self.fail(err).await),
+        };
+        let listen_addr = listen.local_addr()?;
+        let advertised = options
+            .advertised_ip
+            .map_or(listen_addr, |ip| SocketAddr::new(ip, listen_addr.port()));
+        let ctx = self.ctx.clone();
+        let filter = self.filter.clone();
+        let mut conn = self.reply(Reply::Succeeded, Address::from(advertised)).await?;
+        log::debug!("[UDP] {listen_addr} relaying for {:?}", client.0);
+
+        let listen = Arc::new(AssociatedUdpSocket::from((listen, options.max_packet_size)));
+        listen.set_fragment_mtu(options.fragment_mtu);
+        listen.set_throttle(ctx.throttle.clone());
+        let start = Instant::now();
+        let mut nat = NatTable::new(stats.clone());
+        // Domain lookups in flight, with the datagrams waiting for them, so that a slow one holds up nothing else.
+        let mut lookups = JoinSet::new();
+        let mut pending = HashMap::<(SocketAddr, Address), Vec<Bytes>>::new();
+        let mut lookup_keys = HashMap::<Id, (SocketAddr, Address)>::new();
+        let idle_timeout = ctx.timeouts.idle_timeout();
+        let sweep_period = idle_timeout.map_or(options.mapping_idle_timeout, |idle| idle.min(options.mapping_idle_timeout));
+        let mut sweep = tokio::time::interval((sweep_period / 4).max(Duration::from_millis(10)));
+        let mut last_from_client = 0;
+        let lifetime = async {
+            let Some(lifetime) = ctx.timeouts.max_lifetime() else {
+                return std::future::pending().await;
+            };
+            tokio::time::sleep_until(start + lifetime).await;
+            Expired::new(Phase::Lifetime, lifetime)
+        };
+        tokio::pin!(lifetime);
+
+        loop {
+            tokio::select! {
+                res = listen.recv_unthrottled_from() => {
+                    let (pkt, frag, dst_addr, src_addr) = res?;
+                    let client_ip = *client.0.get_or_insert(src_addr.ip());
+                    if src_addr.ip() != client_ip || (client.1 != 0 && src_addr.port() != client.1) {
+                        log::debug!("[UDP] {listen_addr} dropping datagram from unexpected {src_addr}");
+                        stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    // Only charged now, so datagrams from other hosts cannot use up the bandwidth of the client.
+                    if !listen.allows_upload(pkt.len()) {
+                        log::trace!("[UDP] {src_addr} dropping datagram above the bandwidth limit");
+                        stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    let Some((dst_addr, pkt)) = listen.reassemble(src_addr, frag, &dst_addr, &pkt) else {
+                        continue;
+                    };
+                    if filter.as_ref().is_some_and(|DestinationFilter(allows)| !allows(&dst_addr)) {
+                        log::debug!("[UDP] {src_addr} -> {dst_addr} dropped by the destination filter");
+                        stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    stats.packets_from_client.fetch_add(1, Ordering::Relaxed);
+                    stats.bytes_from_client.fetch_add(pkt.len() as u64, Ordering::Relaxed);
+                    last_from_client = elapsed_millis(start);
+
+                    let dst = match &dst_addr {
+                        Address::SocketAddress(dst) => {
+                            if let Some(Err(err)) = ctx.ssrf_guard.as_ref().map(|guard| guard.filter(vec![*dst])) {
+                                log::debug!("[UDP] {src_addr} -> {dst} {err}");
+                                stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                            *dst
+                        }
+                        Address::DomainAddress(..) => match nat.domains.get(&(src_addr, dst_addr.clone())) {
+                            Some(dst) => *dst,
+                            None => {
+                                let key = (src_addr, dst_addr);
+                                let full = nat.mappings.len() + pending.len() >= options.max_mappings;
+                                match pending.get_mut(&key) {
+                                    Some(queue) if queue.len() < MAX_DATAGRAMS_PER_LOOKUP => queue.push(pkt),
+                                    Some(_) => {
+                                        log::debug!("[UDP] {src_addr} -> {} dropped, its lookup has a full queue", key.1);
+                                        stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    None if full => {
+                                        let max = options.max_mappings;
+                                        log::debug!("[UDP] {src_addr} -> {} dropped, {max} mappings already open", key.1);
+                                        stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    None => {
+                                        let (ctx, addr) = (ctx.clone(), key.1.clone());
+                                        let id = lookups.spawn(async move { ctx.resolve(&addr).await }).id();
+                                        lookup_keys.insert(id, key.clone());
+                                        pending.insert(key, vec![pkt]);
+                                    }
+                                }
+                                continue;
+                            }
+                        },
+                    };
+                    nat.forward(src_addr, dst, &pkt, &listen, options, start).await;
+                },
+                Some(res) = lookups.join_next_with_id(), if !lookups.is_empty() => {
+                    let (id, res) = match res {
+                        Ok((id, res)) => (id, res),
+                        Err(err) => (err.id(), Err(std::io::Error::other(err))),
+                    };
+                    let Some(key) = lookup_keys.remove(&id) else {
+                        continue;
+                    };
+                    let queue = pending.remove(&key).unwrap_or_default();
+                    let (src_addr, dst_addr) = key;
+                    let dst = match res {
+                        Ok(addrs) => addrs[0],
+                        Err(err) => {
+                            log::debug!("[UDP] {src_addr} -> {dst_addr} {err}");
+                            stats.packets_dropped.fetch_add(queue.len() as u64, Ordering::Relaxed);
+                            continue;
+                        }
+                    };
+                    for pkt in queue {
+                        nat.forward(src_addr, dst, &pkt, &listen, options, start).await;
+                    }
+                    if nat.mappings.contains_key(&(src_addr, dst)) && nat.domains.len() < options.max_mappings {
+                        nat.domains.insert((src_addr, dst_addr), dst);
+                    }
+                },
+                _ = sweep.tick() => {
+                    let now = elapsed_millis(start);
+                    if let Some(idle) = idle_timeout {
+                        let mappings_active = nat.mappings.values().map(|mapping| mapping.last_active.load(Ordering::Relaxed));
+                        let last_active = mappings_active.fold(last_from_client, u64::max);
+                        if now.saturating_sub(last_active) >= idle.as_millis() as u64 {
+                            log::debug!("[UDP] {listen_addr} idle, releasing {} mappings", nat.mappings.len());
+                            return Err(Expired::new(Phase::Idle, idle).into());
+                        }
+                    }
+                    nat.sweep(now, options.mapping_idle_timeout);
+                },
+                expired = &mut lifetime => {
+                    log::debug!("[UDP] {listen_addr} {expired}, releasing {} mappings", nat.mappings.len());
+                    return Err(expired.into());
+                },
+                res = conn.wait_until_closed() => {
+                    log::debug!("[UDP] {listen_addr} client closed, releasing {} mappings", nat.mappings.len());
+                    break res?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    async fn fail(self, err: std::io::Error) -> crate::Error {
+        match self.reply(Reply::GeneralFailure, Address::unspecified()).await {
+            Ok(mut conn) => {
+                let _ = conn.shutdown().await;
+                err.into()
+            }
+            Err(err) => err.into(),
+        }
+    }
+}
+
+// The output of the authentication is never pinned, only the stream is polled.
+impl<S, O, T: Unpin> Unpin for UdpAssociate<S, O, T> {}
+
+impl<S, O> From<UdpAssociate<S, O>> for TcpStream {
     #[inline]
-    fn from(conn: UdpAssociate<S>) -> Self {
+    fn from(conn: UdpAssociate<S, O>) -> Self {
         conn.stream
     }
 }
@@ -197,6 +511,10 @@ impl<S> From<UdpAssociate<S>> for TcpStream {
 pub struct AssociatedUdpSocket {
     socket: UdpSocket,
     buf_size: AtomicUsize,
+    fragment_mtu: AtomicUsize,
+    reassemblers: Mutex<HashMap<SocketAddr, UdpReassembler>>,
+    max_reassembly_queues: AtomicUsize,
+    throttle: Mutex<Option<Throttle>>,
 }
 
 impl AssociatedUdpSocket {
@@ -216,6 +534,104 @@ impl AssociatedUdpSocket {
         self.buf_size.store(size, Ordering::Release);
     }
 
+    /// Returns the size above which unfragmented packets are split by the send methods, if any.
+    pub fn fragment_mtu(&self) -> Option<usize> {
+        Some(self.fragment_mtu.load(Ordering::Relaxed)).filter(|&mtu| mtu != 0)
+    }
+
+    /// Makes [`send()`](#method.send) and [`send_to()`](#method.send_to) split packets given with a fragment number of 0
+    /// into fragments of at most `mtu` bytes, socks5 UDP header included, when they do not fit. `None` disables it, which is the default.
+    pub fn set_fragment_mtu(&self, mtu: Option<usize>) {
+        self.fragment_mtu.store(mtu.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Returns the maximum number of source addresses [`recv_from_reassembled()`](#method.recv_from_reassembled) keeps
+    /// fragments of at once.
+    pub fn max_reassembly_queues(&self) -> usize {
+        self.max_reassembly_queues.load(Ordering::Relaxed)
+    }
+
+    /// Limits the number of source addresses [`recv_from_reassembled()`](#method.recv_from_reassembled) keeps fragments of
+    /// at once to `max`. Fragments starting a queue above it are dropped until a queue completes or expires.
+    /// Defaults to [`MAX_REASSEMBLY_QUEUES`].
+    pub fn set_max_reassembly_queues(&self, max: usize) {
+        self.max_reassembly_queues.store(max, Ordering::Relaxed);
+    }
+
+    /// Returns the throttle the traffic of the socket is held to, if any.
+    pub fn throttle(&self) -> Option<Throttle> {
+        self.throttle.lock().unwrap().clone()
+    }
+
+    /// Holds the traffic of the socket to `throttle`: the receive methods drop packets above the upload rate, and the send
+    /// methods fail with [`std::io::ErrorKind::WouldBlock`] for packets above the download rate. `None`, the default, removes it.
+    pub fn set_throttle(&self, throttle: Option<Throttle>) {
+        *self.throttle.lock().unwrap() = throttle;
+    }
+
+    fn allows_upload(&self, len: usize) -> bool {
+        self.throttle
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|throttle| throttle.try_upload(len))
+    }
+
+    fn check_download(&self, len: usize) -> std::io::Result<()> {
+        match &*self.throttle.lock().unwrap() {
+            Some(throttle) if !throttle.try_download(len) => {
+                Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "bandwidth limit exceeded"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Like [`recv()`](#method.recv), but reassembles fragmented packets as RFC 1928 section 7 describes,
+    /// and only returns complete ones.
+    pub async fn recv_reassembled(&self) -> std::io::Result<(Bytes, Address)> {
+        let peer_addr = self.socket.peer_addr()?;
+        loop {
+            let (pkt, frag, addr) = self.recv().await?;
+            if let Some((addr, pkt)) = self.reassemble(peer_addr, frag, &addr, &pkt) {
+                return Ok((pkt, addr));
+            }
+        }
+    }
+
+    /// Like [`recv_from()`](#method.recv_from), but reassembles fragmented packets as RFC 1928 section 7 describes,
+    /// with a queue per source address, and only returns complete ones.
+    pub async fn recv_from_reassembled(&self) -> std::io::Result<(Bytes, Address, SocketAddr)> {
+        loop {
+            let (pkt, frag, addr, src_addr) = self.recv_from().await?;
+            if let Some((addr, pkt)) = self.reassemble(src_addr, frag, &addr, &pkt) {
+                return Ok((pkt, addr, src_addr));
+            }
+        }
+    }
+
+    fn reassemble(&self, src_addr: SocketAddr, frag: u8, addr: &Address, pkt: &Bytes) -> Option<(Address, Bytes)> {
+        let mut reassemblers = self.reassemblers.lock().unwrap();
+        if frag == 0 {
+            reassemblers.remove(&src_addr);
+            return Some((addr.clone(), pkt.clone()));
+        }
+        let max = self.max_reassembly_queues();
+        if !reassemblers.contains_key(&src_addr) && reassemblers.len() >= max {
+            // Expired queues are only swept when they are in the way of a new one.
+            reassemblers.retain(|_, reassembler| reassembler.is_pending());
+            if reassemblers.len() >= max {
+                log::trace!("[UDP] dropping fragment from {src_addr}, {max} reassembly queues already open");
+                return None;
+            }
+        }
+        let reassembler = reassemblers.entry(src_addr).or_default();
+        let res = reassembler.push(frag, addr, pkt);
+        if !reassembler.is_pending() {
+            reassemblers.remove(&src_addr);
+        }
+        res.map(|(addr, pkt)| (addr, Bytes::from(pkt)))
+    }
+
     /// Receives a socks5 UDP relay packet on the socket from the remote address to which it is connected.
     /// On success, returns the packet itself, the fragment number and the remote target address.
     ///
@@ -231,6 +647,10 @@ impl AssociatedUdpSocket {
 
             if let Ok(header) = UdpHeader::retrieve_from_async_stream(&mut pkt.as_ref()).await {
                 let pkt = pkt.slice(header.len()..);
+                if !self.allows_upload(pkt.len()) {
+                    log::trace!("[UDP] dropping packet above the bandwidth limit");
+                    continue;
+                }
                 return Ok((pkt, header.frag, header.address));
             }
         }
@@ -239,6 +659,19 @@ impl AssociatedUdpSocket {
     /// Receives a socks5 UDP relay packet on the socket from the any remote address.
     /// On success, returns the packet itself, the fragment number, the remote target address and the source address.
     pub async fn recv_from(&self) -> std::io::Result<(Bytes, u8, Address, SocketAddr)> {
+        loop {
+            let (pkt, frag, addr, src_addr) = self.recv_unthrottled_from().await?;
+            if !self.allows_upload(pkt.len()) {
+                log::trace!("[UDP] dropping packet from {src_addr} above the bandwidth limit");
+                continue;
+            }
+            return Ok((pkt, frag, addr, src_addr));
+        }
+    }
+
+    /// Like [`recv_from()`](#method.recv_from), without holding the packet to the throttle, for callers that only charge
+    /// it once they checked the source.
+    async fn recv_unthrottled_from(&self) -> std::io::Result<(Bytes, u8, Address, SocketAddr)> {
         loop {
             let max_packet_size = self.buf_size.load(Ordering::Acquire);
             let mut buf = vec![0; max_packet_size];
@@ -247,30 +680,42 @@ impl AssociatedUdpSocket {
             let pkt = Bytes::from(buf);
 
             if let Ok(header) = UdpHeader::retrieve_from_async_stream(&mut pkt.as_ref()).await {
-                let pkt = pkt.slice(header.len()..);
-                return Ok((pkt, header.frag, header.address, src_addr));
+                return Ok((pkt.slice(header.len()..), header.frag, header.address, src_addr));
             }
         }
     }
 
     /// Sends a UDP relay packet to the remote address to which it is connected. The socks5 UDP header will be added to the packet.
     pub async fn send<P: AsRef<[u8]>>(&self, pkt: P, frag: u8, from_addr: Address) -> std::io::Result<usize> {
-        let header = UdpHeader::new(frag, from_addr);
-        let mut buf = BytesMut::with_capacity(header.len() + pkt.as_ref().len());
-        header.write_to_buf(&mut buf);
-        buf.extend_from_slice(pkt.as_ref());
-
-        self.socket.send(&buf).await.map(|len| len - header.len())
+        let pkt = pkt.as_ref();
+        self.check_download(pkt.len())?;
+        for datagram in self.datagrams(pkt, frag, from_addr)? {
+            self.socket.send(&datagram).await?;
+        }
+        Ok(pkt.len())
     }
 
     /// Sends a UDP relay packet to a specified remote address to which it is connected. The socks5 UDP header will be added to the packet.
     pub async fn send_to<P: AsRef<[u8]>>(&self, pkt: P, frag: u8, from_addr: Address, to_addr: SocketAddr) -> std::io::Result<usize> {
-        let header = UdpHeader::new(frag, from_addr);
-        let mut buf = BytesMut::with_capacity(header.len() + pkt.as_ref().len());
-        header.write_to_buf(&mut buf);
-        buf.extend_from_slice(pkt.as_ref());
+        let pkt = pkt.as_ref();
+        self.check_download(pkt.len())?;
+        for datagram in self.datagrams(pkt, frag, from_addr)? {
+            self.socket.send_to(&datagram, to_addr).await?;
+        }
+        Ok(pkt.len())
+    }
 
-        self.socket.send_to(&buf, to_addr).await.map(|len| len - header.len())
+    fn datagrams(&self, pkt: &[u8], frag: u8, from_addr: Address) -> crate::Result<Vec<Vec<u8>>> {
+        match self.fragment_mtu() {
+            Some(mtu) if frag == 0 => UdpHeader::fragment(&from_addr, pkt, mtu),
+            _ => {
+                let header = UdpHeader::new(frag, from_addr);
+                let mut buf = Vec::with_capacity(header.len() + pkt.len());
+                header.try_write_to_buf(&mut buf)?;
+                buf.extend_from_slice(pkt);
+                Ok(vec![buf])
+            }
+        }
     }
 }
 
@@ -280,6 +725,10 @@ impl From<(UdpSocket, usize)> for AssociatedUdpSocket {
         AssociatedUdpSocket {
             socket: from.0,
             buf_size: AtomicUsize::new(from.1),
+            fragment_mtu: AtomicUsize::new(0),
+            reassemblers: Mutex::new(HashMap::new()),
+            max_reassembly_queues: AtomicUsize::new(MAX_REASSEMBLY_QUEUES),
+            throttle: Mutex::new(None),
         }
     }
 }
@@ -304,3 +753,606 @@ impl AsMut<UdpSocket> for AssociatedUdpSocket {
         &mut self.socket
     }
 }
+
+/// The default of [`AssociatedUdpSocket::set_max_reassembly_queues()`].
+pub const MAX_REASSEMBLY_QUEUES: usize = 16;
+
+/// The default of [`UdpRelayOptions::with_max_packet_size()`].
+pub const MAX_UDP_RELAY_PACKET_SIZE: usize = 1500;
+
+/// The default of [`UdpRelayOptions::with_max_mappings()`].
+pub const MAX_UDP_RELAY_MAPPINGS: usize = 256;
+
+/// How many datagrams to a domain destination wait for its lookup at most, the next ones are dropped.
+const MAX_DATAGRAMS_PER_LOOKUP: usize = 16;
+
+/// Settings of [`UdpAssociate::relay()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/associate/struct.UdpAssociate.html#method.relay).
+#[derive(Clone, Debug)]
+pub struct UdpRelayOptions {
+    mapping_idle_timeout: Duration,
+    max_packet_size: usize,
+    fragment_mtu: Option<usize>,
+    max_mappings: usize,
+    bind_ip: Option<IpAddr>,
+    advertised_ip: Option<IpAddr>,
+}
+
+impl UdpRelayOptions {
+    /// How long a mapping between a client source and a destination is kept without traffic in either direction.
+    /// Defaults to 60 seconds.
+    #[inline]
+    pub fn with_mapping_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.mapping_idle_timeout = timeout;
+        self
+    }
+
+    /// The largest datagram relayed, SOCKS5 UDP header included. Defaults to [`MAX_UDP_RELAY_PACKET_SIZE`].
+    #[inline]
+    pub fn with_max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = size;
+        self
+    }
+
+    /// Splits replies to the client that do not fit in `mtu` bytes, SOCKS5 UDP header included, into fragments.
+    /// Defaults to `None`, sending replies whole.
+    #[inline]
+    pub fn with_fragment_mtu(mut self, mtu: Option<usize>) -> Self {
+        self.fragment_mtu = mtu;
+        self
+    }
+
+    /// The largest number of mappings open at once. Datagrams that would open another one are dropped.
+    /// Defaults to [`MAX_UDP_RELAY_MAPPINGS`].
+    #[inline]
+    pub fn with_max_mappings(mut self, max: usize) -> Self {
+        self.max_mappings = max;
+        self
+    }
+
+    /// The IP the relay socket is opened on. Defaults to `None`, the IP the client reached the server at, or the
+    /// unspecified IP of the family of [`with_advertised_ip()`](Self::with_advertised_ip) for a transport without one,
+    /// such as a Unix socket.
+    #[inline]
+    pub fn with_bind_ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.bind_ip = ip;
+        self
+    }
+
+    /// The IP sent to the client as the address of the relay socket, with the port of the socket, for when the
+    /// socket is reached at another IP than the one it is opened on, e.g. behind NAT or from a Unix socket client.
+    /// Defaults to `None`, sending the address the socket is opened on.
+    #[inline]
+    pub fn with_advertised_ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.advertised_ip = ip;
+        self
+    }
+
+    /// Returns the mapping idle timeout.
+    #[inline]
+    pub fn mapping_idle_timeout(&self) -> Duration {
+        self.mapping_idle_timeout
+    }
+
+    /// Returns the largest datagram relayed.
+    #[inline]
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// Returns the fragment size of replies, if any.
+    #[inline]
+    pub fn fragment_mtu(&self) -> Option<usize> {
+        self.fragment_mtu
+    }
+
+    /// Returns the largest number of mappings open at once.
+    #[inline]
+    pub fn max_mappings(&self) -> usize {
+        self.max_mappings
+    }
+
+    /// Returns the IP the relay socket is opened on, if set.
+    #[inline]
+    pub fn bind_ip(&self) -> Option<IpAddr> {
+        self.bind_ip
+    }
+
+    /// Returns the IP sent to the client for the relay socket, if set.
+    #[inline]
+    pub fn advertised_ip(&self) -> Option<IpAddr> {
+        self.advertised_ip
+    }
+}
+
+impl Default for UdpRelayOptions {
+    fn default() -> Self {
+        Self {
+            mapping_idle_timeout: Duration::from_secs(60),
+            max_packet_size: MAX_UDP_RELAY_PACKET_SIZE,
+            fragment_mtu: None,
+            max_mappings: MAX_UDP_RELAY_MAPPINGS,
+            bind_ip: None,
+            advertised_ip: None,
+        }
+    }
+}
+
+/// Traffic counters of a [`UdpAssociate::relay()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/associate/struct.UdpAssociate.html#method.relay),
+/// updated while it runs. Byte counts are of payloads, without the SOCKS5 UDP header.
+#[derive(Debug, Default)]
+pub struct UdpRelayStats {
+    packets_from_client: AtomicU64,
+    bytes_from_client: AtomicU64,
+    packets_to_client: AtomicU64,
+    bytes_to_client: AtomicU64,
+    packets_dropped: AtomicU64,
+    mappings: AtomicUsize,
+}
+
+impl UdpRelayStats {
+    /// Datagrams received from the client and accepted for forwarding.
+    pub fn packets_from_client(&self) -> u64 {
+        self.packets_from_client.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes received from the client and accepted for forwarding.
+    pub fn bytes_from_client(&self) -> u64 {
+        self.bytes_from_client.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams sent back to the client.
+    pub fn packets_to_client(&self) -> u64 {
+        self.packets_to_client.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes sent back to the client.
+    pub fn bytes_to_client(&self) -> u64 {
+        self.bytes_to_client.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams dropped, either from an unexpected source, to a destination that is filtered out or could not be
+    /// reached, above the bandwidth limits of the client or over the mapping limit.
+    pub fn packets_dropped(&self) -> u64 {
+        self.packets_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Mappings currently open.
+    pub fn mappings(&self) -> usize {
+        self.mappings.load(Ordering::Relaxed)
+    }
+}
+
+/// The NAT table of a relay. Its mappings are taken off [`UdpRelayStats::mappings()`] when it is dropped, however the
+/// relay ends.
+struct NatTable {
+    mappings: HashMap<(SocketAddr, SocketAddr), Mapping>,
+    // Domain destinations resolved for a client source, kept as long as the mapping they lead to.
+    domains: HashMap<(SocketAddr, Address), SocketAddr>,
+    stats: Arc<UdpRelayStats>,
+}
+
+impl NatTable {
+    fn new(stats: Arc<UdpRelayStats>) -> Self {
+        Self {
+            mappings: HashMap::new(),
+            domains: HashMap::new(),
+            stats,
+        }
+    }
+
+    /// Sends `pkt` from the client source `src` to `dst`, opening a mapping for them unless there are too many.
+    async fn forward(
+        &mut self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        pkt: &[u8],
+        listen: &Arc<AssociatedUdpSocket>,
+        options: &UdpRelayOptions,
+        start: Instant,
+    ) {
+        let len = self.mappings.len();
+        let mapping = match self.mappings.entry((src, dst)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(_) if len >= options.max_mappings => {
+                log::debug!("[UDP] {src} -> {dst} dropped, {} mappings already open", options.max_mappings);
+                self.stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Entry::Vacant(entry) => match Mapping::open(src, dst, listen, start, &self.stats).await {
+                Ok(mapping) => {
+                    self.stats.mappings.fetch_add(1, Ordering::Relaxed);
+                    entry.insert(mapping)
+                }
+                Err(err) => {
+                    log::debug!("[UDP] {src} -> {dst} {err}");
+                    self.stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            },
+        };
+        log::trace!("[UDP] {src} -> {dst} incoming packet size {}", pkt.len());
+        mapping.touch(start);
+        if let Err(err) = mapping.socket.send(pkt).await {
+            log::debug!("[UDP] {src} -> {dst} {err}");
+            self.stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Closes the mappings without traffic for `timeout` at `now`, in milliseconds since the start of the relay.
+    fn sweep(&mut self, now: u64, timeout: Duration) {
+        let timeout = timeout.as_millis() as u64;
+        let stats = &self.stats;
+        self.mappings.retain(|(src, dst), mapping| {
+            let active = now.saturating_sub(mapping.last_active.load(Ordering::Relaxed)) < timeout;
+            if !active {
+                log::trace!("[UDP] {src} -> {dst} mapping idle, closing");
+                stats.mappings.fetch_sub(1, Ordering::Relaxed);
+            }
+            active
+        });
+        let mappings = &self.mappings;
+        self.domains.retain(|(src, _), dst| mappings.contains_key(&(*src, *dst)));
+    }
+}
+
+impl Drop for NatTable {
+    fn drop(&mut self) {
+        self.stats.mappings.fetch_sub(self.mappings.len(), Ordering::Relaxed);
+    }
+}
+
+/// An entry of the NAT table of a relay: the outbound socket of one client source and destination pair,
+/// and the task forwarding what it receives back to the client.
+struct Mapping {
+    socket: Arc<UdpSocket>,
+    last_active: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+}
+
+impl Mapping {
+    async fn open(
+        client: SocketAddr,
+        dst: SocketAddr,
+        listen: &Arc<AssociatedUdpSocket>,
+        start: Instant,
+        stats: &Arc<UdpRelayStats>,
+    ) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified_of(dst.ip()), 0)).await?;
+        socket.connect(dst).await?;
+        let socket = Arc::new(socket);
+        let last_active = Arc::new(AtomicU64::new(elapsed_millis(start)));
+
+        let task = tokio::spawn({
+            let (socket, last_active, listen, stats) = (socket.clone(), last_active.clone(), listen.clone(), stats.clone());
+            async move {
+                let mut buf = vec![0; listen.get_max_packet_size()];
+                loop {
+                    let len = match socket.recv(&mut buf).await {
+                        Ok(len) => len,
+                        // Typically an ICMP error for an earlier datagram, which does not end the mapping.
+                        Err(err) => {
+                            log::trace!("[UDP] {client} <- {dst} {err}");
+                            continue;
+                        }
+                    };
+                    last_active.store(elapsed_millis(start), Ordering::Relaxed);
+                    log::trace!("[UDP] {client} <- {dst} feedback to incoming");
+                    match listen.send_to(&buf[..len], 0, dst.into(), client).await {
+                        Ok(_) => {
+                            stats.packets_to_client.fetch_add(1, Ordering::Relaxed);
+                            stats.bytes_to_client.fetch_add(len as u64, Ordering::Relaxed);
+                        }
+                        Err(err) => {
+                            log::debug!("[UDP] {client} <- {dst} {err}");
+                            stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Self { socket, last_active, task })
+    }
+
+    fn touch(&self, start: Instant) {
+        self.last_active.store(elapsed_millis(start), Ordering::Relaxed);
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn unspecified_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+fn elapsed_millis(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AssociatedUdpSocket, UdpRelayOptions, UdpRelayStats};
+    use crate::{
+        client,
+        protocol::{Address, StreamOperation, UdpHeader},
+        resolver::{Resolver, StaticResolver},
+        server::{
+            auth,
+            bandwidth::{Bandwidth, BandwidthLimiter},
+            ClientConnection, Server,
+        },
+    };
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{net::UdpSocket, task::JoinHandle};
+
+    /// Serves a single UDP ASSOCIATE request with `options` and returns its counters and the outcome of `relay()`.
+    async fn spawn_relay(options: UdpRelayOptions) -> (SocketAddr, Arc<UdpRelayStats>, JoinHandle<crate::Result<()>>) {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth)).await.unwrap();
+        spawn_relay_on(server, options)
+    }
+
+    fn spawn_relay_on(server: Server<()>, options: UdpRelayOptions) -> (SocketAddr, Arc<UdpRelayStats>, JoinHandle<crate::Result<()>>) {
+        let addr = server.local_addr().unwrap();
+        let stats = Arc::new(UdpRelayStats::default());
+        let task = tokio::spawn({
+            let stats = stats.clone();
+            async move {
+                let (conn, _) = server.accept().await?;
+                let conn = conn.authenticate().await?;
+                match conn.wait_request().await? {
+                    ClientConnection::UdpAssociate(associate, addr) => associate.relay(addr, &options, stats).await,
+                    _ => unreachable!(),
+                }
+            }
+        });
+        (addr, stats, task)
+    }
+
+    async fn spawn_echo(addr: &str) -> SocketAddr {
+        let echo = UdpSocket::bind(addr).await.unwrap();
+        let addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((len, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..len], from).await;
+            }
+        });
+        addr
+    }
+
+    async fn echo_through(udp: &client::SocksUdpClient, target: SocketAddr, payload: &[u8]) {
+        udp.send_to(payload, target).await.unwrap();
+        let mut buf = Vec::new();
+        let (_, from) = udp.recv_from(Duration::from_secs(5), &mut buf).await.unwrap();
+        assert_eq!(buf, payload);
+        assert_eq!(from, Address::from(target));
+    }
+
+    #[tokio::test]
+    async fn relay_maps_ipv4_and_ipv6_destinations() {
+        let echo_v4 = spawn_echo("127.0.0.1:0").await;
+        let echo_v6 = spawn_echo("[::1]:0").await;
+        let (proxy, stats, task) = spawn_relay(UdpRelayOptions::default()).await;
+
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        echo_through(&udp, echo_v4, b"hello").await;
+        echo_through(&udp, echo_v6, b"world!").await;
+        echo_through(&udp, echo_v4, b"again").await;
+
+        assert_eq!(stats.mappings(), 2);
+        assert_eq!(stats.packets_from_client(), 3);
+        assert_eq!(stats.bytes_from_client(), 16);
+        assert_eq!(stats.packets_to_client(), 3);
+        assert_eq!(stats.bytes_to_client(), 16);
+        assert_eq!(stats.packets_dropped(), 0);
+
+        // Closing the TCP connection ends the association and its mappings.
+        drop(udp);
+        task.await.unwrap().unwrap();
+        assert_eq!(stats.mappings(), 0);
+    }
+
+    #[tokio::test]
+    async fn relay_reassembles_and_fragments() {
+        let echo = spawn_echo("127.0.0.1:0").await;
+        let (proxy, stats, _task) = spawn_relay(UdpRelayOptions::default().with_fragment_mtu(Some(64))).await;
+
+        let mut udp = client::create_udp_client(proxy, None).await.unwrap();
+        udp.set_fragment_mtu(Some(48));
+        let payload = (0..200).collect::<Vec<u8>>();
+        echo_through(&udp, echo, &payload).await;
+        assert_eq!(stats.packets_from_client(), 1);
+        assert_eq!(stats.bytes_from_client(), 200);
+        assert_eq!(stats.bytes_to_client(), 200);
+    }
+
+    #[tokio::test]
+    async fn relay_closes_idle_mappings() {
+        let echo = spawn_echo("127.0.0.1:0").await;
+        let options = UdpRelayOptions::default().with_mapping_idle_timeout(Duration::from_millis(50));
+        let (proxy, stats, _task) = spawn_relay(options).await;
+
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        echo_through(&udp, echo, b"hello").await;
+        assert_eq!(stats.mappings(), 1);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(stats.mappings(), 0);
+
+        // A new datagram opens a new mapping.
+        echo_through(&udp, echo, b"hello").await;
+        assert_eq!(stats.mappings(), 1);
+    }
+
+    #[tokio::test]
+    async fn relay_drops_datagrams_from_other_sources() {
+        let echo = spawn_echo("127.0.0.1:0").await;
+        let (proxy, stats, _task) = spawn_relay(UdpRelayOptions::default()).await;
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        let Address::SocketAddress(relay) = udp.proxy_addr().clone() else {
+            panic!("relay on a domain")
+        };
+
+        // Another host than the client of the association.
+        let intruder = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let header = UdpHeader::new(0, Address::from(echo));
+        let mut pkt = Vec::with_capacity(header.len() + 5);
+        header.write_to_buf(&mut pkt);
+        pkt.extend_from_slice(b"hello");
+        intruder.send_to(&pkt, relay).await.unwrap();
+
+        echo_through(&udp, echo, b"hello").await;
+        assert_eq!(stats.packets_dropped(), 1);
+        assert_eq!(stats.packets_from_client(), 1);
+    }
+
+    #[tokio::test]
+    async fn reassembly_queues_are_capped() {
+        let socket = AssociatedUdpSocket::from((UdpSocket::bind("127.0.0.1:0").await.unwrap(), 1500));
+        socket.set_max_reassembly_queues(1);
+        let (a, b) = ("192.0.2.1:1".parse().unwrap(), "192.0.2.2:1".parse().unwrap());
+        let addr = Address::from(("example.com", 53));
+        let (head, tail) = (Bytes::from_static(b"hello "), Bytes::from_static(b"world"));
+
+        assert_eq!(socket.reassemble(a, 1, &addr, &head), None);
+        assert_eq!(socket.reassemble(b, 1, &addr, &head), None);
+        assert_eq!(socket.reassemble(b, 2 | UdpHeader::END_OF_SEQUENCE, &addr, &tail), None);
+        let whole = Some((addr.clone(), Bytes::from_static(b"hello world")));
+        assert_eq!(socket.reassemble(a, 2 | UdpHeader::END_OF_SEQUENCE, &addr, &tail), whole);
+        assert!(socket.reassemblers.lock().unwrap().is_empty());
+
+        // The completed queue made room for another source.
+        assert_eq!(socket.reassemble(b, 1, &addr, &head), None);
+        assert_eq!(socket.reassemble(b, 2 | UdpHeader::END_OF_SEQUENCE, &addr, &tail), whole);
+    }
+
+    #[tokio::test]
+    async fn relay_caps_mappings() {
+        let (echo_a, echo_b) = (spawn_echo("127.0.0.1:0").await, spawn_echo("127.0.0.1:0").await);
+        let (proxy, stats, _task) = spawn_relay(UdpRelayOptions::default().with_max_mappings(1)).await;
+
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        echo_through(&udp, echo_a, b"hello").await;
+        udp.send_to(b"world", echo_b).await.unwrap();
+        echo_through(&udp, echo_a, b"again").await;
+        assert_eq!(stats.mappings(), 1);
+        assert_eq!(stats.packets_dropped(), 1);
+        assert_eq!(stats.packets_to_client(), 2);
+    }
+
+    /// A resolver counting its lookups.
+    #[derive(Debug)]
+    struct CountingResolver(StaticResolver, AtomicUsize);
+
+    #[async_trait]
+    impl Resolver for CountingResolver {
+        async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.resolve(host, port).await
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_resolves_domains_once_per_mapping() {
+        let echo = spawn_echo("127.0.0.1:0").await;
+        let hosts = StaticResolver::from_iter([("echo.test", echo.ip())]);
+        let resolver = Arc::new(CountingResolver(hosts, AtomicUsize::new(0)));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth)).await.unwrap();
+        let (proxy, stats, _task) = spawn_relay_on(server.with_resolver(resolver.clone()), UdpRelayOptions::default());
+
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        let target = Address::from(("echo.test", echo.port()));
+        for payload in [&b"hello"[..], b"world", b"again"] {
+            udp.send_to(payload, target.clone()).await.unwrap();
+            let mut buf = Vec::new();
+            udp.recv_from(Duration::from_secs(5), &mut buf).await.unwrap();
+            assert_eq!(buf, payload);
+        }
+        assert_eq!(resolver.1.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.mappings(), 1);
+    }
+
+    /// Never answers for `stalled.test`.
+    #[derive(Debug)]
+    struct StallingResolver(StaticResolver);
+
+    #[async_trait]
+    impl Resolver for StallingResolver {
+        async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+            if host == "stalled.test" {
+                std::future::pending::<()>().await;
+            }
+            self.0.resolve(host, port).await
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_looks_up_domains_in_the_background() {
+        let echo = spawn_echo("127.0.0.1:0").await;
+        let resolver = Arc::new(StallingResolver(StaticResolver::from_iter([("echo.test", echo.ip())])));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth)).await.unwrap();
+        let (proxy, stats, _task) = spawn_relay_on(server.with_resolver(resolver), UdpRelayOptions::default());
+
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        udp.send_to(b"stalled", ("stalled.test", 80)).await.unwrap();
+        echo_through(&udp, echo, b"hello").await;
+        udp.send_to(b"world", ("echo.test", echo.port())).await.unwrap();
+        let mut buf = Vec::new();
+        udp.recv_from(Duration::from_secs(5), &mut buf).await.unwrap();
+        assert_eq!(buf, b"world");
+        // `echo.test` leads to the mapping of the echo server, the stalled lookup opened none.
+        assert_eq!(stats.mappings(), 1);
+    }
+
+    #[tokio::test]
+    async fn relay_releases_mappings_however_it_ends() {
+        let echo = spawn_echo("127.0.0.1:0").await;
+        let (proxy, stats, task) = spawn_relay(UdpRelayOptions::default()).await;
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        echo_through(&udp, echo, b"hello").await;
+        assert_eq!(stats.mappings(), 1);
+
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert_eq!(stats.mappings(), 0);
+    }
+
+    #[tokio::test]
+    async fn relay_charges_only_the_client_bandwidth() {
+        let echo = spawn_echo("127.0.0.1:0").await;
+        let limiter = Arc::new(BandwidthLimiter::new().with_global(Bandwidth::new(Some(1000), None)));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth)).await.unwrap();
+        let (proxy, stats, _task) = spawn_relay_on(server.with_bandwidth_limiter(limiter), UdpRelayOptions::default());
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        let Address::SocketAddress(relay) = udp.proxy_addr().clone() else {
+            panic!("relay on a domain")
+        };
+
+        let intruder = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let header = UdpHeader::new(0, Address::from(echo));
+        let mut pkt = Vec::with_capacity(header.len() + 1000);
+        header.write_to_buf(&mut pkt);
+        pkt.resize(header.len() + 1000, 0);
+        for _ in 0..5 {
+            intruder.send_to(&pkt, relay).await.unwrap();
+        }
+
+        echo_through(&udp, echo, b"hello").await;
+        assert_eq!(stats.packets_dropped(), 5);
+        assert_eq!(stats.packets_from_client(), 1);
+    }
+}
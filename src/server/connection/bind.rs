@@ -1,16 +1,26 @@
-use crate::protocol::{Address, AsyncStreamOperation, Reply, Response};
+use crate::{
+    protocol::{Address, Reply, Version},
+    resolver::Resolver,
+    server::{
+        bandwidth::{Throttle, Throttled},
+        connection::{ConnContext, StreamAddrs},
+        ssrf::SsrfGuard,
+        timeout::{self, Timeouts},
+    },
+};
 use std::{
     marker::PhantomData,
     net::SocketAddr,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
     time::Duration,
 };
 use tokio::{
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::{
         tcp::{ReadHalf, WriteHalf},
-        TcpStream,
+        TcpListener, TcpStream,
     },
 };
 
@@ -23,60 +33,108 @@ use tokio::{
 /// you will get a `Bind<Ready>`, which can be used as a regular async TCP stream.
 ///
 /// A `Bind<S>` can be converted to a regular tokio [`TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) by using the `From` trait.
+///
+/// `O` is the output of the [`AuthExecutor`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html)
+/// that authenticated the client, see [`auth()`](#method.auth). `T` is the transport stream, see
+/// [`IncomingConnection`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.IncomingConnection.html).
 #[derive(Debug)]
-pub struct Bind<S> {
-    stream: TcpStream,
+pub struct Bind<S, O = (), T = TcpStream> {
+    stream: T,
+    addrs: StreamAddrs,
+    version: Version,
+    ctx: ConnContext,
+    auth: O,
     _state: PhantomData<S>,
 }
 
-/// Marker type indicating that the connection needs its first reply.
-#[derive(Debug, Default)]
-pub struct NeedFirstReply;
-
-/// Marker type indicating that the connection needs its second reply.
-#[derive(Debug, Default)]
-pub struct NeedSecondReply;
-
-/// Marker type indicating that the connection is ready to use as a regular TCP stream.
-#[derive(Debug, Default)]
-pub struct Ready;
-
-impl Bind<NeedFirstReply> {
+impl<S, O, T> Bind<S, O, T> {
     #[inline]
-    pub(super) fn new(stream: TcpStream) -> Self {
+    fn with_state(stream: T, addrs: StreamAddrs, version: Version, ctx: ConnContext, auth: O) -> Self {
         Self {
             stream,
+            addrs,
+            version,
+            ctx,
+            auth,
             _state: PhantomData,
         }
     }
 
-    /// Reply to the SOCKS5 client with the given reply and address.
-    ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Bind<NeedSecondReply>> {
-        let resp = Response::new(reply, addr);
-        resp.write_to_async_stream(&mut self.stream).await?;
-        Ok(Bind::<NeedSecondReply>::new(self.stream))
+    /// Returns what the client was authenticated as, e.g. its user name.
+    #[inline]
+    pub fn auth(&self) -> &O {
+        &self.auth
     }
 
-    /// Causes the other peer to receive a read of length 0, indicating that no more data will be sent. This only closes the stream in one direction.
+    /// Returns the SOCKS version spoken by the client.
     #[inline]
-    pub async fn shutdown(&mut self) -> std::io::Result<()> {
-        self.stream.shutdown().await
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the resolver the server was configured with, for looking up the requested address.
+    #[inline]
+    pub fn resolver(&self) -> &Arc<dyn Resolver> {
+        &self.ctx.resolver
+    }
+
+    /// Returns the SSRF guard the server was configured with, if any.
+    #[inline]
+    pub fn ssrf_guard(&self) -> Option<&Arc<SsrfGuard>> {
+        self.ctx.ssrf_guard.as_ref()
+    }
+
+    /// Returns the deadlines of the connection, see the
+    /// [`timeout`](https://docs.rs/socks5-impl/latest/socks5_impl/server/timeout/index.html) module.
+    #[inline]
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.ctx.timeouts
+    }
+
+    /// Returns the share of the client in the bandwidth limits of the server, if it has any.
+    #[inline]
+    pub fn throttle(&self) -> Option<&Throttle> {
+        self.ctx.throttle.as_ref()
+    }
+
+    /// Looks up `addr` with the [`resolver()`](#method.resolver), keeping only the addresses the [`ssrf_guard()`](#method.ssrf_guard)
+    /// allows. Fails with [`PermissionDenied`](std::io::ErrorKind::PermissionDenied) if the guard blocks all of them.
+    ///
+    /// The returned future does not borrow the connection, so it can be awaited while the connection is used elsewhere.
+    #[inline]
+    pub fn resolve(&self, addr: &Address) -> impl std::future::Future<Output = std::io::Result<Vec<SocketAddr>>> + Send + 'static {
+        let (ctx, addr) = (self.ctx.clone(), addr.clone());
+        async move { ctx.resolve(&addr).await }
     }
 
     /// Returns the local address that this stream is bound to.
     #[inline]
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.local_addr()
+        self.addrs.local()
     }
 
     /// Returns the remote address that this stream is connected to.
     #[inline]
     pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.peer_addr()
+        self.addrs.peer()
     }
 
+    /// Returns the transport stream.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+impl<S, O, T: AsyncWrite + Unpin> Bind<S, O, T> {
+    /// Causes the other peer to receive a read of length 0, indicating that no more data will be sent. This only closes the stream in one direction.
+    #[inline]
+    pub async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+impl<S, O> Bind<S, O> {
     /// Reads the linger duration for this socket by getting the `SO_LINGER` option.
     ///
     /// For more information about this option, see
@@ -94,6 +152,7 @@ impl Bind<NeedFirstReply> {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
         self.stream.set_linger(dur)
     }
@@ -132,119 +191,201 @@ impl Bind<NeedFirstReply> {
     }
 }
 
-impl Bind<NeedSecondReply> {
-    #[inline]
-    fn new(stream: TcpStream) -> Self {
-        Self {
-            stream,
-            _state: PhantomData,
-        }
-    }
+/// Marker type indicating that the connection needs its first reply.
+#[derive(Debug, Default)]
+pub struct NeedFirstReply;
 
-    /// Reply to the SOCKS5 client with the given reply and address.
-    ///
-    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> Result<Bind<Ready>, (std::io::Error, TcpStream)> {
-        let resp = Response::new(reply, addr);
+/// Marker type indicating that the connection needs its second reply.
+#[derive(Debug, Default)]
+pub struct NeedSecondReply;
 
-        if let Err(err) = resp.write_to_async_stream(&mut self.stream).await {
-            return Err((err, self.stream));
-        }
+/// Marker type indicating that the connection is ready to use as a regular TCP stream.
+#[derive(Debug, Default)]
+pub struct Ready;
 
-        Ok(Bind::<Ready>::new(self.stream))
-    }
+/// Settings of [`Bind::relay()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Bind.html#method.relay).
+#[derive(Clone, Debug)]
+pub struct BindOptions {
+    accept_timeout: Option<Duration>,
+    check_peer_ip: bool,
+}
 
-    /// Causes the other peer to receive a read of length 0, indicating that no more data will be sent. This only closes the stream in one direction.
+impl BindOptions {
+    /// How long to wait for the inbound peer after the first reply, `None` to wait until the client gives up.
+    /// Defaults to 60 seconds.
     #[inline]
-    pub async fn shutdown(&mut self) -> std::io::Result<()> {
-        self.stream.shutdown().await
+    pub fn with_accept_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.accept_timeout = timeout;
+        self
     }
 
-    /// Returns the local address that this stream is bound to.
+    /// Whether to only accept a peer whose IP is the one of `DST.ADDR`, as suggested by RFC 1928. A domain `DST.ADDR`
+    /// is looked up with the resolver of the connection, and an unspecified IP such as `0.0.0.0` matches any peer.
+    /// Peers that do not match are closed while waiting goes on. Defaults to `true`.
     #[inline]
-    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.local_addr()
+    pub fn with_peer_ip_check(mut self, check: bool) -> Self {
+        self.check_peer_ip = check;
+        self
     }
 
-    /// Returns the remote address that this stream is connected to.
+    /// Returns the accept timeout.
     #[inline]
-    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.peer_addr()
+    pub fn accept_timeout(&self) -> Option<Duration> {
+        self.accept_timeout
     }
 
-    /// Reads the linger duration for this socket by getting the `SO_LINGER` option.
-    ///
-    /// For more information about this option, see
-    /// [set_linger](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Bind.html#method.set_linger).
+    /// Returns whether the IP of the peer is checked against `DST.ADDR`.
     #[inline]
-    pub fn linger(&self) -> std::io::Result<Option<Duration>> {
-        self.stream.linger()
+    pub fn peer_ip_check(&self) -> bool {
+        self.check_peer_ip
     }
+}
 
-    /// Sets the linger duration of this socket by setting the `SO_LINGER` option.
-    ///
-    /// This option controls the action taken when a stream has unsent messages and the stream is closed.
-    /// If `SO_LINGER` is set, the system shall block the process until it can transmit the data or until the time expires.
-    ///
-    /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
-    /// that allows the process to continue as quickly as possible.
-    #[inline]
-    pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
-        self.stream.set_linger(dur)
+impl Default for BindOptions {
+    fn default() -> Self {
+        Self {
+            accept_timeout: Some(Duration::from_secs(60)),
+            check_peer_ip: true,
+        }
     }
+}
 
-    /// Gets the value of the `TCP_NODELAY` option on this socket.
-    ///
-    /// For more information about this option, see
-    /// [set_nodelay](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Bind.html#method.set_nodelay).
+impl<O, T> Bind<NeedFirstReply, O, T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
     #[inline]
-    pub fn nodelay(&self) -> std::io::Result<bool> {
-        self.stream.nodelay()
+    pub(super) fn new(stream: T, addrs: StreamAddrs, version: Version, ctx: ConnContext, auth: O) -> Self {
+        Self::with_state(stream, addrs, version, ctx, auth)
     }
 
-    /// Sets the value of the `TCP_NODELAY` option on this socket.
+    /// Reply to the SOCKS5 client with the given reply and address.
     ///
-    /// If set, this option disables the Nagle algorithm. This means that segments are always sent as soon as possible,
-    /// even if there is only a small amount of data. When not set, data is buffered until there is a sufficient amount to send out,
-    /// thereby avoiding the frequent sending of small packets.
-    pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
-        self.stream.set_nodelay(nodelay)
+    /// The reply is encoded in the SOCKS version spoken by the client.
+    ///
+    /// If encountered an error while writing the reply, the error alongside the original `TcpStream` is returned.
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Bind<NeedSecondReply, O, T>> {
+        super::write_reply(&mut self.stream, self.version, reply, addr).await?;
+        Ok(Bind::with_state(self.stream, self.addrs, self.version, self.ctx, self.auth))
     }
 
-    /// Gets the value of the `IP_TTL` option for this socket.
+    /// Serves the request for `addr`, the `DST.ADDR` of the client, from start to end.
     ///
-    /// For more information about this option, see
-    /// [set_ttl](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Bind.html#method.set_ttl).
-    pub fn ttl(&self) -> std::io::Result<u32> {
-        self.stream.ttl()
+    /// Opens a listener on the IP the client reached the server at and sends it in the first reply, accepts one
+    /// inbound peer, sends its address in the second reply, then relays traffic in both directions until either side closes
+    /// or a relay deadline of the connection expires, see [`timeouts()`](#method.timeouts).
+    /// Returns early without an error if the client closes the connection while the peer is awaited.
+    ///
+    /// If the connection has an SSRF guard, `addr` must resolve to an address it allows unless it is unspecified, or the request is
+    /// answered with [`Reply::ConnectionNotAllowed`].
+    ///
+    /// A failure to look up `addr` or to open the listener is replied to in the first reply, and an accept timeout
+    /// with [`Reply::TtlExpired`] in the second one. The error is returned in both cases.
+    pub async fn relay(self, addr: Address, options: &BindOptions) -> crate::Result<()> {
+        let any_peer = matches!(addr, Address::SocketAddress(addr) if addr.ip().is_unspecified());
+        let allowed = if !any_peer && (options.check_peer_ip || self.ctx.ssrf_guard.is_some()) {
+            match self.ctx.resolve(&addr).await {
+                Ok(addrs) => Some(addrs.into_iter().map(|addr| addr.ip()).collect::<Vec<_>>()),
+                Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                    return Err(self.fail(Reply::ConnectionNotAllowed, err).await);
+                }
+                Err(err) => return Err(self.fail(Reply::HostUnreachable, err).await),
+            }
+        } else {
+            None
+        };
+        let allowed = allowed.filter(|ips| options.check_peer_ip && !ips.iter().any(|ip| ip.is_unspecified()));
+
+        let listen_addr = match self.addrs.local() {
+            Ok(addr) => SocketAddr::new(addr.ip(), 0),
+            Err(err) => return Err(self.fail(Reply::GeneralFailure, err).await),
+        };
+        let listener = match TcpListener::bind(listen_addr).await {
+            Ok(listener) => listener,
+            Err(err) => return Err(self.fail(Reply::GeneralFailure, err).await),
+        };
+        let mut conn = self.reply(Reply::Succeeded, Address::from(listener.local_addr()?)).await?;
+
+        let accept = async {
+            loop {
+                let (peer, peer_addr) = listener.accept().await?;
+                match &allowed {
+                    Some(ips) if !ips.contains(&peer_addr.ip()) => log::debug!("BIND {listen_addr}: refusing peer {peer_addr}"),
+                    _ => return Ok::<_, std::io::Error>((peer, peer_addr)),
+                }
+            }
+        };
+        let accept = async {
+            match options.accept_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, accept).await.unwrap_or_else(|_| {
+                    let msg = format!("no peer connected within {timeout:?}");
+                    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, msg))
+                }),
+                None => accept.await,
+            }
+        };
+
+        let res = tokio::select! {
+            res = accept => res,
+            _ = closed(&mut conn.stream) => return Ok(()),
+        };
+        let (mut peer, peer_addr) = match res {
+            Ok(peer) => peer,
+            Err(err) => {
+                let reply = if err.kind() == std::io::ErrorKind::TimedOut {
+                    Reply::TtlExpired
+                } else {
+                    Reply::GeneralFailure
+                };
+                super::write_reply(&mut conn.stream, conn.version, reply, Address::unspecified()).await?;
+                conn.stream.shutdown().await?;
+                return Err(err.into());
+            }
+        };
+
+        let conn = conn
+            .reply(Reply::Succeeded, Address::from(peer_addr))
+            .await
+            .map_err(|(err, _)| err)?;
+        let (throttle, timeouts) = (conn.throttle().cloned(), *conn.timeouts());
+        timeout::copy_bidirectional(&mut peer, &mut Throttled::new(conn, throttle), &timeouts).await?;
+        Ok(())
     }
 
-    /// Sets the value for the `IP_TTL` option on this socket.
-    ///
-    /// This value sets the time-to-live field that is used in every packet sent from this socket.
-    pub fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
-        self.stream.set_ttl(ttl)
+    async fn fail(self, reply: Reply, err: std::io::Error) -> crate::Error {
+        match self.reply(reply, Address::unspecified()).await {
+            Ok(mut conn) => {
+                let _ = conn.shutdown().await;
+                err.into()
+            }
+            Err(err) => err.into(),
+        }
     }
 }
 
-impl Bind<Ready> {
-    #[inline]
-    fn new(stream: TcpStream) -> Self {
-        Self {
-            stream,
-            _state: PhantomData,
+impl<O, T: AsyncWrite + Unpin + Send> Bind<NeedSecondReply, O, T> {
+    /// Reply to the SOCKS5 client with the given reply and address.
+    ///
+    /// If encountered an error while writing the reply, the error alongside the original transport stream is returned.
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> Result<Bind<Ready, O, T>, (std::io::Error, T)> {
+        if let Err(err) = super::write_reply(&mut self.stream, self.version, reply, addr).await {
+            return Err((err, self.stream));
         }
+
+        Ok(Bind::with_state(self.stream, self.addrs, self.version, self.ctx, self.auth))
     }
+}
 
+impl<O> Bind<Ready, O> {
     /// Split the connection into a read and a write half.
     #[inline]
-    pub fn split(&mut self) -> (ReadHalf, WriteHalf) {
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
         self.stream.split()
     }
 }
 
-impl std::ops::Deref for Bind<Ready> {
-    type Target = TcpStream;
+impl<O, T> std::ops::Deref for Bind<Ready, O, T> {
+    type Target = T;
 
     #[inline]
     fn deref(&self) -> &Self::Target {
@@ -252,21 +393,21 @@ impl std::ops::Deref for Bind<Ready> {
     }
 }
 
-impl std::ops::DerefMut for Bind<Ready> {
+impl<O, T> std::ops::DerefMut for Bind<Ready, O, T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.stream
     }
 }
 
-impl AsyncRead for Bind<Ready> {
+impl<O, T: AsyncRead + Unpin> AsyncRead for Bind<Ready, O, T> {
     #[inline]
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
         Pin::new(&mut self.stream).poll_read(cx, buf)
     }
 }
 
-impl AsyncWrite for Bind<Ready> {
+impl<O, T: AsyncWrite + Unpin> AsyncWrite for Bind<Ready, O, T> {
     #[inline]
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
         Pin::new(&mut self.stream).poll_write(cx, buf)
@@ -283,9 +424,118 @@ impl AsyncWrite for Bind<Ready> {
     }
 }
 
-impl<S> From<Bind<S>> for TcpStream {
+/// Resolves once the client has closed the connection, and never if it sends anything before that.
+///
+/// Whatever the client sends is discarded, it has no business sending anything before the second reply.
+async fn closed<T: AsyncRead + Unpin>(stream: &mut T) {
+    let mut buf = [0; 1];
+    if let Ok(1..) = stream.read(&mut buf).await {
+        std::future::pending::<()>().await;
+    }
+}
+
+// The output of the authentication is never pinned, only the stream is polled.
+impl<S, O, T: Unpin> Unpin for Bind<S, O, T> {}
+
+impl<S, O> From<Bind<S, O>> for TcpStream {
     #[inline]
-    fn from(conn: Bind<S>) -> Self {
+    fn from(conn: Bind<S, O>) -> Self {
         conn.stream
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::BindOptions;
+    use crate::{
+        client,
+        protocol::{Address, Reply},
+        server::{auth, ClientConnection, Server},
+    };
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, BufStream},
+        net::{TcpSocket, TcpStream},
+        task::JoinHandle,
+    };
+
+    /// Serves a single BIND request with `options` and returns the outcome of `relay()`.
+    async fn spawn_bind_server(options: BindOptions) -> (SocketAddr, JoinHandle<crate::Result<()>>) {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), std::sync::Arc::new(auth::NoAuth))
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let (conn, _) = server.accept().await?;
+            let conn = conn.authenticate().await?;
+            match conn.wait_request().await? {
+                ClientConnection::Bind(bind, addr) => bind.relay(addr, &options).await,
+                _ => unreachable!(),
+            }
+        });
+        (addr, task)
+    }
+
+    async fn connect_from(ip: Ipv4Addr, to: SocketAddr) -> TcpStream {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::from((ip, 0))).unwrap();
+        socket.connect(to).await.unwrap()
+    }
+
+    fn bound_addr(addr: &Address) -> SocketAddr {
+        match addr {
+            Address::SocketAddress(addr) => *addr,
+            Address::DomainAddress(..) => panic!("bound to a domain"),
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_checks_the_peer_ip() {
+        let (proxy, task) = spawn_bind_server(BindOptions::default()).await;
+        let stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        let expected = Ipv4Addr::new(127, 0, 0, 2);
+        let listener = client::SocksListener::bind(stream, (expected, 0), None).await.unwrap();
+        let bound = bound_addr(listener.proxy_addr());
+
+        // A peer from another IP is closed without being relayed.
+        let mut intruder = connect_from(Ipv4Addr::LOCALHOST, bound).await;
+        assert_eq!(intruder.read(&mut [0; 1]).await.unwrap(), 0);
+
+        let mut peer = connect_from(expected, bound).await;
+        let (mut stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr, Address::from(peer.local_addr().unwrap()));
+        peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        drop(stream);
+        drop(peer);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn relay_times_out() {
+        let options = BindOptions::default().with_accept_timeout(Some(Duration::from_millis(50)));
+        let (proxy, task) = spawn_bind_server(options).await;
+        let stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        let listener = client::SocksListener::bind(stream, ("0.0.0.0", 0), None).await.unwrap();
+
+        let err = listener.accept().await.unwrap_err();
+        assert_eq!(err.to_string(), Reply::TtlExpired.to_string());
+        let err = task.await.unwrap().unwrap_err();
+        assert!(matches!(err, crate::Error::Io(err) if err.kind() == std::io::ErrorKind::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn relay_ends_when_the_client_leaves() {
+        let (proxy, task) = spawn_bind_server(BindOptions::default().with_accept_timeout(None)).await;
+        let stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        let listener = client::SocksListener::bind(stream, ("0.0.0.0", 0), None).await.unwrap();
+        drop(listener);
+        task.await.unwrap().unwrap();
+    }
+}
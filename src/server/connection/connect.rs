@@ -1,8 +1,18 @@
-use crate::protocol::{Address, AsyncStreamOperation, Reply, Response};
+use crate::{
+    protocol::{Address, Reply, Version},
+    resolver::Resolver,
+    server::{
+        bandwidth::Throttle,
+        connection::{ConnContext, StreamAddrs},
+        ssrf::SsrfGuard,
+        timeout::Timeouts,
+    },
+};
 use std::{
     io::IoSlice,
     net::SocketAddr,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 use tokio::{
@@ -16,34 +26,101 @@ use tokio::{
 /// Socks5 connection type `Connect`
 ///
 /// This connection can be used as a regular async TCP stream after replying the client.
+///
+/// `O` is the output of the [`AuthExecutor`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html)
+/// that authenticated the client, see [`auth()`](#method.auth). `T` is the transport stream, see
+/// [`IncomingConnection`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.IncomingConnection.html).
 #[derive(Debug)]
-pub struct Connect<S> {
-    stream: TcpStream,
+pub struct Connect<S, O = (), T = TcpStream> {
+    stream: T,
+    addrs: StreamAddrs,
+    version: Version,
+    ctx: ConnContext,
+    auth: O,
     _state: S,
 }
 
-impl<S: Default> Connect<S> {
+impl<S: Default, O, T> Connect<S, O, T> {
     #[inline]
-    pub(super) fn new(stream: TcpStream) -> Self {
+    pub(super) fn new(stream: T, addrs: StreamAddrs, version: Version, ctx: ConnContext, auth: O) -> Self {
         Self {
             stream,
+            addrs,
+            version,
+            ctx,
+            auth,
             _state: S::default(),
         }
     }
 
+    /// Returns what the client was authenticated as, e.g. its user name.
+    #[inline]
+    pub fn auth(&self) -> &O {
+        &self.auth
+    }
+
+    /// Returns the SOCKS version spoken by the client.
+    #[inline]
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the resolver the server was configured with, for looking up the requested address.
+    #[inline]
+    pub fn resolver(&self) -> &Arc<dyn Resolver> {
+        &self.ctx.resolver
+    }
+
+    /// Returns the SSRF guard the server was configured with, if any.
+    #[inline]
+    pub fn ssrf_guard(&self) -> Option<&Arc<SsrfGuard>> {
+        self.ctx.ssrf_guard.as_ref()
+    }
+
+    /// Returns the deadlines of the connection, see the
+    /// [`timeout`](https://docs.rs/socks5-impl/latest/socks5_impl/server/timeout/index.html) module.
+    #[inline]
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.ctx.timeouts
+    }
+
+    /// Returns the share of the client in the bandwidth limits of the server, if it has any.
+    #[inline]
+    pub fn throttle(&self) -> Option<&Throttle> {
+        self.ctx.throttle.as_ref()
+    }
+
+    /// Looks up `addr` with the [`resolver()`](#method.resolver), keeping only the addresses the [`ssrf_guard()`](#method.ssrf_guard)
+    /// allows. Fails with [`PermissionDenied`](std::io::ErrorKind::PermissionDenied) if the guard blocks all of them.
+    ///
+    /// The returned future does not borrow the connection, so it can be awaited while the connection is used elsewhere.
+    #[inline]
+    pub fn resolve(&self, addr: &Address) -> impl std::future::Future<Output = std::io::Result<Vec<SocketAddr>>> + Send + 'static {
+        let (ctx, addr) = (self.ctx.clone(), addr.clone());
+        async move { ctx.resolve(&addr).await }
+    }
+
     /// Returns the local address that this stream is bound to.
     #[inline]
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.local_addr()
+        self.addrs.local()
     }
 
     /// Returns the remote address that this stream is connected to.
     #[inline]
     pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.peer_addr()
+        self.addrs.peer()
     }
 
-    /// Shutdown the TCP stream.
+    /// Returns the transport stream.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+impl<S: Default, O, T: AsyncWrite + Unpin> Connect<S, O, T> {
+    /// Shutdown the transport stream.
     #[inline]
     pub async fn shutdown(&mut self) -> std::io::Result<()> {
         self.stream.shutdown().await
@@ -56,20 +133,21 @@ pub struct NeedReply;
 #[derive(Debug, Default)]
 pub struct Ready;
 
-impl Connect<NeedReply> {
+impl<O, T: AsyncWrite + Unpin + Send> Connect<NeedReply, O, T> {
     /// Reply to the client.
+    ///
+    /// The reply is encoded in the SOCKS version spoken by the client.
     #[inline]
-    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Connect<Ready>> {
-        let resp = Response::new(reply, addr);
-        resp.write_to_async_stream(&mut self.stream).await?;
-        Ok(Connect::<Ready>::new(self.stream))
+    pub async fn reply(mut self, reply: Reply, addr: Address) -> std::io::Result<Connect<Ready, O, T>> {
+        super::write_reply(&mut self.stream, self.version, reply, addr).await?;
+        Ok(Connect::new(self.stream, self.addrs, self.version, self.ctx, self.auth))
     }
 }
 
-impl Connect<Ready> {
+impl<O> Connect<Ready, O> {
     /// Returns the read/write half of the stream.
     #[inline]
-    pub fn split(&mut self) -> (ReadHalf, WriteHalf) {
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
         self.stream.split()
     }
 
@@ -80,8 +158,8 @@ impl Connect<Ready> {
     }
 }
 
-impl std::ops::Deref for Connect<Ready> {
-    type Target = TcpStream;
+impl<O, T> std::ops::Deref for Connect<Ready, O, T> {
+    type Target = T;
 
     #[inline]
     fn deref(&self) -> &Self::Target {
@@ -89,21 +167,21 @@ impl std::ops::Deref for Connect<Ready> {
     }
 }
 
-impl std::ops::DerefMut for Connect<Ready> {
+impl<O, T> std::ops::DerefMut for Connect<Ready, O, T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.stream
     }
 }
 
-impl AsyncRead for Connect<Ready> {
+impl<O, T: AsyncRead + Unpin> AsyncRead for Connect<Ready, O, T> {
     #[inline]
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
         Pin::new(&mut self.stream).poll_read(cx, buf)
     }
 }
 
-impl AsyncWrite for Connect<Ready> {
+impl<O, T: AsyncWrite + Unpin> AsyncWrite for Connect<Ready, O, T> {
     #[inline]
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
         Pin::new(&mut self.stream).poll_write(cx, buf)
@@ -130,9 +208,12 @@ impl AsyncWrite for Connect<Ready> {
     }
 }
 
-impl<S> From<Connect<S>> for TcpStream {
+// The output of the authentication is never pinned, only the stream is polled.
+impl<S, O, T: Unpin> Unpin for Connect<S, O, T> {}
+
+impl<S, O> From<Connect<S, O>> for TcpStream {
     #[inline]
-    fn from(conn: Connect<S>) -> Self {
+    fn from(conn: Connect<S, O>) -> Self {
         conn.stream
     }
 }
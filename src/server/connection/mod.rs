@@ -1,46 +1,207 @@
 use self::{associate::UdpAssociate, bind::Bind, connect::Connect};
 use crate::{
-    protocol::{self, handshake, Address, AsyncStreamOperation, AuthMethod, Command},
-    server::AuthAdaptor,
+    protocol::{self, handshake, socks4, Address, AsyncStreamOperation, AuthMethod, Command, Reply, StreamOperation, Version},
+    resolver::{Resolver, TokioResolver},
+    server::{
+        bandwidth::{BandwidthLimiter, Throttle},
+        session::{Session, SessionLimiter},
+        ssrf::SsrfGuard,
+        timeout::{Deadline, Phase, Timeouts},
+        AuthAdaptor, AuthUser,
+    },
+};
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
 };
-use std::{net::SocketAddr, time::Duration};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
 
 pub mod associate;
 pub mod bind;
 pub mod connect;
 
+/// The limiters the server holds its clients to, with the way to name the user from the output of the authentication.
+pub(crate) struct Limits<O> {
+    user_name: fn(&O) -> Option<&str>,
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    sessions: Option<Arc<SessionLimiter>>,
+}
+
+impl<O> Limits<O> {
+    pub(crate) fn bandwidth(&self) -> Option<&Arc<BandwidthLimiter>> {
+        self.bandwidth.as_ref()
+    }
+
+    pub(crate) fn sessions(&self) -> Option<&Arc<SessionLimiter>> {
+        self.sessions.as_ref()
+    }
+}
+
+impl<O: AuthUser> Limits<O> {
+    pub(crate) fn with_bandwidth(mut self, limiter: Arc<BandwidthLimiter>) -> Self {
+        self.user_name = O::user_name;
+        self.bandwidth = Some(limiter);
+        self
+    }
+
+    pub(crate) fn with_sessions(mut self, limiter: Arc<SessionLimiter>) -> Self {
+        self.user_name = O::user_name;
+        self.sessions = Some(limiter);
+        self
+    }
+}
+
+impl<O> Clone for Limits<O> {
+    fn clone(&self) -> Self {
+        Self {
+            user_name: self.user_name,
+            bandwidth: self.bandwidth.clone(),
+            sessions: self.sessions.clone(),
+        }
+    }
+}
+
+impl<O> Default for Limits<O> {
+    fn default() -> Self {
+        Self {
+            user_name: |_| None,
+            bandwidth: None,
+            sessions: None,
+        }
+    }
+}
+
 /// An incoming connection. This may not be a valid socks5 connection. You need to call [`handshake()`](#method.handshake)
 /// to perform the socks5 handshake. It will be converted to a proper socks5 connection after the handshake succeeds.
-pub struct IncomingConnection<O> {
-    stream: TcpStream,
+///
+/// `T` is the transport stream, a [`TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) for connections
+/// accepted by the [`Server`](https://docs.rs/socks5-impl/latest/socks5_impl/server/struct.Server.html). Any other
+/// `AsyncRead + AsyncWrite` stream, such as a TLS stream, a Unix socket or an in-memory pipe, can be served by wrapping it
+/// with [`new()`](#method.new). The socket options of the transport are only offered for TCP streams.
+pub struct IncomingConnection<O, T = TcpStream> {
+    stream: T,
+    addrs: StreamAddrs,
     auth: AuthAdaptor<O>,
+    ctx: ConnContext,
+    limits: Limits<O>,
 }
 
-impl<O: 'static> IncomingConnection<O> {
+impl<O, T> IncomingConnection<O, T> {
+    /// Wraps a transport stream to be authenticated with `auth`.
+    ///
+    /// The connection has no socket addresses, see [`with_local_addr()`](#method.with_local_addr) and
+    /// [`with_peer_addr()`](#method.with_peer_addr), and looks up domain addresses with the
+    /// [`TokioResolver`](https://docs.rs/socks5-impl/latest/socks5_impl/resolver/struct.TokioResolver.html).
+    #[inline]
+    pub fn new(stream: T, auth: AuthAdaptor<O>) -> Self {
+        let addrs = StreamAddrs::default();
+        let ctx = ConnContext::default();
+        IncomingConnection {
+            stream,
+            addrs,
+            auth,
+            ctx,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Replaces the resolver handed on to the request of the client.
+    #[inline]
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.ctx.resolver = resolver;
+        self
+    }
+
+    /// Sets the [`SsrfGuard`](https://docs.rs/socks5-impl/latest/socks5_impl/server/ssrf/struct.SsrfGuard.html) the
+    /// addresses of the request of the client are checked with.
+    #[inline]
+    pub fn with_ssrf_guard(mut self, guard: Arc<SsrfGuard>) -> Self {
+        self.ctx.ssrf_guard = Some(guard);
+        self
+    }
+
+    /// Holds the client to the limits of `limiter` once authenticated, as the user named by the output of the authentication
+    /// and from the IP of its [`peer_addr()`](#method.peer_addr), see
+    /// [`BandwidthLimiter::throttle()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/bandwidth/struct.BandwidthLimiter.html#method.throttle).
     #[inline]
-    pub(crate) fn new(stream: TcpStream, auth: AuthAdaptor<O>) -> Self {
-        IncomingConnection { stream, auth }
+    pub fn with_bandwidth_limiter(mut self, limiter: Arc<BandwidthLimiter>) -> Self
+    where
+        O: AuthUser,
+    {
+        self.limits = self.limits.with_bandwidth(limiter);
+        self
+    }
+
+    /// Sets the deadlines of the handshake, the request and the relay of the connection, see the
+    /// [`timeout`](https://docs.rs/socks5-impl/latest/socks5_impl/server/timeout/index.html) module.
+    #[inline]
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.ctx.timeouts = timeouts;
+        self
+    }
+
+    /// Counts the client against the limits of `limiter` from the start of the handshake, as a session from the IP of its
+    /// [`peer_addr()`](#method.peer_addr) and, once authenticated, of the user named by the output of the authentication.
+    /// See the [`session`](https://docs.rs/socks5-impl/latest/socks5_impl/server/session/index.html) module for how clients
+    /// over a limit are turned down.
+    #[inline]
+    pub fn with_session_limiter(mut self, limiter: Arc<SessionLimiter>) -> Self
+    where
+        O: AuthUser,
+    {
+        self.limits = self.limits.with_sessions(limiter);
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_limits(mut self, limits: Limits<O>) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the address the client reached the server at.
+    ///
+    /// The built-in BIND and UDP ASSOCIATE relays open their sockets on its IP.
+    #[inline]
+    pub fn with_local_addr(mut self, addr: SocketAddr) -> Self {
+        self.addrs.local = Some(addr);
+        self
+    }
+
+    /// Sets the address of the client.
+    ///
+    /// It is seen by [`AuthExecutor::select_method`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html#method.select_method),
+    /// which sees `0.0.0.0:0` for a connection without one.
+    #[inline]
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.addrs.peer = Some(addr);
+        self
     }
 
     /// Returns the local address that this stream is bound to.
     #[inline]
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.local_addr()
+        self.addrs.local()
     }
 
     /// Returns the remote address that this stream is connected to.
     #[inline]
     pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.peer_addr()
+        self.addrs.peer()
     }
 
-    /// Shutdown the TCP stream.
+    /// Returns the transport stream.
     #[inline]
-    pub async fn shutdown(&mut self) -> std::io::Result<()> {
-        self.stream.shutdown().await
+    pub fn into_inner(self) -> T {
+        self.stream
     }
+}
 
+impl<O> IncomingConnection<O> {
     /// Reads the linger duration for this socket by getting the `SO_LINGER` option.
     ///
     /// For more information about this option, see
@@ -58,6 +219,7 @@ impl<O: 'static> IncomingConnection<O> {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
         self.stream.set_linger(dur)
     }
@@ -94,41 +256,151 @@ impl<O: 'static> IncomingConnection<O> {
     pub fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
         self.stream.set_ttl(ttl)
     }
+}
+
+impl<O, T> IncomingConnection<O, T>
+where
+    O: Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// Shutdown the transport stream.
+    #[inline]
+    pub async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.stream.shutdown().await
+    }
 
     /// Perform a SOCKS5 authentication handshake using the given
     /// [`AuthExecutor`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html) adapter.
     ///
+    /// The version byte sent by the client is inspected first, so SOCKS4 and SOCKS4a clients can be served on the same listener.
+    /// Their request is read right away and the `USERID` is handed to
+    /// [`AuthExecutor::identify_socks4`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html#method.identify_socks4),
+    /// whose output takes the place of the SOCKS5 authentication output.
+    ///
     /// If the handshake succeeds, an [`Authenticated`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Authenticated.html)
-    /// alongs with the output of the [`AuthExecutor`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html) adapter is returned.
-    /// Otherwise, the error and the original [`TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) is returned.
+    /// holding the output of the [`AuthExecutor`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html) adapter is returned.
+    /// Otherwise, including when the executor rejects the client, the error is returned.
+    ///
+    /// The method negotiation and the authentication sub-negotiation each have to finish within their timeout, see
+    /// [`with_timeouts()`](#method.with_timeouts), or an error of kind [`std::io::ErrorKind::TimedOut`] is returned.
     ///
     /// Note that this method will not implicitly close the connection even if the handshake failed.
-    pub async fn authenticate(mut self) -> std::io::Result<(Authenticated, O)> {
-        let request = handshake::Request::retrieve_from_async_stream(&mut self.stream).await?;
-        if let Some(method) = self.evaluate_request(&request) {
+    pub async fn authenticate(mut self) -> std::io::Result<Authenticated<O, T>> {
+        let session = match &self.limits.sessions {
+            Some(limiter) => limiter.acquire(self.addrs.peer().ok().map(|addr| addr.ip())).map(Some),
+            None => Ok(None),
+        };
+        let deadline = Deadline::new(Phase::Handshake, self.ctx.timeouts.handshake_timeout());
+        let ver = deadline.run(self.stream.read_u8()).await?;
+        match Version::try_from(ver)? {
+            Version::V5 => self.authenticate_v5(ver, session, deadline).await,
+            Version::V4 => self.authenticate_v4(ver, session, deadline).await,
+        }
+    }
+
+    async fn authenticate_v5(
+        mut self,
+        ver: u8,
+        session: std::io::Result<Option<Session>>,
+        deadline: Deadline,
+    ) -> std::io::Result<Authenticated<O, T>> {
+        let ver = [ver];
+        let mut stream = (&ver[..]).chain(&mut self.stream);
+        let request = deadline.run(handshake::Request::retrieve_from_async_stream(&mut stream)).await?;
+        let session = match session {
+            Ok(session) => session,
+            Err(err) => {
+                let response = handshake::Response::new(AuthMethod::NoAcceptableMethods);
+                deadline.run(response.write_to_async_stream(&mut self.stream)).await?;
+                return Err(err);
+            }
+        };
+        if let Some(method) = self.auth.select_method(request.methods(), self.addrs.peer_or_unspecified()) {
             let response = handshake::Response::new(method);
-            response.write_to_async_stream(&mut self.stream).await?;
-            let output = self.auth.execute(&mut self.stream).await;
-            Ok((Authenticated::new(self.stream), output))
+            deadline.run(response.write_to_async_stream(&mut self.stream)).await?;
+            let deadline = Deadline::new(Phase::Auth, self.ctx.timeouts.auth_timeout());
+            let output = deadline.run(self.auth.execute_method(method, &mut self.stream)).await?;
+            let refusal = self.apply_limits(&output, session).err();
+            Ok(Authenticated::new(self.stream, self.addrs, self.ctx, output).with_refusal(refusal))
         } else {
             let response = handshake::Response::new(AuthMethod::NoAcceptableMethods);
-            response.write_to_async_stream(&mut self.stream).await?;
+            deadline.run(response.write_to_async_stream(&mut self.stream)).await?;
             let err = "No available handshake method provided by client";
             Err(std::io::Error::new(std::io::ErrorKind::Unsupported, err))
         }
     }
 
-    fn evaluate_request(&self, req: &handshake::Request) -> Option<AuthMethod> {
-        let method = self.auth.auth_method();
-        if req.evaluate_method(method) {
-            Some(method)
+    /// Sets the throttle of the authenticated client, and counts its session for its user, failing if the user is at its limit.
+    fn apply_limits(&mut self, output: &O, session: Option<Session>) -> std::io::Result<()> {
+        let user = (self.limits.user_name)(output);
+        if let Some(limiter) = &self.limits.bandwidth {
+            let ip = self.addrs.peer().ok().map(|addr| addr.ip());
+            self.ctx.throttle = Some(limiter.throttle(user, ip));
+        }
+        let Some(mut session) = session else {
+            return Ok(());
+        };
+        let res = user.map_or(Ok(()), |user| session.set_user(user));
+        self.ctx.session = Some(Arc::new(session));
+        res
+    }
+
+    async fn authenticate_v4(
+        mut self,
+        ver: u8,
+        session: std::io::Result<Option<Session>>,
+        deadline: Deadline,
+    ) -> std::io::Result<Authenticated<O, T>> {
+        let ver = [ver];
+        let mut stream = (&ver[..]).chain(&mut self.stream);
+        let request = deadline.run(socks4::Request::retrieve_from_async_stream(&mut stream)).await?;
+        let session = match session {
+            Ok(session) => session,
+            Err(err) => {
+                deadline
+                    .run(write_reply(
+                        &mut self.stream,
+                        Version::V4,
+                        Reply::GeneralFailure,
+                        Address::unspecified(),
+                    ))
+                    .await?;
+                return Err(err);
+            }
+        };
+        let auth_deadline = Deadline::new(Phase::Auth, self.ctx.timeouts.auth_timeout());
+        let output = auth_deadline
+            .run(async { Ok(self.auth.identify_socks4(&request.user_id).await) })
+            .await?;
+        if let Some(output) = output {
+            if let Err(err) = self.apply_limits(&output, session) {
+                deadline
+                    .run(write_reply(
+                        &mut self.stream,
+                        Version::V4,
+                        Reply::GeneralFailure,
+                        Address::unspecified(),
+                    ))
+                    .await?;
+                return Err(err);
+            }
+            Ok(Authenticated::new_v4(self.stream, self.addrs, self.ctx, request, output))
         } else {
-            None
+            deadline
+                .run(write_reply(
+                    &mut self.stream,
+                    Version::V4,
+                    Reply::ConnectionNotAllowed,
+                    Address::unspecified(),
+                ))
+                .await?;
+            let err = format!("SOCKS4 client with user-id \"{}\" is not allowed", request.user_id);
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, err))
         }
     }
 }
 
-impl<O> std::fmt::Debug for IncomingConnection<O> {
+impl<O, T: std::fmt::Debug> std::fmt::Debug for IncomingConnection<O, T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("IncomingConnection").field("stream", &self.stream).finish()
     }
@@ -141,20 +413,94 @@ impl<O> From<IncomingConnection<O>> for TcpStream {
     }
 }
 
-/// A TCP stream that has been authenticated.
+/// A stream that has been authenticated.
 ///
 /// To get the command from the SOCKS5 client, use
 /// [`wait_request`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Authenticated.html#method.wait_request).
 ///
-/// It can also be converted back into a raw [`tokio::TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) with `From` trait.
-pub struct Authenticated(TcpStream);
+/// It can also be converted back into the transport stream with [`into_inner()`](#method.into_inner), or into a raw
+/// [`tokio::TcpStream`](https://docs.rs/tokio/latest/tokio/net/struct.TcpStream.html) with `From` trait.
+///
+/// `O` is the output of the [`AuthExecutor`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html),
+/// e.g. the user name of the client. It is handed on to the connection returned by `wait_request`, so the request can
+/// be judged by who sent it.
+pub struct Authenticated<O = (), T = TcpStream> {
+    stream: T,
+    addrs: StreamAddrs,
+    ctx: ConnContext,
+    socks4_request: Option<socks4::Request>,
+    refusal: Option<std::io::Error>,
+    auth: O,
+}
+
+impl<O, T> Authenticated<O, T> {
+    #[inline]
+    fn new(stream: T, addrs: StreamAddrs, ctx: ConnContext, auth: O) -> Self {
+        Self {
+            stream,
+            addrs,
+            ctx,
+            socks4_request: None,
+            refusal: None,
+            auth,
+        }
+    }
+
+    #[inline]
+    fn new_v4(stream: T, addrs: StreamAddrs, ctx: ConnContext, request: socks4::Request, auth: O) -> Self {
+        Self {
+            stream,
+            addrs,
+            ctx,
+            socks4_request: Some(request),
+            refusal: None,
+            auth,
+        }
+    }
 
-impl Authenticated {
+    /// Makes [`wait_request()`](#method.wait_request) answer the request with [`Reply::GeneralFailure`] and fail with `refusal`.
     #[inline]
-    fn new(stream: TcpStream) -> Self {
-        Self(stream)
+    fn with_refusal(mut self, refusal: Option<std::io::Error>) -> Self {
+        self.refusal = refusal;
+        self
     }
 
+    /// Returns what the client was authenticated as.
+    #[inline]
+    pub fn auth(&self) -> &O {
+        &self.auth
+    }
+
+    /// Returns the SOCKS version spoken by the client.
+    #[inline]
+    pub fn version(&self) -> Version {
+        if self.socks4_request.is_some() {
+            Version::V4
+        } else {
+            Version::V5
+        }
+    }
+
+    /// Returns the local address that this stream is bound to.
+    #[inline]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.addrs.local()
+    }
+
+    /// Returns the remote address that this stream is connected to.
+    #[inline]
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.addrs.peer()
+    }
+
+    /// Returns the transport stream.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+impl<O, T: AsyncRead + AsyncWrite + Unpin + Send> Authenticated<O, T> {
     /// Waits the SOCKS5 client to send a request.
     ///
     /// This method will return a [`Command`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/enum.Command.html)
@@ -162,45 +508,64 @@ impl Authenticated {
     ///
     /// When encountering an error, the stream will be returned alongside the error.
     ///
+    /// For a SOCKS4 client the request has already been read during the handshake, so it is returned immediately.
+    /// Replies sent through the returned connection are encoded in the SOCKS version of the client.
+    ///
+    /// The request has to arrive within the request timeout of the connection, or an error of kind
+    /// [`std::io::ErrorKind::TimedOut`] is returned, see the
+    /// [`timeout`](https://docs.rs/socks5-impl/latest/socks5_impl/server/timeout/index.html) module.
+    ///
+    /// If the user of the client is at its limit of sessions, the request is answered with [`Reply::GeneralFailure`] and an error
+    /// of kind [`std::io::ErrorKind::QuotaExceeded`] is returned.
+    ///
     /// Note that this method will not implicitly close the connection even if the client sends an invalid request.
-    pub async fn wait_request(mut self) -> crate::Result<ClientConnection> {
-        let req = protocol::Request::retrieve_from_async_stream(&mut self.0).await?;
+    pub async fn wait_request(mut self) -> crate::Result<ClientConnection<O, T>> {
+        let (version, req) = match self.socks4_request.take() {
+            Some(req) => (Version::V4, protocol::Request::new(req.command, req.address)),
+            None => {
+                let deadline = Deadline::new(Phase::Request, self.ctx.timeouts.request_timeout());
+                let req = deadline
+                    .run(protocol::Request::retrieve_from_async_stream(&mut self.stream))
+                    .await?;
+                (Version::V5, req)
+            }
+        };
+        if let Some(err) = self.refusal.take() {
+            write_reply(&mut self.stream, version, Reply::GeneralFailure, Address::unspecified()).await?;
+            return Err(err.into());
+        }
 
         match req.command {
             Command::UdpAssociate => Ok(ClientConnection::UdpAssociate(
-                UdpAssociate::<associate::NeedReply>::new(self.0),
+                UdpAssociate::<associate::NeedReply, O, T>::new(self.stream, self.addrs, self.ctx, self.auth),
+                req.address,
+            )),
+            Command::Bind => Ok(ClientConnection::Bind(
+                Bind::<bind::NeedFirstReply, O, T>::new(self.stream, self.addrs, version, self.ctx, self.auth),
+                req.address,
+            )),
+            Command::Connect => Ok(ClientConnection::Connect(
+                Connect::<connect::NeedReply, O, T>::new(self.stream, self.addrs, version, self.ctx, self.auth),
                 req.address,
             )),
-            Command::Bind => Ok(ClientConnection::Bind(Bind::<bind::NeedFirstReply>::new(self.0), req.address)),
-            Command::Connect => Ok(ClientConnection::Connect(Connect::<connect::NeedReply>::new(self.0), req.address)),
         }
     }
 
     /// Causes the other peer to receive a read of length 0, indicating that no more data will be sent. This only closes the stream in one direction.
     #[inline]
     pub async fn shutdown(&mut self) -> std::io::Result<()> {
-        self.0.shutdown().await
-    }
-
-    /// Returns the local address that this stream is bound to.
-    #[inline]
-    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        self.0.local_addr()
-    }
-
-    /// Returns the remote address that this stream is connected to.
-    #[inline]
-    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        self.0.peer_addr()
+        self.stream.shutdown().await
     }
+}
 
+impl<O> Authenticated<O> {
     /// Reads the linger duration for this socket by getting the `SO_LINGER` option.
     ///
     /// For more information about this option, see
     /// [set_linger](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Authenticated.html#method.set_linger).
     #[inline]
     pub fn linger(&self) -> std::io::Result<Option<Duration>> {
-        self.0.linger()
+        self.stream.linger()
     }
 
     /// Sets the linger duration of this socket by setting the `SO_LINGER` option.
@@ -211,8 +576,9 @@ impl Authenticated {
     /// If `SO_LINGER` is not specified, and the stream is closed, the system handles the call in a way
     /// that allows the process to continue as quickly as possible.
     #[inline]
+    #[allow(deprecated)]
     pub fn set_linger(&self, dur: Option<Duration>) -> std::io::Result<()> {
-        self.0.set_linger(dur)
+        self.stream.set_linger(dur)
     }
 
     /// Gets the value of the `TCP_NODELAY` option on this socket.
@@ -221,7 +587,7 @@ impl Authenticated {
     /// [set_nodelay](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Authenticated.html#method.set_nodelay).
     #[inline]
     pub fn nodelay(&self) -> std::io::Result<bool> {
-        self.0.nodelay()
+        self.stream.nodelay()
     }
 
     /// Sets the value of the `TCP_NODELAY` option on this socket.
@@ -230,7 +596,7 @@ impl Authenticated {
     /// even if there is only a small amount of data. When not set, data is buffered until there is a sufficient amount to send out,
     /// thereby avoiding the frequent sending of small packets.
     pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
-        self.0.set_nodelay(nodelay)
+        self.stream.set_nodelay(nodelay)
     }
 
     /// Gets the value of the `IP_TTL` option for this socket.
@@ -238,21 +604,21 @@ impl Authenticated {
     /// For more information about this option, see
     /// [set_ttl](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.Authenticated.html#method.set_ttl).
     pub fn ttl(&self) -> std::io::Result<u32> {
-        self.0.ttl()
+        self.stream.ttl()
     }
 
     /// Sets the value for the `IP_TTL` option on this socket.
     ///
     /// This value sets the time-to-live field that is used in every packet sent from this socket.
     pub fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
-        self.0.set_ttl(ttl)
+        self.stream.set_ttl(ttl)
     }
 }
 
-impl From<Authenticated> for TcpStream {
+impl<O> From<Authenticated<O>> for TcpStream {
     #[inline]
-    fn from(conn: Authenticated) -> Self {
-        conn.0
+    fn from(conn: Authenticated<O>) -> Self {
+        conn.stream
     }
 }
 
@@ -261,9 +627,224 @@ impl From<Authenticated> for TcpStream {
 /// - Associate
 /// - Bind
 /// - Connect
+///
+/// Each of them carries the output of the authentication, see for example
+/// [`Connect::auth()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/connect/struct.Connect.html#method.auth).
 #[derive(Debug)]
-pub enum ClientConnection {
-    UdpAssociate(UdpAssociate<associate::NeedReply>, Address),
-    Bind(Bind<bind::NeedFirstReply>, Address),
-    Connect(Connect<connect::NeedReply>, Address),
+pub enum ClientConnection<O = (), T = TcpStream> {
+    UdpAssociate(UdpAssociate<associate::NeedReply, O, T>, Address),
+    Bind(Bind<bind::NeedFirstReply, O, T>, Address),
+    Connect(Connect<connect::NeedReply, O, T>, Address),
+}
+
+/// The socket addresses of the transport of a connection, for the transports that have them.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct StreamAddrs {
+    local: Option<SocketAddr>,
+    peer: Option<SocketAddr>,
+}
+
+impl StreamAddrs {
+    pub(crate) fn local(&self) -> std::io::Result<SocketAddr> {
+        self.local.ok_or_else(Self::missing)
+    }
+
+    pub(crate) fn peer(&self) -> std::io::Result<SocketAddr> {
+        self.peer.ok_or_else(Self::missing)
+    }
+
+    /// The address of the client, or `0.0.0.0:0` for a transport without one.
+    pub(crate) fn peer_or_unspecified(&self) -> SocketAddr {
+        self.peer.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+    }
+
+    fn missing() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "the transport has no socket address")
+    }
+}
+
+/// What the server hands on to the request of a connection: the resolver, the SSRF guard and the timeouts it was configured
+/// with, and the throttle and the session of the client once it is authenticated.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnContext {
+    resolver: Arc<dyn Resolver>,
+    ssrf_guard: Option<Arc<SsrfGuard>>,
+    timeouts: Timeouts,
+    throttle: Option<Throttle>,
+    session: Option<Arc<Session>>,
+}
+
+impl ConnContext {
+    pub(crate) async fn resolve(&self, addr: &Address) -> std::io::Result<Vec<SocketAddr>> {
+        let addrs = addr.resolve(&self.resolver).await?;
+        match &self.ssrf_guard {
+            Some(guard) => guard.filter(addrs),
+            None => Ok(addrs),
+        }
+    }
+}
+
+impl Default for ConnContext {
+    fn default() -> Self {
+        Self {
+            resolver: Arc::new(TokioResolver),
+            ssrf_guard: None,
+            timeouts: Timeouts::default(),
+            throttle: None,
+            session: None,
+        }
+    }
+}
+
+/// Writes a reply to the command request, encoded in the given SOCKS version.
+///
+/// A SOCKS4 reply can only carry an IPv4 address, so any other address is replied as `0.0.0.0:0`.
+/// A SOCKS5 reply whose domain address is too long to encode fails without writing anything.
+pub(crate) async fn write_reply<S>(stream: &mut S, version: Version, reply: Reply, addr: Address) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin + Send,
+{
+    match version {
+        Version::V5 => {
+            let resp = protocol::Response::new(reply, addr);
+            let mut buf = bytes::BytesMut::with_capacity(resp.len());
+            resp.try_write_to_buf(&mut buf)?;
+            stream.write_all(&buf).await
+        }
+        Version::V4 => {
+            let addr = match addr {
+                Address::SocketAddress(SocketAddr::V4(addr)) => addr,
+                _ => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            };
+            socks4::Response::new(reply.into(), addr).write_to_async_stream(stream).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        client,
+        protocol::{socks4, Address, AsyncStreamOperation, Command, Reply, UserKey},
+        resolver::StaticResolver,
+        server::{auth, ClientConnection, Server},
+    };
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::{io::BufStream, net::TcpStream};
+
+    async fn spawn_server<O: Send + 'static>(auth: crate::server::AuthAdaptor<O>) -> SocketAddr {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), auth).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = server.accept().await {
+                tokio::spawn(async move {
+                    let conn = conn.authenticate().await?;
+                    let version = conn.version();
+                    match conn.wait_request().await? {
+                        ClientConnection::Connect(connect, _) => {
+                            assert_eq!(connect.version(), version);
+                            let bound = Address::from(("10.0.0.1".parse::<std::net::Ipv4Addr>().unwrap(), 1080));
+                            connect.reply(Reply::Succeeded, bound).await?;
+                        }
+                        ClientConnection::Bind(bind, _) => {
+                            bind.reply(Reply::CommandNotSupported, Address::unspecified()).await?;
+                        }
+                        ClientConnection::UdpAssociate(associate, _) => {
+                            associate.reply(Reply::CommandNotSupported, Address::unspecified()).await?;
+                        }
+                    }
+                    Ok::<_, crate::Error>(())
+                });
+            }
+        });
+        addr
+    }
+
+    async fn socks4_request(server: SocketAddr, req: socks4::Request) -> std::io::Result<socks4::Response> {
+        let mut stream = TcpStream::connect(server).await?;
+        req.write_to_async_stream(&mut stream).await?;
+        socks4::Response::retrieve_from_async_stream(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn serve_socks4_and_socks5_on_one_listener() {
+        let server = spawn_server(Arc::new(auth::NoAuth)).await;
+
+        let req = socks4::Request::new(
+            Command::Connect,
+            Address::from(("127.0.0.1".parse::<std::net::Ipv4Addr>().unwrap(), 80)),
+            "alice",
+        );
+        let resp = socks4_request(server, req).await.unwrap();
+        assert_eq!(resp.reply, socks4::Reply::Granted);
+        assert_eq!(resp.address, "10.0.0.1:1080".parse().unwrap());
+
+        let req = socks4::Request::new(Command::Connect, Address::from(("example.com", 80)), "");
+        let resp = socks4_request(server, req).await.unwrap();
+        assert_eq!(resp.reply, socks4::Reply::Granted);
+
+        let req = socks4::Request::new(Command::Bind, Address::from(("example.com", 21)), "");
+        let resp = socks4_request(server, req).await.unwrap();
+        assert_eq!(resp.reply, socks4::Reply::Rejected);
+
+        let mut stream = BufStream::new(TcpStream::connect(server).await.unwrap());
+        let addr = client::connect(&mut stream, ("example.com", 80), None).await.unwrap();
+        assert_eq!(addr, Address::from(("10.0.0.1".parse::<std::net::Ipv4Addr>().unwrap(), 1080)));
+    }
+
+    #[tokio::test]
+    async fn reject_socks4_without_identity() {
+        let server = spawn_server(Arc::new(auth::UserKeyAuth::new("hyper", "proxy"))).await;
+
+        let req = socks4::Request::new(Command::Connect, Address::from(("example.com", 80)), "hyper");
+        let resp = socks4_request(server, req).await.unwrap();
+        assert_eq!(resp.reply, socks4::Reply::Rejected);
+
+        let mut stream = BufStream::new(TcpStream::connect(server).await.unwrap());
+        let auth = Some(UserKey::new("hyper", "proxy"));
+        client::connect(&mut stream, ("example.com", 80), auth).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reply_with_unencodable_address_fails() {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let (conn, _) = server.accept().await?;
+            let ClientConnection::Connect(connect, _) = conn.authenticate().await?.wait_request().await? else {
+                unreachable!()
+            };
+            let bound = Address::from(("a".repeat(300), 80));
+            Ok::<_, crate::Error>(connect.reply(Reply::Succeeded, bound).await.map(drop))
+        });
+
+        let mut stream = BufStream::new(TcpStream::connect(addr).await.unwrap());
+        // The server refuses to send the over-long bound address and drops the connection instead.
+        assert!(client::connect(&mut stream, ("example.com", 80), None).await.is_err());
+        let err = task.await.unwrap().unwrap().unwrap_err();
+        assert!(err.to_string().contains("domain is 300 bytes long"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn connections_resolve_with_the_server_resolver() {
+        let resolver = StaticResolver::from_iter([("proxied.test", "192.0.2.7".parse().unwrap())]);
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth))
+            .await
+            .unwrap()
+            .with_resolver(Arc::new(resolver));
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (conn, _) = server.accept().await?;
+            let conn = conn.authenticate().await?;
+            if let ClientConnection::Connect(connect, addr) = conn.wait_request().await? {
+                let resolved = addr.resolve(connect.resolver()).await?;
+                connect.reply(Reply::Succeeded, Address::from(resolved[0])).await?;
+            }
+            Ok::<_, crate::Error>(())
+        });
+
+        let mut stream = BufStream::new(TcpStream::connect(addr).await.unwrap());
+        let bound = client::connect(&mut stream, ("proxied.test", 443), None).await.unwrap();
+        assert_eq!(bound, Address::from(("192.0.2.7".parse::<std::net::Ipv4Addr>().unwrap(), 443)));
+    }
 }
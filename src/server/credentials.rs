@@ -0,0 +1,421 @@
+//! Credentials of the username and password method, looked up by user name.
+//!
+//! A [`CredentialStore`] is checked by [`CredentialAuth`](crate::server::auth::CredentialAuth). Two stores are provided:
+//! [`MemoryCredentials`], changed at runtime, and with the `password-hash` feature [`FileCredentials`], a file of
+//! password hashes that is reloaded when it changes.
+
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+#[cfg(feature = "password-hash")]
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use subtle::ConstantTimeEq;
+#[cfg(feature = "password-hash")]
+use tokio::time::Instant;
+
+/// The secret a user is checked against.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// A password kept as is.
+    Plain(String),
+    /// A password hash, in the PHC format of argon2 (`$argon2id$...`), or the crypt format of bcrypt (`$2b$...`)
+    /// or SHA-crypt (`$5$...` and `$6$...`). Build it with [`Credential::hashed()`] to have the format checked.
+    #[cfg(feature = "password-hash")]
+    Hashed(String),
+}
+
+impl Credential {
+    /// Takes a password hash, failing if its format is not one of [`Credential::Hashed`].
+    #[cfg(feature = "password-hash")]
+    pub fn hashed(hash: &str) -> std::io::Result<Self> {
+        match HashKind::of(hash) {
+            Some(_) => Ok(Self::Hashed(hash.to_owned())),
+            None => {
+                let err = format!("unsupported password hash format \"{}\"", hash.split('$').nth(1).unwrap_or(hash));
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+            }
+        }
+    }
+
+    /// Returns whether the credential needs hashing to be checked, which is slow by design.
+    pub fn is_hashed(&self) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            #[cfg(feature = "password-hash")]
+            Self::Hashed(_) => true,
+        }
+    }
+
+    /// Checks `password` against the credential.
+    ///
+    /// Plain passwords are compared in constant time. Hashes are checked with their own algorithm and parameters,
+    /// which takes long enough that it should be done off the async runtime, see [`is_hashed()`](Self::is_hashed).
+    pub fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Plain(expected) => password.as_bytes().ct_eq(expected.as_bytes()).into(),
+            #[cfg(feature = "password-hash")]
+            Self::Hashed(hash) => match HashKind::of(hash) {
+                Some(HashKind::Argon2) => {
+                    use argon2::{PasswordHash, PasswordVerifier};
+                    PasswordHash::new(hash).is_ok_and(|hash| argon2::Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+                }
+                Some(HashKind::Bcrypt) => bcrypt::verify(password, hash).unwrap_or(false),
+                Some(HashKind::ShaCrypt) => {
+                    use sha_crypt::PasswordVerifier;
+                    sha_crypt::ShaCrypt::default()
+                        .verify_password(password.as_bytes(), hash.as_str())
+                        .is_ok()
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plain(_) => f.write_str("Plain(..)"),
+            #[cfg(feature = "password-hash")]
+            Self::Hashed(_) => f.write_str("Hashed(..)"),
+        }
+    }
+}
+
+#[cfg(feature = "password-hash")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HashKind {
+    Argon2,
+    Bcrypt,
+    ShaCrypt,
+}
+
+#[cfg(feature = "password-hash")]
+impl HashKind {
+    fn of(hash: &str) -> Option<Self> {
+        match hash.split('$').nth(1)? {
+            "argon2i" | "argon2d" | "argon2id" => Some(Self::Argon2),
+            "2a" | "2b" | "2x" | "2y" => Some(Self::Bcrypt),
+            "5" | "6" => Some(Self::ShaCrypt),
+            _ => None,
+        }
+    }
+}
+
+/// Where the username and password method looks users up.
+///
+/// # Example
+/// ```rust
+/// use async_trait::async_trait;
+/// use socks5_impl::server::credentials::{Credential, CredentialStore};
+///
+/// /// Every user's password is its name spelled backwards.
+/// struct Backwards;
+///
+/// #[async_trait]
+/// impl CredentialStore for Backwards {
+///     async fn lookup(&self, username: &str) -> Option<Credential> {
+///         Some(Credential::Plain(username.chars().rev().collect()))
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait CredentialStore: Send + Sync {
+    /// Returns the credential of `username`, or `None` if there is no such user.
+    async fn lookup(&self, username: &str) -> Option<Credential>;
+}
+
+#[async_trait]
+impl<C: CredentialStore + ?Sized> CredentialStore for Arc<C> {
+    async fn lookup(&self, username: &str) -> Option<Credential> {
+        (**self).lookup(username).await
+    }
+}
+
+/// Credentials kept in memory. Users can be added and removed while the server runs.
+#[derive(Debug, Default)]
+pub struct MemoryCredentials {
+    users: RwLock<HashMap<String, Credential>>,
+}
+
+impl MemoryCredentials {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a user with a plain password.
+    pub fn with_user(self, username: &str, password: &str) -> Self {
+        self.with_credential(username, Credential::Plain(password.to_owned()))
+    }
+
+    /// Adds a user with any credential.
+    pub fn with_credential(self, username: &str, credential: Credential) -> Self {
+        self.insert(username, credential);
+        self
+    }
+
+    /// Adds or replaces a user, returning its previous credential.
+    pub fn insert(&self, username: &str, credential: Credential) -> Option<Credential> {
+        self.users.write().unwrap().insert(username.to_owned(), credential)
+    }
+
+    /// Removes a user, returning its credential.
+    pub fn remove(&self, username: &str) -> Option<Credential> {
+        self.users.write().unwrap().remove(username)
+    }
+
+    /// Returns the number of users.
+    pub fn len(&self) -> usize {
+        self.users.read().unwrap().len()
+    }
+
+    /// Returns whether there is no user.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl CredentialStore for MemoryCredentials {
+    async fn lookup(&self, username: &str) -> Option<Credential> {
+        self.users.read().unwrap().get(username).cloned()
+    }
+}
+
+/// Credentials read from a file, in the style of `htpasswd`: one `username:hash` line per user, the hash being in
+/// one of the formats of [`Credential::Hashed`]. Blank lines and lines starting with `#` are skipped.
+///
+/// The file is checked for changes on lookups, at most once per [`with_check_interval()`](Self::with_check_interval),
+/// and reloaded when its modification time, size or contents changed. A file that fails to load keeps the previous users
+/// in use.
+#[cfg(feature = "password-hash")]
+#[derive(Debug)]
+pub struct FileCredentials {
+    path: PathBuf,
+    check_interval: Duration,
+    users: RwLock<HashMap<String, Credential>>,
+    check: tokio::sync::Mutex<FileCheck>,
+}
+
+#[cfg(feature = "password-hash")]
+#[derive(Debug)]
+struct FileCheck {
+    at: Instant,
+    stamp: Option<FileStamp>,
+}
+
+/// What tells a changed file apart: its modification time and size, and a hash of its contents for a hash replaced by
+/// another of the same length within the granularity of the modification time.
+#[cfg(feature = "password-hash")]
+#[derive(Debug, PartialEq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+    digest: u64,
+}
+
+#[cfg(feature = "password-hash")]
+impl FileCredentials {
+    /// Loads the credentials of the file at `path`.
+    pub async fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let (users, stamp) = Self::load(&path).await?;
+        Ok(Self {
+            path,
+            check_interval: Duration::from_secs(1),
+            users: RwLock::new(users),
+            check: tokio::sync::Mutex::new(FileCheck {
+                at: Instant::now(),
+                stamp: Some(stamp),
+            }),
+        })
+    }
+
+    /// How often lookups check the file for changes. Defaults to one second.
+    pub fn with_check_interval(mut self, interval: Duration) -> Self {
+        self.check_interval = interval;
+        self
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of users.
+    pub fn len(&self) -> usize {
+        self.users.read().unwrap().len()
+    }
+
+    /// Returns whether there is no user.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reloads the file now, whether it changed or not. On error the previous users are kept.
+    pub async fn reload(&self) -> std::io::Result<()> {
+        let mut check = self.check.lock().await;
+        self.reload_locked(&mut check).await
+    }
+
+    async fn reload_locked(&self, check: &mut FileCheck) -> std::io::Result<()> {
+        let (users, stamp) = Self::load(&self.path).await?;
+        *self.users.write().unwrap() = users;
+        check.stamp = Some(stamp);
+        Ok(())
+    }
+
+    async fn reload_if_changed(&self) {
+        let mut check = self.check.lock().await;
+        if check.at.elapsed() < self.check_interval {
+            return;
+        }
+        check.at = Instant::now();
+        let stamp = Self::read(&self.path).await.ok().map(|(_, stamp)| stamp);
+        if stamp != check.stamp {
+            match self.reload_locked(&mut check).await {
+                Ok(()) => log::info!("reloaded credentials from {}", self.path.display()),
+                Err(err) => log::warn!("keeping previous credentials, failed to reload {}: {err}", self.path.display()),
+            }
+        }
+    }
+
+    async fn load(path: &Path) -> std::io::Result<(HashMap<String, Credential>, FileStamp)> {
+        let (text, stamp) = Self::read(path).await?;
+        let users = Self::parse(&text).map_err(|err| std::io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+        Ok((users, stamp))
+    }
+
+    async fn read(path: &Path) -> std::io::Result<(String, FileStamp)> {
+        let meta = tokio::fs::metadata(path).await?;
+        let text = tokio::fs::read_to_string(path).await?;
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        let stamp = FileStamp {
+            modified: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            len: meta.len(),
+            digest: hasher.finish(),
+        };
+        Ok((text, stamp))
+    }
+
+    fn parse(text: &str) -> std::io::Result<HashMap<String, Credential>> {
+        let mut users = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("line {}: {msg}", number + 1));
+            let (username, hash) = line.split_once(':').ok_or_else(|| invalid("expected username:hash"))?;
+            let credential = Credential::hashed(hash.trim()).map_err(|err| invalid(&err.to_string()))?;
+            users.insert(username.trim().to_owned(), credential);
+        }
+        Ok(users)
+    }
+}
+
+#[cfg(feature = "password-hash")]
+#[async_trait]
+impl CredentialStore for FileCredentials {
+    async fn lookup(&self, username: &str) -> Option<Credential> {
+        self.reload_if_changed().await;
+        self.users.read().unwrap().get(username).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Credential, CredentialStore, MemoryCredentials};
+
+    #[tokio::test]
+    async fn memory_credentials() {
+        let store = MemoryCredentials::new().with_user("hyper", "proxy");
+        assert!(store.lookup("hyper").await.unwrap().verify("proxy"));
+        assert!(!store.lookup("hyper").await.unwrap().verify("prox"));
+        assert!(store.lookup("other").await.is_none());
+
+        store.insert("other", Credential::Plain("secret".into()));
+        assert!(store.lookup("other").await.unwrap().verify("secret"));
+        store.remove("hyper");
+        assert!(store.lookup("hyper").await.is_none());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[cfg(feature = "password-hash")]
+    #[test]
+    fn hashed_credentials() {
+        use argon2::{password_hash::SaltString, PasswordHasher};
+
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        let argon2 = argon2::Argon2::default().hash_password(b"proxy", &salt).unwrap().to_string();
+        let bcrypt = bcrypt::hash("proxy", 4).unwrap();
+        for hash in [argon2.as_str(), bcrypt.as_str()] {
+            let credential = Credential::hashed(hash).unwrap();
+            assert!(credential.is_hashed());
+            assert!(credential.verify("proxy"), "{hash}");
+            assert!(!credential.verify("proxy "), "{hash}");
+        }
+
+        // From the test vectors of the sha-crypt crate.
+        for hash in [
+            "$5$9aEeVXnCiCNHUjO/$FrVBcjyJukRaE6inMYazyQv1DBnwaKfom.71ebgQR/0",
+            "$6$bbe605c2cce4c642$BiBOywFAm9kdv6ZPpj2GaKVqeh/.c21pf1uFBaq.e59KEE2Ej74iJleXaLXURYV6uh5LF4K7dDc4vtRtPiiKB/",
+        ] {
+            let credential = Credential::hashed(hash).unwrap();
+            assert!(credential.verify("foobar"), "{hash}");
+            assert!(!credential.verify("foobaz"), "{hash}");
+        }
+
+        assert!(Credential::hashed("$1$md5$is-not-supported").is_err());
+        assert!(Credential::hashed("proxy").is_err());
+    }
+
+    #[cfg(feature = "password-hash")]
+    #[tokio::test]
+    async fn file_credentials_reload() {
+        use super::FileCredentials;
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("socks5-impl-credentials-{}", std::process::id()));
+        let hyper = bcrypt::hash("proxy", 4).unwrap();
+        tokio::fs::write(&path, format!("# users\n\nhyper:{hyper}\n")).await.unwrap();
+
+        let store = FileCredentials::open(&path).await.unwrap().with_check_interval(Duration::ZERO);
+        assert_eq!(store.len(), 1);
+        assert!(store.lookup("hyper").await.unwrap().verify("proxy"));
+        assert!(store.lookup("other").await.is_none());
+
+        let other = bcrypt::hash("secret", 4).unwrap();
+        tokio::fs::write(&path, format!("hyper:{hyper}\nother:{other}\n")).await.unwrap();
+        assert!(store.lookup("other").await.unwrap().verify("secret"));
+
+        // A hash replaced by another of the same length, with the modification time unchanged.
+        let modified = tokio::fs::metadata(&path).await.unwrap().modified().unwrap();
+        let revoked = bcrypt::hash("revoked", 4).unwrap();
+        assert_eq!(revoked.len(), other.len());
+        tokio::fs::write(&path, format!("hyper:{hyper}\nother:{revoked}\n")).await.unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(store.lookup("other").await.unwrap().verify("revoked"));
+
+        // A broken file keeps the users loaded before.
+        tokio::fs::write(&path, "hyper:plain-text\n").await.unwrap();
+        assert!(store.lookup("other").await.is_some());
+        let err = store.reload().await.unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{err}");
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
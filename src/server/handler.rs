@@ -0,0 +1,430 @@
+//! Request handling for [`Server::serve`](crate::server::Server::serve).
+//!
+//! A [`Handler`] decides what happens to a client once it has authenticated and sent its request.
+//! Every hook has a default that makes a plain proxy, so an implementation only overrides what it wants to change,
+//! and can still fall back to the routines of this module, which are what the defaults call.
+
+use crate::{
+    protocol::{Address, Reply},
+    server::{
+        bandwidth::Throttled,
+        connection::{associate, bind, connect},
+        timeout, Bind, BindOptions, ClientConnection, Connect, IncomingConnection, UdpAssociate, UdpRelayOptions, UdpRelayStats,
+    },
+};
+use async_trait::async_trait;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+/// Hooks called by [`Server::serve`](crate::server::Server::serve) for every client, `O` being the output of the
+/// [`AuthExecutor`](crate::server::AuthExecutor) of the server and `T` the transport stream of the connections.
+///
+/// Errors returned by a hook are logged by the server and end the connection.
+///
+/// # Example
+/// ```no_run
+/// use async_trait::async_trait;
+/// use socks5_impl::{
+///     protocol::{Address, Reply},
+///     server::{auth, connection::connect, handler, Connect, Handler, Server},
+/// };
+/// use std::sync::Arc;
+///
+/// struct NoLoopback;
+///
+/// #[async_trait]
+/// impl Handler for NoLoopback {
+///     async fn connect(&self, connect: Connect<connect::NeedReply>, addr: Address) -> socks5_impl::Result<()> {
+///         match &addr {
+///             Address::SocketAddress(ip) if ip.ip().is_loopback() => {
+///                 connect.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+///                 Ok(())
+///             }
+///             _ => handler::connect(connect, addr).await,
+///         }
+///     }
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> socks5_impl::Result<()> {
+/// let server = Server::bind("127.0.0.1:1080".parse().unwrap(), Arc::new(auth::NoAuth)).await?;
+/// server.serve(NoLoopback).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[async_trait]
+pub trait Handler<O = (), T = TcpStream>: Send + Sync + 'static
+where
+    O: Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Decides whether an authenticated client may go on to send its request. Returning `false` closes the connection.
+    ///
+    /// `peer_addr` is `0.0.0.0:0` for transports without socket addresses, such as Unix sockets.
+    ///
+    /// Clients rejected by the [`AuthExecutor`](crate::server::AuthExecutor) never get here. The default admits everybody else.
+    async fn authenticated(&self, _auth: &O, _peer_addr: SocketAddr) -> bool {
+        true
+    }
+
+    /// Handles a `CONNECT` request. Defaults to [`handler::connect`](connect()).
+    ///
+    /// The output of the authentication is available from [`Connect::auth()`].
+    async fn connect(&self, connect: Connect<connect::NeedReply, O, T>, addr: Address) -> crate::Result<()> {
+        self::connect(connect, addr).await
+    }
+
+    /// Handles a `BIND` request. Defaults to [`handler::bind`](bind()).
+    async fn bind(&self, bind: Bind<bind::NeedFirstReply, O, T>, addr: Address) -> crate::Result<()> {
+        self::bind(bind, addr).await
+    }
+
+    /// Handles a `UDP ASSOCIATE` request. Defaults to [`handler::udp_associate`](udp_associate()).
+    async fn udp_associate(&self, associate: UdpAssociate<associate::NeedReply, O, T>, addr: Address) -> crate::Result<()> {
+        self::udp_associate(associate, addr).await
+    }
+}
+
+/// A [`Handler`] that keeps all the defaults, i.e. a plain SOCKS5 proxy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultHandler;
+
+impl<O, T> Handler<O, T> for DefaultHandler
+where
+    O: Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+}
+
+/// Runs one client through authentication, its request and the matching hook of `handler`.
+pub(crate) async fn handle<O, T, H>(conn: IncomingConnection<O, T>, handler: &H) -> crate::Result<()>
+where
+    O: Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    H: Handler<O, T> + ?Sized,
+{
+    let peer_addr = conn.peer_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let conn = conn.authenticate().await?;
+    if !handler.authenticated(conn.auth(), peer_addr).await {
+        log::debug!("{peer_addr} not admitted after authentication");
+        return Ok(());
+    }
+
+    match conn.wait_request().await? {
+        ClientConnection::Connect(connect, addr) => handler.connect(connect, addr).await,
+        ClientConnection::Bind(bind, addr) => handler.bind(bind, addr).await,
+        ClientConnection::UdpAssociate(associate, addr) => handler.udp_associate(associate, addr).await,
+    }
+}
+
+/// Maps the error of reaching a destination to the reply telling the client about it.
+pub(crate) fn reply_for(err: &std::io::Error) -> Reply {
+    use std::io::ErrorKind::*;
+    match err.kind() {
+        ConnectionRefused => Reply::ConnectionRefused,
+        NotFound | HostUnreachable => Reply::HostUnreachable,
+        NetworkUnreachable => Reply::NetworkUnreachable,
+        TimedOut => Reply::TtlExpired,
+        PermissionDenied => Reply::ConnectionNotAllowed,
+        _ => Reply::GeneralFailure,
+    }
+}
+
+/// Connects to `addr`, looked up with the resolver of the connection, replies with the outcome and relays traffic
+/// in both directions until either side closes, or the relay idle timeout or maximum lifetime of the connection expires.
+///
+/// Only the addresses the SSRF guard of the connection allows are tried, see [`Connect::resolve()`].
+pub async fn connect<O, T>(connect: Connect<connect::NeedReply, O, T>, addr: Address) -> crate::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let target = match connect.resolve(&addr).await {
+        Ok(addrs) => TcpStream::connect(&addrs[..]).await,
+        Err(err) => Err(err),
+    };
+    let mut target = match target {
+        Ok(target) => target,
+        Err(err) => {
+            let mut conn = connect.reply(reply_for(&err), Address::unspecified()).await?;
+            conn.shutdown().await?;
+            return Err(err.into());
+        }
+    };
+
+    let conn = connect.reply(Reply::Succeeded, Address::from(target.local_addr()?)).await?;
+    log::trace!("CONNECT {addr} -> {}", target.peer_addr()?);
+    let (throttle, timeouts) = (conn.throttle().cloned(), *conn.timeouts());
+    timeout::copy_bidirectional(&mut target, &mut Throttled::new(conn, throttle), &timeouts).await?;
+    Ok(())
+}
+
+/// Serves a `BIND` request with [`Bind::relay()`] and the default [`BindOptions`].
+pub async fn bind<O, T>(bind: Bind<bind::NeedFirstReply, O, T>, addr: Address) -> crate::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    bind.relay(addr, &BindOptions::default()).await
+}
+
+/// Serves a `UDP ASSOCIATE` request with [`UdpAssociate::relay()`] and the default [`UdpRelayOptions`].
+pub async fn udp_associate<O, T>(associate: UdpAssociate<associate::NeedReply, O, T>, addr: Address) -> crate::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let stats = Arc::new(UdpRelayStats::default());
+    let res = associate.relay(addr, &UdpRelayOptions::default(), stats.clone()).await;
+    log::debug!(
+        "[UDP] relayed {} packets ({} bytes) out, {} packets ({} bytes) back, dropped {}",
+        stats.packets_from_client(),
+        stats.bytes_from_client(),
+        stats.packets_to_client(),
+        stats.bytes_to_client(),
+        stats.packets_dropped()
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultHandler, Handler};
+    #[cfg(unix)]
+    use crate::server::{connection::associate, UdpAssociate, UdpRelayOptions};
+    use crate::{
+        client,
+        protocol::{Address, Reply, UserKey},
+        server::{auth, connection::connect, Connect, IncomingConnection, Server},
+    };
+    use async_trait::async_trait;
+    #[cfg(unix)]
+    use std::net::Ipv4Addr;
+    use std::{net::SocketAddr, sync::Arc};
+    #[cfg(unix)]
+    use tokio::net::UnixStream;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, BufStream},
+        net::{TcpListener, TcpStream, UdpSocket},
+    };
+
+    async fn spawn_echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    tokio::io::copy(&mut r, &mut w).await
+                });
+            }
+        });
+        addr
+    }
+
+    async fn spawn_udp_echo() -> SocketAddr {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((len, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..len], from).await;
+            }
+        });
+        addr
+    }
+
+    async fn spawn_server<O, H>(auth: crate::server::AuthAdaptor<O>, handler: H) -> SocketAddr
+    where
+        O: Send + Sync + 'static,
+        H: Handler<O>,
+    {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), auth).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(handler));
+        addr
+    }
+
+    async fn echo_through(proxy: SocketAddr, target: SocketAddr, auth: Option<UserKey>) -> crate::Result<()> {
+        let mut stream = BufStream::new(TcpStream::connect(proxy).await?);
+        client::connect(&mut stream, target, auth).await?;
+        stream.write_all(b"hello").await?;
+        stream.flush().await?;
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn default_handler_proxies_connect() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_server(Arc::new(auth::NoAuth), DefaultHandler).await;
+        echo_through(proxy, echo, None).await.unwrap();
+
+        // Nothing listens on the port of a listener that was just dropped.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let err = echo_through(proxy, closed, None).await.unwrap_err();
+        assert_eq!(err.to_string(), Reply::ConnectionRefused.to_string());
+    }
+
+    #[tokio::test]
+    async fn default_handler_checks_user_key() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_server(Arc::new(auth::UserKeyAuth::new("hyper", "proxy")), DefaultHandler).await;
+        echo_through(proxy, echo, Some(UserKey::new("hyper", "proxy"))).await.unwrap();
+        assert!(echo_through(proxy, echo, Some(UserKey::new("hyper", "wrong"))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn default_handler_serves_in_memory_streams() {
+        let echo = spawn_echo().await;
+        let serve = |stream| {
+            let conn = IncomingConnection::new(stream, Arc::new(auth::UserKeyAuth::new("hyper", "proxy")));
+            tokio::spawn(async move { super::handle(conn, &DefaultHandler).await })
+        };
+
+        let (client_end, server_end) = tokio::io::duplex(1024);
+        let task = serve(server_end);
+        let mut stream = BufStream::new(client_end);
+        client::connect(&mut stream, echo, Some(UserKey::new("hyper", "proxy")))
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(stream);
+        task.await.unwrap().unwrap();
+
+        // Without a local address there is no IP to open the BIND listener on.
+        let (client_end, server_end) = tokio::io::duplex(1024);
+        let task = serve(server_end);
+        let stream = BufStream::new(client_end);
+        let err = client::SocksListener::bind(stream, ("127.0.0.1", 0), Some(UserKey::new("hyper", "proxy")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), Reply::GeneralFailure.to_string());
+        assert!(task.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn default_handler_binds() {
+        let proxy = spawn_server(Arc::new(auth::NoAuth), DefaultHandler).await;
+        let stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        let listener = client::SocksListener::bind(stream, ("127.0.0.1", 0), None).await.unwrap();
+        let Address::SocketAddress(bound) = listener.proxy_addr().clone() else {
+            panic!("bound to a domain")
+        };
+
+        let mut peer = TcpStream::connect(bound).await.unwrap();
+        let (mut stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr, Address::from(peer.local_addr().unwrap()));
+        peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn default_handler_relays_udp() {
+        let echo_addr = spawn_udp_echo().await;
+        let proxy = spawn_server(Arc::new(auth::NoAuth), DefaultHandler).await;
+        let udp = client::create_udp_client(proxy, None).await.unwrap();
+        udp.send_to(b"hello", echo_addr).await.unwrap();
+        let mut buf = Vec::new();
+        let (_, from) = udp.recv_from(std::time::Duration::from_secs(5), &mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        assert_eq!(from, Address::from(echo_addr));
+    }
+
+    /// Relays UDP for clients on a Unix socket, telling them to send their datagrams to loopback.
+    #[cfg(unix)]
+    struct AdvertiseLoopback;
+
+    #[cfg(unix)]
+    #[async_trait]
+    impl Handler<(), UnixStream> for AdvertiseLoopback {
+        async fn udp_associate(&self, associate: UdpAssociate<associate::NeedReply, (), UnixStream>, addr: Address) -> crate::Result<()> {
+            let options = UdpRelayOptions::default().with_advertised_ip(Some(Ipv4Addr::LOCALHOST.into()));
+            associate.relay(addr, &options, Arc::default()).await
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_server() {
+        let path = std::env::temp_dir().join(format!("socks5-impl-server-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = Server::bind_unix(&path, Arc::new(auth::NoAuth)).unwrap();
+        tokio::spawn(server.serve(AdvertiseLoopback));
+
+        let echo = spawn_echo().await;
+        let mut stream = BufStream::new(UnixStream::connect(&path).await.unwrap());
+        client::connect(&mut stream, echo, None).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let echo_addr = spawn_udp_echo().await;
+        let udp = client::create_unix_udp_client(&path, None).await.unwrap();
+        assert_eq!(udp.proxy_addr().port(), udp.get_ref().peer_addr().unwrap().port());
+        udp.send_to(b"hello", echo_addr).await.unwrap();
+        let mut buf = Vec::new();
+        let (_, from) = udp.recv_from(std::time::Duration::from_secs(5), &mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        assert_eq!(from, Address::from(echo_addr));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    struct Refuse;
+
+    #[async_trait]
+    impl Handler for Refuse {
+        async fn connect(&self, connect: Connect<connect::NeedReply>, _addr: Address) -> crate::Result<()> {
+            connect.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_handler_overrides_connect() {
+        let echo = spawn_echo().await;
+        let proxy = spawn_server(Arc::new(auth::NoAuth), Refuse).await;
+        let err = echo_through(proxy, echo, None).await.unwrap_err();
+        assert_eq!(err.to_string(), Reply::ConnectionNotAllowed.to_string());
+    }
+
+    /// Lets anonymous clients in, but only lets users connect.
+    struct UsersOnly;
+
+    #[async_trait]
+    impl Handler<Option<String>> for UsersOnly {
+        async fn connect(&self, connect: Connect<connect::NeedReply, Option<String>>, addr: Address) -> crate::Result<()> {
+            match connect.auth() {
+                Some(user) if user == "hyper" => super::connect(connect, addr).await,
+                _ => {
+                    connect.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_sees_the_auth_output() {
+        let auth = auth::MultiAuth::new(auth::AuthSelection::ServerPreference)
+            .with_mapped(auth::UserKeyAuth::new("hyper", "proxy"), Some)
+            .with_mapped(auth::NoAuth, |()| None);
+        let echo = spawn_echo().await;
+        let proxy = spawn_server(Arc::new(auth), UsersOnly).await;
+
+        let err = echo_through(proxy, echo, None).await.unwrap_err();
+        assert_eq!(err.to_string(), Reply::ConnectionNotAllowed.to_string());
+        echo_through(proxy, echo, Some(UserKey::new("hyper", "proxy"))).await.unwrap();
+    }
+}
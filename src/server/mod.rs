@@ -1,40 +1,128 @@
+use crate::resolver::{Resolver, TokioResolver};
+use connection::Limits;
 use std::{
     net::SocketAddr,
-    task::{Context, Poll},
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
 };
-use tokio::net::TcpListener;
 
+pub mod acl;
 pub mod auth;
+pub mod bandwidth;
 pub mod connection;
+pub mod credentials;
+pub mod handler;
+pub mod session;
+pub mod ssrf;
+pub mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 pub use crate::{
-    server::auth::{AuthAdaptor, AuthExecutor},
+    server::auth::{AuthAdaptor, AuthExecutor, AuthStream, AuthUser},
     server::connection::{
-        associate::{AssociatedUdpSocket, UdpAssociate},
-        bind::Bind,
+        associate::{AssociatedUdpSocket, UdpAssociate, UdpRelayOptions, UdpRelayStats},
+        bind::{Bind, BindOptions},
         connect::Connect,
         ClientConnection, IncomingConnection,
     },
+    server::handler::{DefaultHandler, Handler},
+    server::{bandwidth::BandwidthLimiter, session::SessionLimiter, ssrf::SsrfGuard, timeout::Timeouts},
 };
 
+/// A listener the [`Server`](https://docs.rs/socks5-impl/latest/socks5_impl/server/struct.Server.html) accepts
+/// transport streams from.
+///
+/// It is implemented for [`TcpListener`](https://docs.rs/tokio/latest/tokio/net/struct.TcpListener.html) and, on Unix,
+/// [`UnixListener`](https://docs.rs/tokio/latest/tokio/net/struct.UnixListener.html).
+pub trait Listener: Send + Sync + 'static {
+    /// The transport stream of accepted clients.
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// The address of the listener and of accepted clients.
+    type Addr: std::fmt::Debug + Send + 'static;
+
+    /// Polls to accept a client, returning its stream and address.
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, Self::Addr)>>;
+
+    /// Returns the address the listener is bound to.
+    fn local_addr(&self) -> std::io::Result<Self::Addr>;
+
+    /// Returns the local and peer socket addresses of an accepted stream, for transports that have them.
+    ///
+    /// They are set on the [`IncomingConnection`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.IncomingConnection.html),
+    /// see [`IncomingConnection::with_local_addr()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.IncomingConnection.html#method.with_local_addr).
+    /// The default has none.
+    fn socket_addrs(_stream: &Self::Stream) -> std::io::Result<(Option<SocketAddr>, Option<SocketAddr>)> {
+        Ok((None, None))
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Addr = SocketAddr;
+
+    #[inline]
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, Self::Addr)>> {
+        TcpListener::poll_accept(self, cx)
+    }
+
+    #[inline]
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        TcpListener::local_addr(self)
+    }
+
+    #[inline]
+    fn socket_addrs(stream: &Self::Stream) -> std::io::Result<(Option<SocketAddr>, Option<SocketAddr>)> {
+        Ok((Some(stream.local_addr()?), Some(stream.peer_addr()?)))
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+    type Addr = tokio::net::unix::SocketAddr;
+
+    #[inline]
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(Self::Stream, Self::Addr)>> {
+        tokio::net::UnixListener::poll_accept(self, cx)
+    }
+
+    #[inline]
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        tokio::net::UnixListener::local_addr(self)
+    }
+}
+
+/// A connection accepted by a [`Server`] on the listener `L`, with the address of the client.
+pub type Accepted<O, L = TcpListener> = (IncomingConnection<O, <L as Listener>::Stream>, <L as Listener>::Addr);
+
 /// The socks5 server itself.
 ///
 /// The server can be constructed on a given socket address, or be created on an existing TcpListener.
+/// It can also serve on any other [`Listener`](https://docs.rs/socks5-impl/latest/socks5_impl/server/trait.Listener.html),
+/// such as a Unix socket with [`bind_unix()`](#method.bind_unix).
 ///
 /// The authentication method can be configured with the
 /// [`AuthExecutor`](https://docs.rs/socks5-impl/latest/socks5_impl/server/auth/trait.AuthExecutor.html) trait.
-pub struct Server<O> {
-    listener: TcpListener,
+///
+/// Requested domain addresses are looked up with a [`Resolver`](https://docs.rs/socks5-impl/latest/socks5_impl/resolver/trait.Resolver.html),
+/// by default the [`TokioResolver`](https://docs.rs/socks5-impl/latest/socks5_impl/resolver/struct.TokioResolver.html).
+/// It is handed to every connection, see for example
+/// [`Connect::resolver()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/connect/struct.Connect.html#method.resolver).
+pub struct Server<O, L = TcpListener> {
+    listener: L,
     auth: AuthAdaptor<O>,
+    resolver: Arc<dyn Resolver>,
+    ssrf_guard: Option<Arc<SsrfGuard>>,
+    timeouts: Timeouts,
+    limits: Limits<O>,
 }
 
 impl<O: 'static> Server<O> {
-    /// Create a new socks5 server with the given TCP listener and authentication method.
-    #[inline]
-    pub fn new(listener: TcpListener, auth: AuthAdaptor<O>) -> Self {
-        Self { listener, auth }
-    }
-
     /// Create a new socks5 server on the given socket address and authentication method.
     #[inline]
     pub async fn bind(addr: SocketAddr, auth: AuthAdaptor<O>) -> std::io::Result<Self> {
@@ -48,15 +136,122 @@ impl<O: 'static> Server<O> {
         let listener = socket.listen(1024)?;
         Ok(Self::new(listener, auth))
     }
+}
+
+#[cfg(unix)]
+impl<O: 'static> Server<O, tokio::net::UnixListener> {
+    /// Create a new socks5 server on a Unix socket at `path`, which must not exist yet.
+    ///
+    /// Access is controlled with the permissions of the socket file and its directory. Unix sockets have no IP addresses,
+    /// so the built-in UDP ASSOCIATE relay needs
+    /// [`UdpRelayOptions::with_advertised_ip()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/associate/struct.UdpRelayOptions.html#method.with_advertised_ip)
+    /// or [`with_bind_ip()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/associate/struct.UdpRelayOptions.html#method.with_bind_ip)
+    /// to know where to open its socket.
+    #[inline]
+    pub fn bind_unix<P: AsRef<std::path::Path>>(path: P, auth: AuthAdaptor<O>) -> std::io::Result<Self> {
+        Ok(Self::new(tokio::net::UnixListener::bind(path)?, auth))
+    }
+}
+
+impl<O: 'static, L: Listener> Server<O, L> {
+    /// Create a new socks5 server with the given listener and authentication method.
+    #[inline]
+    pub fn new(listener: L, auth: AuthAdaptor<O>) -> Self {
+        let resolver = Arc::new(TokioResolver);
+        Self {
+            listener,
+            auth,
+            resolver,
+            ssrf_guard: None,
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+        }
+    }
+
+    /// Replaces the resolver handed to the connections of this server.
+    #[inline]
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Returns the resolver handed to the connections of this server.
+    #[inline]
+    pub fn resolver(&self) -> &Arc<dyn Resolver> {
+        &self.resolver
+    }
+
+    /// Checks the addresses requested from this server with `guard`, see the
+    /// [`ssrf`](https://docs.rs/socks5-impl/latest/socks5_impl/server/ssrf/index.html) module. There is no guard by default.
+    #[inline]
+    pub fn with_ssrf_guard(mut self, guard: Arc<SsrfGuard>) -> Self {
+        self.ssrf_guard = Some(guard);
+        self
+    }
+
+    /// Returns the SSRF guard handed to the connections of this server.
+    #[inline]
+    pub fn ssrf_guard(&self) -> Option<&Arc<SsrfGuard>> {
+        self.ssrf_guard.as_ref()
+    }
+
+    /// Holds the clients of this server to the limits of `limiter`, see the
+    /// [`bandwidth`](https://docs.rs/socks5-impl/latest/socks5_impl/server/bandwidth/index.html) module.
+    /// Keep a clone of the `Arc` to change the limits while the server runs.
+    #[inline]
+    pub fn with_bandwidth_limiter(mut self, limiter: Arc<BandwidthLimiter>) -> Self
+    where
+        O: AuthUser,
+    {
+        self.limits = self.limits.with_bandwidth(limiter);
+        self
+    }
+
+    /// Returns the bandwidth limiter of this server.
+    #[inline]
+    pub fn bandwidth_limiter(&self) -> Option<&Arc<BandwidthLimiter>> {
+        self.limits.bandwidth()
+    }
+
+    /// Sets the deadlines of the handshake, the request and the relay of the connections of this server, see the
+    /// [`timeout`](https://docs.rs/socks5-impl/latest/socks5_impl/server/timeout/index.html) module. There are none by default.
+    #[inline]
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Returns the timeouts handed to the connections of this server.
+    #[inline]
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    /// Caps the number of concurrent sessions of this server, see the
+    /// [`session`](https://docs.rs/socks5-impl/latest/socks5_impl/server/session/index.html) module.
+    /// Keep a clone of the `Arc` to read the counts or change the limits while the server runs.
+    #[inline]
+    pub fn with_session_limiter(mut self, limiter: Arc<SessionLimiter>) -> Self
+    where
+        O: AuthUser,
+    {
+        self.limits = self.limits.with_sessions(limiter);
+        self
+    }
+
+    /// Returns the session limiter of this server.
+    #[inline]
+    pub fn session_limiter(&self) -> Option<&Arc<SessionLimiter>> {
+        self.limits.sessions()
+    }
 
     /// Accept an [`IncomingConnection`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.IncomingConnection.html).
     /// The connection may not be a valid socks5 connection. You need to call
     /// [`IncomingConnection::handshake()`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.IncomingConnection.html#method.handshake)
     /// to hand-shake it into a proper socks5 connection.
     #[inline]
-    pub async fn accept(&self) -> std::io::Result<(IncomingConnection<O>, SocketAddr)> {
-        let (stream, addr) = self.listener.accept().await?;
-        Ok((IncomingConnection::new(stream, self.auth.clone()), addr))
+    pub async fn accept(&self) -> std::io::Result<Accepted<O, L>> {
+        std::future::poll_fn(|cx| self.poll_accept(cx)).await
     }
 
     /// Polls to accept an [`IncomingConnection<O>`](https://docs.rs/socks5-impl/latest/socks5_impl/server/connection/struct.IncomingConnection.html).
@@ -68,30 +263,218 @@ impl<O: 'static> Server<O> {
     ///
     /// If there is no connection to accept, Poll::Pending is returned and the current task will be notified by a waker.
     /// Note that on multiple calls to poll_accept, only the Waker from the Context passed to the most recent call is scheduled to receive a wakeup.
-    #[inline]
-    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(IncomingConnection<O>, SocketAddr)>> {
-        self.listener
-            .poll_accept(cx)
-            .map_ok(|(stream, addr)| (IncomingConnection::new(stream, self.auth.clone()), addr))
+    ///
+    /// A client whose socket addresses cannot be read, typically because it reset the connection right after it was
+    /// accepted, is dropped rather than failing the accept.
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<Accepted<O, L>>> {
+        loop {
+            let (stream, addr) = ready!(self.listener.poll_accept(cx))?;
+            let (local_addr, peer_addr) = match L::socket_addrs(&stream) {
+                Ok(addrs) => addrs,
+                Err(err) => {
+                    log::debug!("dropping an accepted client without socket addresses: {err}");
+                    continue;
+                }
+            };
+            let mut conn = IncomingConnection::new(stream, self.auth.clone()).with_resolver(self.resolver.clone());
+            if let Some(local_addr) = local_addr {
+                conn = conn.with_local_addr(local_addr);
+            }
+            if let Some(peer_addr) = peer_addr {
+                conn = conn.with_peer_addr(peer_addr);
+            }
+            if let Some(guard) = &self.ssrf_guard {
+                conn = conn.with_ssrf_guard(guard.clone());
+            }
+            return Poll::Ready(Ok((conn.with_timeouts(self.timeouts).with_limits(self.limits.clone()), addr)));
+        }
+    }
+
+    /// Runs the server until its listener breaks, handing every client to `handler` on a task of its own.
+    ///
+    /// Errors of individual connections are logged with the [`log`](https://docs.rs/log) crate and do not stop the server.
+    /// Neither do the accept errors about a single client, such as an aborted connection, nor running out of file descriptors
+    /// or memory, upon which the server waits a little before accepting again. Any other accept error is returned.
+    /// Use [`DefaultHandler`](https://docs.rs/socks5-impl/latest/socks5_impl/server/handler/struct.DefaultHandler.html)
+    /// for a plain proxy, or implement
+    /// [`Handler`](https://docs.rs/socks5-impl/latest/socks5_impl/server/handler/trait.Handler.html) to change how requests are served.
+    pub async fn serve<H>(self, handler: H) -> std::io::Result<()>
+    where
+        O: Send + Sync,
+        H: Handler<O, L::Stream>,
+    {
+        let handler = Arc::new(handler);
+        loop {
+            let (conn, peer_addr) = match self.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) if is_connection_error(&err) => {
+                    log::debug!("accept: {err}");
+                    continue;
+                }
+                Err(err) if is_resource_error(&err) => {
+                    log::warn!("accept: {err}, retrying in {ACCEPT_BACKOFF:?}");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handler::handle(conn, &*handler).await {
+                    log::error!("{peer_addr:?}: {err}");
+                }
+            });
+        }
     }
 
     /// Get the the local socket address binded to this server
     #[inline]
-    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+    pub fn local_addr(&self) -> std::io::Result<L::Addr> {
         self.listener.local_addr()
     }
 }
 
-impl<O> From<(TcpListener, AuthAdaptor<O>)> for Server<O> {
+/// How long [`Server::serve()`] waits before accepting again when the process is out of resources.
+const ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Whether an accept error is about the client being accepted only, not the listener.
+///
+/// `WouldBlock` is left out: `poll_accept` waits for readiness instead, and a listener returning it again and again would
+/// have the accept loop spin.
+fn is_connection_error(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        err.kind(),
+        ConnectionAborted | ConnectionRefused | ConnectionReset | Interrupted | TimedOut
+    )
+}
+
+/// Whether an accept error comes from the process or the system running out of file descriptors or memory.
+fn is_resource_error(err: &std::io::Error) -> bool {
+    // EMFILE, ENFILE and ENOMEM on Unix, WSAEMFILE and WSAENOBUFS on Windows.
+    #[cfg(unix)]
+    const CODES: &[i32] = &[24, 23, 12];
+    #[cfg(windows)]
+    const CODES: &[i32] = &[10024, 10055];
+    #[cfg(not(any(unix, windows)))]
+    const CODES: &[i32] = &[];
+    err.kind() == std::io::ErrorKind::OutOfMemory || err.raw_os_error().is_some_and(|code| CODES.contains(&code))
+}
+
+impl<O, L> From<(L, AuthAdaptor<O>)> for Server<O, L> {
     #[inline]
-    fn from((listener, auth): (TcpListener, AuthAdaptor<O>)) -> Self {
-        Self { listener, auth }
+    fn from((listener, auth): (L, AuthAdaptor<O>)) -> Self {
+        let resolver = Arc::new(TokioResolver);
+        Self {
+            listener,
+            auth,
+            resolver,
+            ssrf_guard: None,
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+        }
     }
 }
 
-impl<O> From<Server<O>> for (TcpListener, AuthAdaptor<O>) {
+impl<O, L> From<Server<O, L>> for (L, AuthAdaptor<O>) {
     #[inline]
-    fn from(server: Server<O>) -> Self {
+    fn from(server: Server<O, L>) -> Self {
         (server.listener, server.auth)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Listener, Server};
+    use crate::server::{auth::NoAuth, DefaultHandler};
+    use std::{
+        collections::VecDeque,
+        io::{Error, ErrorKind},
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    };
+    use tokio::net::{TcpListener, TcpStream};
+
+    static FAIL_NEXT: AtomicBool = AtomicBool::new(false);
+
+    /// A TCP listener failing to read the addresses of a stream when asked to, as for a client gone right after the accept.
+    struct FlakyListener(TcpListener);
+
+    impl Listener for FlakyListener {
+        type Stream = TcpStream;
+        type Addr = SocketAddr;
+
+        fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<(TcpStream, SocketAddr)>> {
+            self.0.poll_accept(cx)
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+
+        fn socket_addrs(stream: &TcpStream) -> std::io::Result<(Option<SocketAddr>, Option<SocketAddr>)> {
+            if FAIL_NEXT.swap(false, Ordering::Relaxed) {
+                return Err(std::io::ErrorKind::NotConnected.into());
+            }
+            <TcpListener as Listener>::socket_addrs(stream)
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_skips_clients_without_addresses() {
+        let listener = FlakyListener(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let server = Server::new(listener, Arc::new(NoAuth));
+        let addr = server.local_addr().unwrap();
+
+        FAIL_NEXT.store(true, Ordering::Relaxed);
+        let _gone = TcpStream::connect(addr).await.unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), client.local_addr().unwrap());
+    }
+
+    /// A listener failing every accept with the errors it is given, in turn.
+    struct FailingListener(Mutex<VecDeque<Error>>);
+
+    impl Listener for FailingListener {
+        type Stream = TcpStream;
+        type Addr = SocketAddr;
+
+        fn poll_accept(&self, _cx: &mut Context<'_>) -> Poll<std::io::Result<(TcpStream, SocketAddr)>> {
+            let err = self.0.lock().unwrap().pop_front().unwrap_or_else(|| ErrorKind::InvalidInput.into());
+            Poll::Ready(Err(err))
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Err(ErrorKind::Unsupported.into())
+        }
+
+        fn socket_addrs(_stream: &TcpStream) -> std::io::Result<(Option<SocketAddr>, Option<SocketAddr>)> {
+            Ok((None, None))
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_outlives_client_errors() {
+        let errors = [
+            ErrorKind::ConnectionAborted.into(),
+            Error::from_raw_os_error(if cfg!(windows) { 10024 } else { 24 }),
+            ErrorKind::ConnectionReset.into(),
+            ErrorKind::PermissionDenied.into(),
+        ];
+        let server = Server::new(FailingListener(Mutex::new(errors.into())), Arc::new(NoAuth));
+        let start = Instant::now();
+        let err = server.serve(DefaultHandler).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let server = Server::new(FailingListener(Mutex::new([ErrorKind::WouldBlock.into()].into())), Arc::new(NoAuth));
+        let err = server.serve(DefaultHandler).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,312 @@
+//! Limits on the number of concurrent sessions, globally, per client IP and per user.
+//!
+//! A [`SessionLimiter`] set with [`Server::with_session_limiter()`](crate::server::Server::with_session_limiter) counts every
+//! connection from the start of its handshake until it is dropped. Clients over the global or the per-IP limit are turned down
+//! in the method negotiation with [`AuthMethod::NoAcceptableMethods`](crate::protocol::AuthMethod::NoAcceptableMethods), or
+//! with a rejected reply for SOCKS4 clients. Authenticated users over their limit get
+//! [`Reply::GeneralFailure`](crate::protocol::Reply::GeneralFailure) to their request. Either way the error returned by the
+//! handshake or by [`Authenticated::wait_request()`](crate::server::connection::Authenticated::wait_request) is of kind
+//! [`io::ErrorKind::QuotaExceeded`].
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Default)]
+struct State {
+    max: Option<usize>,
+    max_per_ip: Option<usize>,
+    max_per_user: Option<usize>,
+    sessions: usize,
+    ips: HashMap<IpAddr, usize>,
+    users: HashMap<String, usize>,
+}
+
+fn release<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// Maximum numbers of concurrent sessions, with the counts of the sessions open.
+///
+/// Nothing is limited by default. The limits can be changed while the server runs, through the `Arc` the limiter is shared
+/// with; lowering one does not end the sessions already over it.
+///
+/// ```
+/// use socks5_impl::server::session::SessionLimiter;
+/// use std::sync::Arc;
+///
+/// let limiter = Arc::new(SessionLimiter::new().with_max_sessions(10_000).with_max_sessions_per_ip(64));
+/// let session = limiter.acquire(Some("192.0.2.1".parse().unwrap())).unwrap();
+/// assert_eq!(limiter.sessions(), 1);
+/// drop(session);
+/// assert_eq!(limiter.sessions(), 0);
+/// ```
+#[derive(Debug, Default)]
+pub struct SessionLimiter {
+    state: Mutex<State>,
+}
+
+impl SessionLimiter {
+    /// Creates a limiter without any limit.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the number of sessions of the server to `max`.
+    #[inline]
+    pub fn with_max_sessions(self, max: usize) -> Self {
+        self.set_max_sessions(Some(max));
+        self
+    }
+
+    /// Limits the number of sessions of each client IP to `max`.
+    #[inline]
+    pub fn with_max_sessions_per_ip(self, max: usize) -> Self {
+        self.set_max_sessions_per_ip(Some(max));
+        self
+    }
+
+    /// Limits the number of sessions of each authenticated user to `max`.
+    #[inline]
+    pub fn with_max_sessions_per_user(self, max: usize) -> Self {
+        self.set_max_sessions_per_user(Some(max));
+        self
+    }
+
+    /// Changes the limit of the number of sessions of the server, `None` removing it.
+    pub fn set_max_sessions(&self, max: Option<usize>) {
+        self.state.lock().unwrap().max = max;
+    }
+
+    /// Changes the limit of the number of sessions of each client IP, `None` removing it.
+    pub fn set_max_sessions_per_ip(&self, max: Option<usize>) {
+        self.state.lock().unwrap().max_per_ip = max;
+    }
+
+    /// Changes the limit of the number of sessions of each authenticated user, `None` removing it.
+    pub fn set_max_sessions_per_user(&self, max: Option<usize>) {
+        self.state.lock().unwrap().max_per_user = max;
+    }
+
+    /// Returns the limit of the number of sessions of the server.
+    pub fn max_sessions(&self) -> Option<usize> {
+        self.state.lock().unwrap().max
+    }
+
+    /// Returns the limit of the number of sessions of each client IP.
+    pub fn max_sessions_per_ip(&self) -> Option<usize> {
+        self.state.lock().unwrap().max_per_ip
+    }
+
+    /// Returns the limit of the number of sessions of each authenticated user.
+    pub fn max_sessions_per_user(&self) -> Option<usize> {
+        self.state.lock().unwrap().max_per_user
+    }
+
+    /// Returns the number of sessions open.
+    pub fn sessions(&self) -> usize {
+        self.state.lock().unwrap().sessions
+    }
+
+    /// Returns the number of sessions open from `ip`.
+    pub fn ip_sessions(&self, ip: IpAddr) -> usize {
+        self.state.lock().unwrap().ips.get(&ip.to_canonical()).copied().unwrap_or(0)
+    }
+
+    /// Returns the number of sessions open by `user`.
+    pub fn user_sessions(&self, user: &str) -> usize {
+        self.state.lock().unwrap().users.get(user).copied().unwrap_or(0)
+    }
+
+    /// Opens a session from `ip`, or from a client without an IP address such as those of Unix sockets, failing with
+    /// [`io::ErrorKind::QuotaExceeded`] if the server or the IP is at its limit. The session is counted until it is dropped.
+    pub fn acquire(self: &Arc<Self>, ip: Option<IpAddr>) -> io::Result<Session> {
+        let ip = ip.map(|ip| ip.to_canonical());
+        let mut state = self.state.lock().unwrap();
+        if state.max.is_some_and(|max| state.sessions >= max) {
+            return Err(too_many_sessions("on the server"));
+        }
+        if let Some(ip) = ip {
+            let count = state.ips.get(&ip).copied().unwrap_or(0);
+            if state.max_per_ip.is_some_and(|max| count >= max) {
+                return Err(too_many_sessions(&format!("from {ip}")));
+            }
+            state.ips.insert(ip, count + 1);
+        }
+        state.sessions += 1;
+        Ok(Session {
+            limiter: self.clone(),
+            ip,
+            user: None,
+        })
+    }
+}
+
+/// A session counted by a [`SessionLimiter`], until it is dropped.
+#[derive(Debug)]
+pub struct Session {
+    limiter: Arc<SessionLimiter>,
+    ip: Option<IpAddr>,
+    user: Option<String>,
+}
+
+impl Session {
+    /// Returns the client IP the session is counted for.
+    #[inline]
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    /// Returns the user the session is counted for.
+    #[inline]
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Counts the session for `user` too, instead of the user it was counted for before, if any. Fails with
+    /// [`io::ErrorKind::QuotaExceeded`] and leaves the session as it was if `user` is at its limit.
+    pub fn set_user(&mut self, user: &str) -> io::Result<()> {
+        if self.user.as_deref() == Some(user) {
+            return Ok(());
+        }
+        let mut state = self.limiter.state.lock().unwrap();
+        let count = state.users.get(user).copied().unwrap_or(0);
+        if state.max_per_user.is_some_and(|max| count >= max) {
+            return Err(too_many_sessions(&format!("of user {user}")));
+        }
+        state.users.insert(user.to_owned(), count + 1);
+        if let Some(old) = self.user.replace(user.to_owned()) {
+            release(&mut state.users, &old);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.sessions -= 1;
+        if let Some(ip) = &self.ip {
+            release(&mut state.ips, ip);
+        }
+        if let Some(user) = &self.user {
+            release(&mut state.users, user);
+        }
+    }
+}
+
+fn too_many_sessions(of: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::QuotaExceeded, format!("too many sessions {of}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionLimiter;
+    use crate::{
+        client,
+        protocol::{AuthMethod, Reply, UserKey},
+        server::{auth::UserKeyAuth, DefaultHandler, Server},
+        Error,
+    };
+    use std::{io::ErrorKind, net::IpAddr, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, BufStream},
+        net::{TcpListener, TcpStream},
+    };
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn counts_sessions() {
+        let limiter = Arc::new(
+            SessionLimiter::new()
+                .with_max_sessions(3)
+                .with_max_sessions_per_ip(2)
+                .with_max_sessions_per_user(1),
+        );
+        let mut a = limiter.acquire(Some(ip("192.0.2.1"))).unwrap();
+        let b = limiter.acquire(Some(ip("::ffff:192.0.2.1"))).unwrap();
+        let err = limiter.acquire(Some(ip("192.0.2.1"))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+        let mut c = limiter.acquire(None).unwrap();
+        assert!(limiter.acquire(Some(ip("192.0.2.2"))).is_err());
+        assert_eq!((limiter.sessions(), limiter.ip_sessions(ip("192.0.2.1"))), (3, 2));
+
+        a.set_user("alice").unwrap();
+        assert_eq!(c.set_user("alice").unwrap_err().kind(), ErrorKind::QuotaExceeded);
+        c.set_user("bob").unwrap();
+        a.set_user("alice").unwrap();
+        assert_eq!((limiter.user_sessions("alice"), c.user()), (1, Some("bob")));
+
+        drop(a);
+        c.set_user("alice").unwrap();
+        assert_eq!((limiter.user_sessions("alice"), limiter.user_sessions("bob")), (1, 0));
+        limiter.set_max_sessions(None);
+        limiter.set_max_sessions_per_ip(Some(1));
+        assert!(limiter.acquire(Some(ip("192.0.2.1"))).is_err());
+        drop((b, c));
+        assert_eq!((limiter.sessions(), limiter.ip_sessions(ip("192.0.2.1"))), (0, 0));
+        assert_eq!(limiter.user_sessions("alice"), 0);
+    }
+
+    async fn settle(limiter: &SessionLimiter, sessions: usize) {
+        for _ in 0..100 {
+            if limiter.sessions() == sessions {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} sessions instead of {sessions}", limiter.sessions());
+    }
+
+    #[tokio::test]
+    async fn limited_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move { stream.read_to_end(&mut Vec::new()).await });
+            }
+        });
+        let limiter = Arc::new(SessionLimiter::new().with_max_sessions_per_user(1));
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(UserKeyAuth::new("hyper", "proxy")))
+            .await
+            .unwrap()
+            .with_session_limiter(limiter.clone());
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.serve(DefaultHandler));
+
+        let connect = || async {
+            let mut stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+            let user_key = UserKey::new("hyper", "proxy");
+            client::connect(&mut stream, target, Some(user_key)).await.map(|_| stream)
+        };
+        let first = connect().await.unwrap();
+        assert_eq!(limiter.user_sessions("hyper"), 1);
+        let err = connect().await.unwrap_err();
+        assert_eq!(err.to_string(), Reply::GeneralFailure.to_string());
+        settle(&limiter, 1).await;
+        assert_eq!(limiter.ip_sessions(ip("127.0.0.1")), 1);
+
+        limiter.set_max_sessions_per_ip(Some(1));
+        let err = connect().await.unwrap_err();
+        assert!(matches!(err, Error::InvalidAuthMethod(AuthMethod::NoAcceptableMethods)), "{err:?}");
+        settle(&limiter, 1).await;
+
+        drop(first);
+        settle(&limiter, 0).await;
+        connect().await.unwrap();
+    }
+}
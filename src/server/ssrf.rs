@@ -0,0 +1,306 @@
+//! Protection against server-side request forgery, i.e. clients using the proxy to reach the network it runs in.
+//!
+//! An [`SsrfGuard`] set with [`Server::with_ssrf_guard()`](crate::server::Server::with_ssrf_guard) is applied to the addresses
+//! the requests resolve to, so domain names pointing at internal addresses, for example through DNS rebinding, are caught as well
+//! as IP literals. The built-in relays only ever use addresses the guard let through:
+//!
+//! - [`handler::connect()`](crate::server::handler::connect) answers requests without an allowed address with
+//!   [`Reply::ConnectionNotAllowed`](crate::protocol::Reply::ConnectionNotAllowed),
+//! - [`Bind::relay()`](crate::server::Bind::relay) does the same for blocked peer addresses,
+//! - [`UdpAssociate::relay()`](crate::server::UdpAssociate::relay) drops datagrams to blocked destinations.
+//!
+//! Custom handlers resolve through the guard with [`Connect::resolve()`](crate::server::Connect::resolve) and its counterparts.
+
+use crate::server::acl::Cidr;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::LazyLock,
+};
+
+/// Networks blocked by default: "this" network, private, shared, loopback, link-local, IETF protocol assignment, benchmarking
+/// and multicast networks, the broadcast address, and their IPv6 counterparts.
+static BLOCKED: LazyLock<Vec<Cidr>> = LazyLock::new(|| {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "255.255.255.255/32",
+        "::/128",
+        "::1/128",
+        "fc00::/7",
+        "fe80::/10",
+        "fec0::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|net| net.parse().unwrap())
+    .collect()
+});
+
+/// A filter of destination addresses, blocking loopback, link-local and private networks unless they are allowed explicitly.
+///
+/// IPv4-mapped IPv6 addresses are checked as IPv4 addresses. NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`) addresses must
+/// also embed an IPv4 address the guard allows, as they reach it through a translator or a relay. With the `serde` feature the guard can be loaded from a config such
+/// as `{ "allowed": ["10.1.0.0/16"], "blocked": ["192.0.2.0/24"] }`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize), serde(default))]
+pub struct SsrfGuard {
+    allowed: Vec<Cidr>,
+    blocked: Vec<Cidr>,
+}
+
+impl SsrfGuard {
+    /// Creates a guard blocking the loopback, link-local, private and multicast networks, i.e.
+    /// `0.0.0.0/8`, `10.0.0.0/8`, `100.64.0.0/10`, `127.0.0.0/8`, `169.254.0.0/16`, `172.16.0.0/12`, `192.0.0.0/24`,
+    /// `192.168.0.0/16`, `198.18.0.0/15`, `224.0.0.0/4`, `255.255.255.255`, `::`, `::1`, `fc00::/7`, `fe80::/10`, `fec0::/10`
+    /// and `ff00::/8`.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            allowed: Vec::new(),
+            blocked: Vec::new(),
+        }
+    }
+
+    /// Allows the network `net`, even if it is blocked by default or with [`with_blocked()`](SsrfGuard::with_blocked).
+    #[inline]
+    pub fn with_allowed(mut self, net: Cidr) -> Self {
+        self.allowed.push(net);
+        self
+    }
+
+    /// Blocks the network `net` in addition to the default ones.
+    #[inline]
+    pub fn with_blocked(mut self, net: Cidr) -> Self {
+        self.blocked.push(net);
+        self
+    }
+
+    /// Returns the explicitly allowed networks.
+    #[inline]
+    pub fn allowed(&self) -> &[Cidr] {
+        &self.allowed
+    }
+
+    /// Returns the networks blocked in addition to the default ones.
+    #[inline]
+    pub fn blocked(&self) -> &[Cidr] {
+        &self.blocked
+    }
+
+    /// Returns whether `ip` may be reached.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.allowed.iter().any(|net| net.contains(ip)) {
+            return true;
+        }
+        if embedded_ipv4(ip).is_some_and(|v4| !self.allows(v4.into())) {
+            return false;
+        }
+        let blocked_by_default = BLOCKED.iter().any(|net| net.contains(ip));
+        !blocked_by_default && !self.blocked.iter().any(|net| net.contains(ip))
+    }
+
+    /// Keeps the allowed addresses of `addrs`, failing with [`io::ErrorKind::PermissionDenied`] if there are none.
+    pub fn filter(&self, addrs: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+        let (allowed, blocked): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| self.allows(addr.ip()));
+        if allowed.is_empty() {
+            let msg = format!("destination {blocked:?} blocked by the SSRF guard");
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+        }
+        if !blocked.is_empty() {
+            log::debug!("SSRF guard dropped {blocked:?}");
+        }
+        Ok(allowed)
+    }
+}
+
+/// Returns the IPv4 address a NAT64 or 6to4 address leads to.
+fn embedded_ipv4(ip: IpAddr) -> Option<Ipv4Addr> {
+    let IpAddr::V6(ip) = ip else {
+        return None;
+    };
+    let octets = ip.octets();
+    match ip.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+impl Default for SsrfGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SsrfGuard;
+    use crate::{
+        client,
+        protocol::{Address, Reply},
+        resolver::StaticResolver,
+        server::{auth, DefaultHandler, Server},
+    };
+    use std::{
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, BufStream},
+        net::{TcpListener, TcpStream, UdpSocket},
+    };
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn default_blocks() {
+        let guard = SsrfGuard::new();
+        for blocked in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.31.255.255",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "224.0.0.251",
+            "239.255.255.250",
+            "ff02::1",
+            "192.0.0.170",
+            "198.18.0.1",
+            "198.19.255.255",
+            "fec0::1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::1",
+            "2002:c0a8:101::",
+        ] {
+            assert!(!guard.allows(ip(blocked)), "{blocked}");
+        }
+        for allowed in [
+            "93.184.216.34",
+            "172.32.0.1",
+            "8.8.8.8",
+            "2606:4700::1111",
+            "198.20.0.1",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ] {
+            assert!(guard.allows(ip(allowed)), "{allowed}");
+        }
+    }
+
+    #[test]
+    fn allow_list_wins() {
+        let guard = SsrfGuard::new()
+            .with_allowed("10.1.0.0/16".parse().unwrap())
+            .with_blocked("8.8.0.0/16".parse().unwrap())
+            .with_allowed("8.8.8.8".parse().unwrap());
+        assert!(guard.allows(ip("10.1.2.3")));
+        assert!(!guard.allows(ip("10.2.0.1")));
+        assert!(guard.allows(ip("8.8.8.8")));
+        assert!(!guard.allows(ip("8.8.4.4")));
+        assert!(guard.allows(ip("64:ff9b::a01:203")));
+        assert!(!guard.allows(ip("2002:808:404::")));
+
+        let addrs = vec![SocketAddr::from(([10, 2, 0, 1], 80)), SocketAddr::from(([10, 1, 0, 1], 80))];
+        assert_eq!(guard.filter(addrs).unwrap(), vec![SocketAddr::from(([10, 1, 0, 1], 80))]);
+        let err = guard.filter(vec![SocketAddr::from(([127, 0, 0, 1], 80))]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn ssrf_guard_serde() {
+        let guard: SsrfGuard = serde_json::from_str(r#"{ "allowed": ["127.0.0.1"] }"#).unwrap();
+        assert_eq!(guard, SsrfGuard::new().with_allowed("127.0.0.1".parse().unwrap()));
+        assert_eq!(serde_json::from_str::<SsrfGuard>("{}").unwrap(), SsrfGuard::new());
+        assert!(serde_json::from_str::<SsrfGuard>(r#"{ "blocked": ["nowhere"] }"#).is_err());
+    }
+
+    async fn spawn_server(guard: SsrfGuard) -> SocketAddr {
+        let resolver = StaticResolver::from_iter([("rebind.test", "127.0.0.1".parse().unwrap())]);
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(auth::NoAuth))
+            .await
+            .unwrap()
+            .with_resolver(Arc::new(resolver))
+            .with_ssrf_guard(Arc::new(guard));
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(DefaultHandler));
+        addr
+    }
+
+    async fn connect_through(proxy: SocketAddr, target: Address) -> crate::Result<()> {
+        let mut stream = BufStream::new(TcpStream::connect(proxy).await?);
+        client::connect(&mut stream, target, None).await?;
+        stream.write_all(b"hello").await?;
+        stream.flush().await?;
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn guarded_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    tokio::io::copy(&mut r, &mut w).await
+                });
+            }
+        });
+        let udp_echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_echo_addr = udp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((len, from)) = udp_echo.recv_from(&mut buf).await {
+                let _ = udp_echo.send_to(&buf[..len], from).await;
+            }
+        });
+
+        let not_allowed = Reply::ConnectionNotAllowed.to_string();
+        let guarded = spawn_server(SsrfGuard::new()).await;
+        let err = connect_through(guarded, echo.into()).await.unwrap_err();
+        assert_eq!(err.to_string(), not_allowed);
+        // A domain resolving to loopback is caught as well.
+        let err = connect_through(guarded, ("rebind.test", echo.port()).into()).await.unwrap_err();
+        assert_eq!(err.to_string(), not_allowed);
+        let stream = BufStream::new(TcpStream::connect(guarded).await.unwrap());
+        let err = client::SocksListener::bind(stream, ("rebind.test", 80), None).await.unwrap_err();
+        assert_eq!(err.to_string(), not_allowed);
+        let udp = client::create_udp_client(guarded, None).await.unwrap();
+        udp.send_to(b"ping", udp_echo_addr).await.unwrap();
+        assert!(udp.recv_from(Duration::from_millis(200), &mut Vec::new()).await.is_err());
+
+        let allowed = spawn_server(SsrfGuard::new().with_allowed("127.0.0.1".parse().unwrap())).await;
+        connect_through(allowed, echo.into()).await.unwrap();
+        connect_through(allowed, ("rebind.test", echo.port()).into()).await.unwrap();
+        let udp = client::create_udp_client(allowed, None).await.unwrap();
+        udp.send_to(b"ping", udp_echo_addr).await.unwrap();
+        let mut buf = Vec::new();
+        udp.recv_from(Duration::from_secs(2), &mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+    }
+}
@@ -0,0 +1,402 @@
+//! Deadlines on the phases of a connection, so clients that stall cannot hold on to the server.
+//!
+//! The [`Timeouts`] set with [`Server::with_timeouts()`](crate::server::Server::with_timeouts) bound the method negotiation and
+//! the authentication sub-negotiation in [`IncomingConnection::authenticate()`](crate::server::IncomingConnection::authenticate),
+//! the wait for the request in [`Authenticated::wait_request()`](crate::server::connection::Authenticated::wait_request), and
+//! the relays of [`handler::connect()`](crate::server::handler::connect), [`Bind::relay()`](crate::server::Bind::relay) and
+//! [`UdpAssociate::relay()`](crate::server::UdpAssociate::relay), with an idle timeout and a maximum lifetime. Custom handlers
+//! get the same relay deadlines with [`copy_bidirectional()`].
+//!
+//! An expired deadline is reported as an [`Expired`] error, which is an [`io::Error`] of kind [`io::ErrorKind::TimedOut`]
+//! wrapping it, or [`Error::Expired`](crate::Error::Expired).
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+
+/// The deadlines of the phases of a connection, `None` being no deadline, which is the default for all of them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timeouts {
+    handshake: Option<Duration>,
+    auth: Option<Duration>,
+    request: Option<Duration>,
+    idle: Option<Duration>,
+    lifetime: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Creates timeouts without any deadline.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long the client has to send its greeting and read the selected method.
+    #[inline]
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake = timeout;
+        self
+    }
+
+    /// How long the authentication sub-negotiation of the selected method may take.
+    #[inline]
+    pub fn with_auth_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.auth = timeout;
+        self
+    }
+
+    /// How long the server waits for the request once the client is authenticated.
+    #[inline]
+    pub fn with_request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request = timeout;
+        self
+    }
+
+    /// How long a relay is kept without traffic in either direction.
+    #[inline]
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle = timeout;
+        self
+    }
+
+    /// How long a relay is kept at most, whatever its traffic.
+    #[inline]
+    pub fn with_max_lifetime(mut self, lifetime: Option<Duration>) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Returns the method negotiation timeout.
+    #[inline]
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake
+    }
+
+    /// Returns the authentication sub-negotiation timeout.
+    #[inline]
+    pub fn auth_timeout(&self) -> Option<Duration> {
+        self.auth
+    }
+
+    /// Returns the request timeout.
+    #[inline]
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request
+    }
+
+    /// Returns the relay idle timeout.
+    #[inline]
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle
+    }
+
+    /// Returns the maximum relay lifetime.
+    #[inline]
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        self.lifetime
+    }
+}
+
+/// The phase of a connection a deadline is set on.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Phase {
+    /// The method negotiation, and the request of a SOCKS4 client, which comes with it.
+    Handshake,
+    /// The authentication sub-negotiation.
+    Auth,
+    /// The wait for the request.
+    Request,
+    /// A relay without traffic.
+    Idle,
+    /// A relay, from its start.
+    Lifetime,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            Phase::Handshake => "method negotiation",
+            Phase::Auth => "authentication",
+            Phase::Request => "request",
+            Phase::Idle => "idle relay",
+            Phase::Lifetime => "relay lifetime",
+        };
+        f.write_str(phase)
+    }
+}
+
+/// The error of a deadline that expired.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, thiserror::Error)]
+#[error("{phase} timed out after {timeout:?}")]
+pub struct Expired {
+    phase: Phase,
+    timeout: Duration,
+}
+
+impl Expired {
+    /// Creates the error of the deadline of `phase`, `timeout` long.
+    #[inline]
+    pub fn new(phase: Phase, timeout: Duration) -> Self {
+        Self { phase, timeout }
+    }
+
+    /// Returns the phase the deadline was set on.
+    #[inline]
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Returns how long the deadline was.
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns the expired deadline `err` wraps, if it is one.
+    pub fn from_io(err: &io::Error) -> Option<Self> {
+        err.get_ref().and_then(|err| err.downcast_ref::<Self>()).copied()
+    }
+}
+
+impl From<Expired> for io::Error {
+    fn from(expired: Expired) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, expired)
+    }
+}
+
+/// The deadline of one phase, counted from when it is created.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline {
+    phase: Phase,
+    timeout: Option<Duration>,
+    at: Instant,
+}
+
+impl Deadline {
+    pub(crate) fn new(phase: Phase, timeout: Option<Duration>) -> Self {
+        Self {
+            phase,
+            timeout,
+            at: Instant::now(),
+        }
+    }
+
+    /// Runs `fut`, failing with [`Expired`] if it is not done by the deadline.
+    pub(crate) async fn run<R>(&self, fut: impl Future<Output = io::Result<R>>) -> io::Result<R> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout_at(self.at + timeout, fut)
+                .await
+                .unwrap_or_else(|_| Err(Expired::new(self.phase, timeout).into())),
+            None => fut.await,
+        }
+    }
+}
+
+/// Like [`tokio::io::copy_bidirectional()`], but failing with [`Expired`] once the relay is idle for longer than the
+/// [idle timeout](Timeouts::with_idle_timeout) of `timeouts` or has run for its [maximum lifetime](Timeouts::with_max_lifetime).
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B, timeouts: &Timeouts) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let start = Instant::now();
+    let last_active = AtomicU64::new(0);
+    let (mut a, mut b) = (Watched::new(a, start, &last_active), Watched::new(b, start, &last_active));
+    let copy = tokio::io::copy_bidirectional(&mut a, &mut b);
+    let idle = async {
+        let Some(timeout) = timeouts.idle else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = start + Duration::from_millis(last_active.load(Ordering::Relaxed)) + timeout;
+            if Instant::now() >= deadline {
+                return Expired::new(Phase::Idle, timeout);
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    };
+    let lifetime = async {
+        let Some(lifetime) = timeouts.lifetime else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep_until(start + lifetime).await;
+        Expired::new(Phase::Lifetime, lifetime)
+    };
+    tokio::select! {
+        res = copy => res,
+        expired = idle => Err(expired.into()),
+        expired = lifetime => Err(expired.into()),
+    }
+}
+
+/// A stream recording when it last carried data.
+struct Watched<'a, S: ?Sized> {
+    stream: &'a mut S,
+    start: Instant,
+    last_active: &'a AtomicU64,
+}
+
+impl<'a, S: ?Sized> Watched<'a, S> {
+    fn new(stream: &'a mut S, start: Instant, last_active: &'a AtomicU64) -> Self {
+        Self {
+            stream,
+            start,
+            last_active,
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last_active.fetch_max(elapsed, Ordering::Relaxed);
+    }
+}
+
+impl<S: AsyncRead + Unpin + ?Sized> AsyncRead for Watched<'_, S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut *this.stream).poll_read(cx, buf))?;
+        if buf.filled().len() > filled {
+            this.touch();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin + ?Sized> AsyncWrite for Watched<'_, S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let len = ready!(Pin::new(&mut *this.stream).poll_write(cx, buf))?;
+        this.touch();
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_bidirectional, Expired, Phase, Timeouts};
+    use crate::{
+        client,
+        server::{
+            auth::{NoAuth, UserKeyAuth},
+            DefaultHandler, IncomingConnection, Server,
+        },
+        Error,
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, BufStream},
+        net::{TcpListener, TcpStream},
+        time::Instant,
+    };
+
+    const SHORT: Duration = Duration::from_millis(100);
+
+    fn phase_of(err: &std::io::Error) -> Phase {
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        Expired::from_io(err).unwrap().phase()
+    }
+
+    #[tokio::test]
+    async fn handshake_deadlines() {
+        let timeouts = Timeouts::new()
+            .with_handshake_timeout(Some(SHORT))
+            .with_auth_timeout(Some(SHORT))
+            .with_request_timeout(Some(SHORT));
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5]).await.unwrap();
+        let conn = IncomingConnection::new(server, Arc::new(NoAuth)).with_timeouts(timeouts);
+        assert_eq!(phase_of(&conn.authenticate().await.map(drop).unwrap_err()), Phase::Handshake);
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 2, 1]).await.unwrap();
+        let conn = IncomingConnection::new(server, Arc::new(UserKeyAuth::new("hyper", "proxy"))).with_timeouts(timeouts);
+        assert_eq!(phase_of(&conn.authenticate().await.map(drop).unwrap_err()), Phase::Auth);
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0, 5]).await.unwrap();
+        let conn = IncomingConnection::new(server, Arc::new(NoAuth)).with_timeouts(timeouts);
+        let err = conn.authenticate().await.unwrap().wait_request().await.unwrap_err();
+        assert!(
+            matches!(err, Error::Expired(expired) if expired.phase() == Phase::Request),
+            "{err:?}"
+        );
+        assert_eq!(phase_of(&err.into()), Phase::Request);
+    }
+
+    #[tokio::test]
+    async fn relay_deadlines() {
+        let (mut a, mut a_peer) = duplex(64);
+        let (mut b, _b_peer) = duplex(64);
+        let timeouts = Timeouts::new().with_idle_timeout(Some(SHORT));
+        let start = Instant::now();
+        tokio::spawn(async move {
+            for _ in 0..4 {
+                a_peer.write_all(b"ping").await.unwrap();
+                tokio::time::sleep(SHORT / 2).await;
+            }
+            a_peer
+        });
+        let err = copy_bidirectional(&mut a, &mut b, &timeouts).await.unwrap_err();
+        assert_eq!(phase_of(&err), Phase::Idle);
+        assert!(start.elapsed() >= SHORT * 2, "{:?}", start.elapsed());
+
+        let (mut a, mut a_peer) = duplex(64);
+        let (mut b, mut b_peer) = duplex(64);
+        let timeouts = Timeouts::new().with_idle_timeout(Some(SHORT)).with_max_lifetime(Some(SHORT * 3));
+        tokio::spawn(async move {
+            while a_peer.write_all(b"ping").await.is_ok() && b_peer.read_exact(&mut [0; 4]).await.is_ok() {
+                tokio::time::sleep(SHORT / 4).await;
+            }
+        });
+        let err = copy_bidirectional(&mut a, &mut b, &timeouts).await.unwrap_err();
+        assert_eq!(phase_of(&err), Phase::Lifetime);
+    }
+
+    #[tokio::test]
+    async fn idle_connect_is_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move { stream.read_to_end(&mut Vec::new()).await });
+            }
+        });
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), Arc::new(NoAuth))
+            .await
+            .unwrap()
+            .with_timeouts(Timeouts::new().with_handshake_timeout(Some(SHORT)).with_idle_timeout(Some(SHORT)));
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.serve(DefaultHandler));
+
+        // A client stalling in the handshake is dropped.
+        let mut stalled = TcpStream::connect(proxy).await.unwrap();
+        stalled.write_all(&[5]).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(2), stalled.read(&mut [0; 8])).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{read:?}");
+
+        let mut stream = BufStream::new(TcpStream::connect(proxy).await.unwrap());
+        client::connect(&mut stream, target, None).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut [0; 8])).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{read:?}");
+    }
+}
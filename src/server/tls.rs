@@ -0,0 +1,455 @@
+//! SOCKS5 over TLS on the server side, with the `tls` feature.
+//!
+//! A [`TlsListener`] wraps every accepted TCP connection in TLS before the SOCKS5 authentication of
+//! [`IncomingConnection::authenticate()`](crate::server::IncomingConnection::authenticate). The TLS handshake runs lazily on the
+//! first read or write of the [`TlsServerStream`], i.e. on the task of the connection, so a slow client never blocks the accept loop.
+//!
+//! The rustls configuration is built with [`TlsServerOptions`], which selects certificates by SNI and can verify client certificates.
+
+use crate::{
+    server::{AuthAdaptor, Listener, Server},
+    tls::{provider, rustls, tls_error},
+};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{server::TlsStream, Accept, TlsAcceptor};
+
+/// Builds the rustls [`ServerConfig`] of a TLS-wrapped SOCKS5 server.
+///
+/// The default certificate is served to clients without SNI or with an unknown server name, the certificates added with
+/// [`with_sni_cert()`](TlsServerOptions::with_sni_cert) to clients asking for their name.
+/// Client certificates are only requested once a client CA is set with [`with_client_ca()`](TlsServerOptions::with_client_ca).
+#[derive(Debug, Clone)]
+pub struct TlsServerOptions {
+    default_cert: Option<Arc<CertifiedKey>>,
+    sni_certs: HashMap<String, Arc<CertifiedKey>>,
+    client_roots: RootCertStore,
+    client_auth_optional: bool,
+}
+
+impl TlsServerOptions {
+    /// Creates options serving the certificate chain `certs` with its private key `key` by default.
+    #[inline]
+    pub fn new(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Self> {
+        let mut options = Self::without_default_cert();
+        options.default_cert = Some(certified_key(certs, key)?);
+        Ok(options)
+    }
+
+    /// Creates options serving the certificate chain and private key of the given PEM files by default.
+    #[inline]
+    pub fn from_pem_files<C: AsRef<Path>, K: AsRef<Path>>(cert_path: C, key_path: K) -> io::Result<Self> {
+        Self::new(crate::tls::load_certs(cert_path)?, crate::tls::load_private_key(key_path)?)
+    }
+
+    /// Creates options without a default certificate, which only accept clients asking for a name added with
+    /// [`with_sni_cert()`](TlsServerOptions::with_sni_cert).
+    #[inline]
+    pub fn without_default_cert() -> Self {
+        Self {
+            default_cert: None,
+            sni_certs: HashMap::new(),
+            client_roots: RootCertStore::empty(),
+            client_auth_optional: false,
+        }
+    }
+
+    /// Serves the certificate chain `certs` with its private key `key` to clients asking for `server_name` through SNI.
+    #[inline]
+    pub fn with_sni_cert(
+        mut self,
+        server_name: &str,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> io::Result<Self> {
+        self.sni_certs.insert(server_name.to_ascii_lowercase(), certified_key(certs, key)?);
+        Ok(self)
+    }
+
+    /// Requires clients to present a certificate issued by one of the CA certificates `certs`.
+    #[inline]
+    pub fn with_client_ca(mut self, certs: Vec<CertificateDer<'static>>) -> io::Result<Self> {
+        for cert in certs {
+            self.client_roots.add(cert).map_err(tls_error)?;
+        }
+        Ok(self)
+    }
+
+    /// Also accepts clients without a certificate when a client CA is set. Presented certificates are still verified.
+    #[inline]
+    pub fn with_client_auth_optional(mut self, optional: bool) -> Self {
+        self.client_auth_optional = optional;
+        self
+    }
+
+    /// Returns whether clients without a certificate are accepted when a client CA is set.
+    #[inline]
+    pub fn client_auth_optional(&self) -> bool {
+        self.client_auth_optional
+    }
+
+    /// Builds the rustls server configuration.
+    pub fn build(self) -> io::Result<Arc<ServerConfig>> {
+        if self.default_cert.is_none() && self.sni_certs.is_empty() {
+            return Err(tls_error("no server certificate configured"));
+        }
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = if self.client_roots.is_empty() {
+            builder.with_no_client_auth()
+        } else {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(self.client_roots), provider);
+            let verifier = if self.client_auth_optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(tls_error)?)
+        };
+        let resolver = SniResolver {
+            default_cert: self.default_cert,
+            sni_certs: self.sni_certs,
+        };
+        Ok(Arc::new(builder.with_cert_resolver(Arc::new(resolver))))
+    }
+}
+
+fn certified_key(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Arc<CertifiedKey>> {
+    if certs.is_empty() {
+        return Err(tls_error("empty certificate chain"));
+    }
+    Ok(Arc::new(CertifiedKey::from_der(certs, key, &provider()).map_err(tls_error)?))
+}
+
+#[derive(Debug)]
+struct SniResolver {
+    default_cert: Option<Arc<CertifiedKey>>,
+    sni_certs: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.sni_certs.get(&name.to_ascii_lowercase()))
+            .or(self.default_cert.as_ref())
+            .cloned()
+    }
+}
+
+/// A TCP listener wrapping accepted connections in TLS.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsListener {
+    /// Wraps the connections of `listener` in TLS with the given configuration.
+    #[inline]
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> Self {
+        let acceptor = TlsAcceptor::from(config);
+        Self { listener, acceptor }
+    }
+
+    /// Returns the underlying TCP listener.
+    #[inline]
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.listener
+    }
+}
+
+impl Listener for TlsListener {
+    type Stream = TlsServerStream;
+    type Addr = SocketAddr;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, Self::Addr)>> {
+        loop {
+            let (stream, addr) = ready!(self.listener.poll_accept(cx))?;
+            // A client gone right after the accept is dropped, it must not fail the listener.
+            let local_addr = match stream.local_addr() {
+                Ok(local_addr) => local_addr,
+                Err(err) => {
+                    log::debug!("dropping TLS client {addr} without a local address: {err}");
+                    continue;
+                }
+            };
+            let stream = TlsServerStream {
+                state: TlsState::Handshaking(Box::new(self.acceptor.accept(stream))),
+                local_addr,
+                peer_addr: addr,
+            };
+            return Poll::Ready(Ok((stream, addr)));
+        }
+    }
+
+    #[inline]
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+
+    #[inline]
+    fn socket_addrs(stream: &Self::Stream) -> io::Result<(Option<SocketAddr>, Option<SocketAddr>)> {
+        Ok((Some(stream.local_addr), Some(stream.peer_addr)))
+    }
+}
+
+/// A TLS stream accepted by a [`TlsListener`], which completes its handshake on first use.
+pub struct TlsServerStream {
+    state: TlsState,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+enum TlsState {
+    Handshaking(Box<Accept<TcpStream>>),
+    Ready(Box<TlsStream<TcpStream>>),
+    Failed,
+}
+
+impl TlsServerStream {
+    /// Completes the TLS handshake, which otherwise happens on the first read or write.
+    #[inline]
+    pub async fn handshake(&mut self) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_handshake(cx).map_ok(|_| ())).await
+    }
+
+    /// Returns the TLS stream once the handshake is complete, e.g. to inspect the
+    /// [SNI name](rustls::ServerConnection::server_name) or the
+    /// [client certificates](rustls::CommonState::peer_certificates) of its connection.
+    #[inline]
+    pub fn get_ref(&self) -> Option<&TlsStream<TcpStream>> {
+        match &self.state {
+            TlsState::Ready(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// Returns the local address of the TCP connection.
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the peer address of the TCP connection.
+    #[inline]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&mut TlsStream<TcpStream>>> {
+        if let TlsState::Handshaking(accept) = &mut self.state {
+            match ready!(Pin::new(&mut **accept).poll(cx)) {
+                Ok(stream) => self.state = TlsState::Ready(Box::new(stream)),
+                Err(err) => {
+                    self.state = TlsState::Failed;
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+        match &mut self.state {
+            TlsState::Ready(stream) => Poll::Ready(Ok(stream)),
+            _ => Poll::Ready(Err(io::Error::new(io::ErrorKind::NotConnected, "TLS handshake failed"))),
+        }
+    }
+}
+
+impl AsyncRead for TlsServerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_handshake(cx))?;
+        Pin::new(stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsServerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let stream = ready!(self.get_mut().poll_handshake(cx))?;
+        Pin::new(stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_handshake(cx))?;
+        Pin::new(stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_handshake(cx))?;
+        Pin::new(stream).poll_shutdown(cx)
+    }
+}
+
+impl<O: 'static> Server<O, TlsListener> {
+    /// Create a new socks5 server on the given socket address, wrapping every connection in TLS with `config` before the
+    /// authentication handshake.
+    ///
+    /// The configuration is usually built with [`TlsServerOptions`].
+    pub async fn bind_tls(addr: SocketAddr, config: Arc<ServerConfig>, auth: AuthAdaptor<O>) -> io::Result<Self> {
+        let (listener, _): (TcpListener, _) = Server::bind(addr, auth.clone()).await?.into();
+        Ok(Self::new(TlsListener::new(listener, config), auth))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TlsServerOptions;
+    use crate::{
+        client::{
+            self,
+            tls::{connect_tls, TlsClientOptions},
+        },
+        server::{auth, DefaultHandler, Server},
+        tls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    };
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    struct Ca {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        fn der(&self) -> CertificateDer<'static> {
+            self.cert.der().clone()
+        }
+
+        fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (rcgen::Certificate, KeyPair) {
+            let mut params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            (cert, key)
+        }
+
+        fn issue_der(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+            let (cert, key) = self.issue(name, usage);
+            (vec![cert.der().clone()], PrivatePkcs8KeyDer::from(key.serialize_der()).into())
+        }
+    }
+
+    async fn spawn_echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    tokio::io::copy(&mut r, &mut w).await
+                });
+            }
+        });
+        addr
+    }
+
+    async fn spawn_tls_server(options: TlsServerOptions) -> SocketAddr {
+        let config = options.build().unwrap();
+        let server = Server::bind_tls("127.0.0.1:0".parse().unwrap(), config, Arc::new(auth::NoAuth))
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(DefaultHandler));
+        addr
+    }
+
+    async fn echo_through(proxy: SocketAddr, server_name: &str, client: TlsClientOptions, target: SocketAddr) -> crate::Result<()> {
+        let mut stream = connect_tls(proxy, server_name, client.build()?).await?;
+        client::connect(&mut stream, target, None).await?;
+        stream.write_all(b"hello").await?;
+        stream.flush().await?;
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_server_with_sni() {
+        let echo = spawn_echo().await;
+        let ca = Ca::new();
+
+        let dir = std::env::temp_dir().join(format!("socks5-impl-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = ca.issue("proxy.test", ExtendedKeyUsagePurpose::ServerAuth);
+        std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+        let (alt_certs, alt_key) = ca.issue_der("alt.test", ExtendedKeyUsagePurpose::ServerAuth);
+        let options = TlsServerOptions::from_pem_files(dir.join("cert.pem"), dir.join("key.pem"))
+            .unwrap()
+            .with_sni_cert("ALT.test", alt_certs, alt_key)
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let proxy = spawn_tls_server(options).await;
+
+        let roots = || TlsClientOptions::new().with_root_certs(vec![ca.der()]).unwrap();
+        echo_through(proxy, "proxy.test", roots(), echo).await.unwrap();
+        echo_through(proxy, "alt.test", roots(), echo).await.unwrap();
+        // Unknown names get the default certificate, which is not valid for them.
+        assert!(echo_through(proxy, "other.test", roots(), echo).await.is_err());
+        // A certificate of an untrusted CA is rejected.
+        let untrusted = TlsClientOptions::new().with_root_certs(vec![Ca::new().der()]).unwrap();
+        assert!(echo_through(proxy, "proxy.test", untrusted, echo).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_server_with_client_certificates() {
+        let echo = spawn_echo().await;
+        let ca = Ca::new();
+        let client_ca = Ca::new();
+        let (certs, key) = ca.issue_der("proxy.test", ExtendedKeyUsagePurpose::ServerAuth);
+        let options = TlsServerOptions::new(certs, key)
+            .unwrap()
+            .with_client_ca(vec![client_ca.der()])
+            .unwrap();
+        let required = spawn_tls_server(options.clone()).await;
+        let optional = spawn_tls_server(options.with_client_auth_optional(true)).await;
+
+        let roots = || TlsClientOptions::new().with_root_certs(vec![ca.der()]).unwrap();
+        let with_cert = || {
+            let (certs, key) = client_ca.issue_der("client", ExtendedKeyUsagePurpose::ClientAuth);
+            roots().with_client_cert(certs, key)
+        };
+        let with_other_cert = || {
+            let (certs, key) = Ca::new().issue_der("client", ExtendedKeyUsagePurpose::ClientAuth);
+            roots().with_client_cert(certs, key)
+        };
+
+        echo_through(required, "proxy.test", with_cert(), echo).await.unwrap();
+        assert!(echo_through(required, "proxy.test", roots(), echo).await.is_err());
+        assert!(echo_through(required, "proxy.test", with_other_cert(), echo).await.is_err());
+
+        echo_through(optional, "proxy.test", with_cert(), echo).await.unwrap();
+        echo_through(optional, "proxy.test", roots(), echo).await.unwrap();
+        assert!(echo_through(optional, "proxy.test", with_other_cert(), echo).await.is_err());
+    }
+}
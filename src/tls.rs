@@ -0,0 +1,43 @@
+//! Shared helpers of the `tls` feature, which runs SOCKS5 inside TLS with [rustls](https://docs.rs/rustls).
+//!
+//! The server side is [`server::tls`](crate::server::tls) and the client side is [`client::tls`](crate::client::tls).
+//! Both use the `ring` crypto provider of rustls, so no process-wide default provider has to be installed.
+
+pub use tokio_rustls::rustls;
+
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::{io, path::Path, sync::Arc};
+
+/// Loads all certificates of a PEM file, e.g. a certificate chain with the end-entity certificate first.
+pub fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(pem_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error)?;
+    if certs.is_empty() {
+        let msg = format!("no certificate in {}", path.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok(certs)
+}
+
+/// Loads the first private key of a PEM file, in PKCS#1, PKCS#8 or SEC1 format.
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(pem_error)
+}
+
+pub(crate) fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+pub(crate) fn tls_error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn pem_error(err: rustls::pki_types::pem::Error) -> io::Error {
+    match err {
+        rustls::pki_types::pem::Error::Io(err) => err,
+        err => tls_error(err),
+    }
+}